@@ -0,0 +1,845 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::manager::Pool::Pool;
+use crate::storage::BlockDevice::{self as block_device, BlockDevice};
+use crate::storage::Compression::Compression;
+use crate::utils::checksum::ChecksumKind;
+use crate::utils::clock::unix_now;
+use crate::utils::Error::{StorageError, StorageResult};
+
+pub const KVS_PREFIX: &str = "Filesystem:";
+pub const INODE_PREFIX: &str = "FsInode:"; //FsInode:<filesystem>:<ino>
+pub const DIR_PREFIX: &str = "FsDir:"; //FsDir:<filesystem>:<ino>
+const INODE_SEQ_PREFIX: &str = "FsInodeSeq:"; //integer, the last inode number handed out
+const FILES_PREFIX: &str = "FsFiles:"; //integer, inodes in the filesystem
+const USAGE_PREFIX: &str = "FsUsage:"; //integer, bytes allocated by file contents
+pub const ROOT_INO: u64 = 1;
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+pub const MAX_NAME_LEN: usize = 255;
+
+//A POSIX filesystem kept in the Kvs. Directories and inodes are small records of their own,
+//the contents of every regular file is a thin BlockDevice, so files are chunked, deduplicated,
+//compressed and counted against pools exactly like block devices are.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileSystem {
+    pub name: String,
+    pub created_at: u64,
+    pub block_size_bytes: usize, //of the devices holding file contents
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub checksum: ChecksumKind,
+    #[serde(default)]
+    pub pool: Option<String>, //pool file contents allocate from
+    #[serde(skip)]
+    kvs: Option<Kvs>,
+    #[serde(skip)]
+    contents: BTreeMap<u64, BlockDevice>, //file contents in use, by inode
+    #[serde(skip)]
+    open_handles: BTreeMap<u64, u32>, //handles the kernel holds, by inode
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InodeKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct FsTime {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl FsTime {
+    pub fn now() -> Self {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        FsTime { sec: since_epoch.as_secs() as i64, nsec: since_epoch.subsec_nanos() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inode {
+    pub ino: u64,
+    pub kind: InodeKind,
+    pub perm: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub size: u64, //bytes, the content device is this rounded up to whole blocks
+    pub atime: FsTime,
+    pub mtime: FsTime,
+    pub ctime: FsTime,
+    #[serde(default)]
+    pub content: Option<u128>, //device holding a file's bytes, created on the first write
+    #[serde(default)]
+    pub target: Option<String>, //where a symlink points
+    #[serde(skip)]
+    filesystem: String,
+}
+
+//Entries of one directory, `.` and `..` are implied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Directory {
+    pub ino: u64,
+    pub parent: u64,
+    pub entries: BTreeMap<String, u64>,
+    #[serde(skip)]
+    filesystem: String,
+}
+
+//Attribute changes asked for by setattr, unset ones are left alone
+#[derive(Debug, Clone, Default)]
+pub struct SetAttr {
+    pub perm: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<FsTime>,
+    pub mtime: Option<FsTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsStats {
+    pub files: u64,
+    pub allocated_bytes: u64,
+    pub available_bytes: Option<u64>, //left in the pool, when the filesystem has one
+}
+
+//Filesystem operations fail with the errno the kernel is answered with
+#[derive(Debug)]
+pub enum FsError {
+    Errno(i32),
+    Storage(StorageError),
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+impl FsError {
+    pub fn errno(&self) -> i32 {
+        match self {
+            FsError::Errno(errno) => *errno,
+            FsError::Storage(e) => e.errno(),
+        }
+    }
+}
+
+impl From<StorageError> for FsError {
+    fn from(e: StorageError) -> Self {
+        FsError::Storage(e)
+    }
+}
+
+impl std::fmt::Display for FsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsError::Errno(errno) => write!(f, "{}", std::io::Error::from_raw_os_error(*errno)),
+            FsError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn errno<T>(errno: i32) -> FsResult<T> {
+    Err(FsError::Errno(errno))
+}
+
+impl FileSystem {
+    pub fn new(name: &str) -> Self {
+        FileSystem {
+            name: name.to_string(),
+            created_at: unix_now(),
+            block_size_bytes: DEFAULT_BLOCK_SIZE,
+            compression: Compression::None,
+            checksum: ChecksumKind::Sha256,
+            pool: None,
+            kvs: None,
+            contents: BTreeMap::new(),
+            open_handles: BTreeMap::new(),
+        }
+    }
+
+    pub fn kvs_id(name: &str) -> String {
+        format!("{}{}", KVS_PREFIX, name)
+    }
+
+    //stores a new filesystem with an empty root directory
+    pub fn create(kvs: &Kvs, mut fs: FileSystem) -> StorageResult<FileSystem> {
+        if fs.name.is_empty() || fs.name.contains(':') || fs.name.len() > MAX_NAME_LEN {
+            return Err(StorageError::InvalidArgument("filesystem names are non-empty and have no ':'".into()));
+        }
+        if fs.block_size_bytes == 0 || !fs.block_size_bytes.is_power_of_two() {
+            return Err(StorageError::InvalidArgument("the block size has to be a power of two".into()));
+        }
+        if !kvs.set_raw_if_absent(&Self::kvs_id(&fs.name), b"")? {
+            return Err(StorageError::Conflict(format!("filesystem {} already exists", fs.name)));
+        }
+        kvs.set_raw(&format!("{}{}", INODE_SEQ_PREFIX, fs.name), ROOT_INO.to_string().as_bytes())?;
+        kvs.set_raw(&format!("{}{}", FILES_PREFIX, fs.name), b"1")?;
+        kvs.set_raw(&format!("{}{}", USAGE_PREFIX, fs.name), b"0")?;
+        fs.attach(kvs.clone());
+        let root = fs.new_inode(ROOT_INO, InodeKind::Directory, 0o755, 0, 0);
+        fs.store_inode(&root)?;
+        fs.store_dir(&Directory { ino: ROOT_INO, parent: ROOT_INO, entries: BTreeMap::new(), filesystem: fs.name.clone() })?;
+        fs.store(kvs)?;
+        Ok(fs)
+    }
+
+    pub fn open(kvs: &Kvs, name: &str) -> StorageResult<FileSystem> {
+        let mut fs = FileSystem::load(&Self::kvs_id(name), kvs)?;
+        if kvs.get_raw(&format!("{}{}", FILES_PREFIX, name))?.is_none() {
+            fs.recount(kvs)?;
+        }
+        Ok(fs)
+    }
+
+    //filesystems made before files and usage were counted get their counters from one scan
+    fn recount(&mut self, kvs: &Kvs) -> StorageResult<()> {
+        let inodes = kvs.scan_keys(&format!("{}{}:*", INODE_PREFIX, self.name))?;
+        let mut allocated_bytes = 0;
+        for key in &inodes {
+            let inode = match Inode::load(key, kvs) {
+                Ok(inode) => inode,
+                Err(StorageError::NotFound(_)) => continue, //deleted while we were scanning
+                Err(e) => return Err(e),
+            };
+            if let Some(content) = inode.content {
+                allocated_bytes += BlockDevice::load(&format!("{}{}", block_device::KVS_PREFIX, content), kvs)?.allocated_bytes()?;
+            }
+        }
+        //another mount may have counted first, its counters have been kept up since
+        kvs.set_raw_if_absent(&format!("{}{}", USAGE_PREFIX, self.name), allocated_bytes.to_string().as_bytes())?;
+        kvs.set_raw_if_absent(&format!("{}{}", FILES_PREFIX, self.name), inodes.len().to_string().as_bytes())?;
+        Ok(())
+    }
+
+    fn count(&self, prefix: &str, delta: i64) -> StorageResult<i64> {
+        self.kvs()?.incr_by(&format!("{}{}", prefix, self.name), delta)
+    }
+
+    pub fn attach(&mut self, kvs: Kvs) {
+        self.kvs = Some(kvs);
+    }
+
+    fn kvs(&self) -> StorageResult<Kvs> {
+        self.kvs.clone().ok_or_else(|| {
+            StorageError::BackendUnavailable(format!("filesystem {} is not attached to a store", self.name))
+        })
+    }
+
+    fn new_inode(&self, ino: u64, kind: InodeKind, perm: u16, uid: u32, gid: u32) -> Inode {
+        let now = FsTime::now();
+        Inode {
+            ino,
+            kind,
+            perm: perm & 0o7777,
+            uid,
+            gid,
+            nlink: if kind == InodeKind::Directory { 2 } else { 1 },
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            content: None,
+            target: None,
+            filesystem: self.name.clone(),
+        }
+    }
+
+    pub fn getattr(&self, ino: u64) -> FsResult<Inode> {
+        let key = format!("{}{}:{}", INODE_PREFIX, self.name, ino);
+        match Inode::load(&key, &self.kvs()?) {
+            Ok(mut inode) => {
+                inode.filesystem = self.name.clone();
+                Ok(inode)
+            }
+            Err(StorageError::NotFound(_)) => errno(libc::ENOENT),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn store_inode(&self, inode: &Inode) -> StorageResult<()> {
+        inode.store(&self.kvs()?)
+    }
+
+    fn dir(&self, ino: u64) -> FsResult<Directory> {
+        let key = format!("{}{}:{}", DIR_PREFIX, self.name, ino);
+        match Directory::load(&key, &self.kvs()?) {
+            Ok(mut dir) => {
+                dir.filesystem = self.name.clone();
+                Ok(dir)
+            }
+            Err(StorageError::NotFound(_)) => match self.getattr(ino)?.kind {
+                InodeKind::Directory => Err(StorageError::Corruption(format!("directory {} has no entries record", ino)).into()),
+                _ => errno(libc::ENOTDIR),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn store_dir(&self, dir: &Directory) -> StorageResult<()> {
+        dir.store(&self.kvs()?)
+    }
+
+    pub fn lookup(&self, parent: u64, name: &str) -> FsResult<Inode> {
+        let dir = self.dir(parent)?;
+        match name {
+            "." => self.getattr(parent),
+            ".." => self.getattr(dir.parent),
+            _ => match dir.entries.get(name) {
+                Some(&ino) => self.getattr(ino),
+                None => errno(libc::ENOENT),
+            },
+        }
+    }
+
+    //entries with `.` and `..` first, as (ino, kind, name)
+    pub fn readdir(&self, ino: u64) -> FsResult<Vec<(u64, InodeKind, String)>> {
+        let dir = self.dir(ino)?;
+        let mut listing = vec![(ino, InodeKind::Directory, ".".to_string()), (dir.parent, InodeKind::Directory, "..".to_string())];
+        for (name, &child) in &dir.entries {
+            listing.push((child, self.getattr(child)?.kind, name.clone()));
+        }
+        Ok(listing)
+    }
+
+    fn check_name(name: &str) -> FsResult<()> {
+        if name.len() > MAX_NAME_LEN {
+            return errno(libc::ENAMETOOLONG);
+        }
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return errno(libc::EINVAL);
+        }
+        Ok(())
+    }
+
+    //makes a new inode and links it into the parent
+    fn make(&mut self, parent: u64, name: &str, mut inode: Inode) -> FsResult<Inode> {
+        Self::check_name(name)?;
+        let mut dir = self.dir(parent)?;
+        if dir.entries.contains_key(name) {
+            return errno(libc::EEXIST);
+        }
+        inode.ino = self.kvs()?.incr_by(&format!("{}{}", INODE_SEQ_PREFIX, self.name), 1)? as u64;
+        if inode.kind == InodeKind::Directory {
+            self.store_dir(&Directory { ino: inode.ino, parent, entries: BTreeMap::new(), filesystem: self.name.clone() })?;
+            self.touch_links(parent, 1)?;
+        }
+        self.store_inode(&inode)?;
+        self.count(FILES_PREFIX, 1)?;
+        dir.entries.insert(name.to_string(), inode.ino);
+        self.store_dir(&dir)?;
+        self.touch(parent)?;
+        Ok(inode)
+    }
+
+    pub fn create_file(&mut self, parent: u64, name: &str, perm: u16, uid: u32, gid: u32) -> FsResult<Inode> {
+        let inode = self.new_inode(0, InodeKind::File, perm, uid, gid);
+        self.make(parent, name, inode)
+    }
+
+    pub fn mkdir(&mut self, parent: u64, name: &str, perm: u16, uid: u32, gid: u32) -> FsResult<Inode> {
+        let inode = self.new_inode(0, InodeKind::Directory, perm, uid, gid);
+        self.make(parent, name, inode)
+    }
+
+    pub fn symlink(&mut self, parent: u64, name: &str, target: &str, uid: u32, gid: u32) -> FsResult<Inode> {
+        let mut inode = self.new_inode(0, InodeKind::Symlink, 0o777, uid, gid);
+        inode.size = target.len() as u64;
+        inode.target = Some(target.to_string());
+        self.make(parent, name, inode)
+    }
+
+    pub fn readlink(&self, ino: u64) -> FsResult<String> {
+        match self.getattr(ino)?.target {
+            Some(target) => Ok(target),
+            None => errno(libc::EINVAL),
+        }
+    }
+
+    //another name for an existing file, directories cannot be hard linked
+    pub fn link(&mut self, ino: u64, parent: u64, name: &str) -> FsResult<Inode> {
+        Self::check_name(name)?;
+        let mut inode = self.getattr(ino)?;
+        if inode.kind == InodeKind::Directory {
+            return errno(libc::EPERM);
+        }
+        let mut dir = self.dir(parent)?;
+        if dir.entries.contains_key(name) {
+            return errno(libc::EEXIST);
+        }
+        dir.entries.insert(name.to_string(), ino);
+        self.store_dir(&dir)?;
+        inode.nlink += 1;
+        inode.ctime = FsTime::now();
+        self.store_inode(&inode)?;
+        self.touch(parent)?;
+        Ok(inode)
+    }
+
+    pub fn unlink(&mut self, parent: u64, name: &str) -> FsResult<()> {
+        let mut dir = self.dir(parent)?;
+        let ino = *dir.entries.get(name).ok_or(FsError::Errno(libc::ENOENT))?;
+        let inode = self.getattr(ino)?;
+        if inode.kind == InodeKind::Directory {
+            return errno(libc::EISDIR);
+        }
+        dir.entries.remove(name);
+        self.store_dir(&dir)?;
+        self.touch(parent)?;
+        self.drop_link(inode)
+    }
+
+    pub fn rmdir(&mut self, parent: u64, name: &str) -> FsResult<()> {
+        let mut dir = self.dir(parent)?;
+        let ino = *dir.entries.get(name).ok_or(FsError::Errno(libc::ENOENT))?;
+        if !self.dir(ino)?.entries.is_empty() {
+            return errno(libc::ENOTEMPTY);
+        }
+        dir.entries.remove(name);
+        self.store_dir(&dir)?;
+        self.touch_links(parent, -1)?;
+        let kvs = self.kvs()?;
+        kvs.delete(&format!("{}{}:{}", DIR_PREFIX, self.name, ino))?;
+        kvs.delete(&format!("{}{}:{}", INODE_PREFIX, self.name, ino))?;
+        self.count(FILES_PREFIX, -1)?;
+        Ok(())
+    }
+
+    //moves an entry, replacing what the new name pointed at when that is allowed
+    pub fn rename(&mut self, parent: u64, name: &str, new_parent: u64, new_name: &str) -> FsResult<()> {
+        Self::check_name(new_name)?;
+        let ino = *self.dir(parent)?.entries.get(name).ok_or(FsError::Errno(libc::ENOENT))?;
+        let moved = self.getattr(ino)?;
+        if moved.kind == InodeKind::Directory && parent != new_parent {
+            //a directory cannot end up inside itself
+            let mut at = new_parent;
+            while at != ROOT_INO {
+                if at == ino {
+                    return errno(libc::EINVAL);
+                }
+                at = self.dir(at)?.parent;
+            }
+        }
+        if let Some(&replaced) = self.dir(new_parent)?.entries.get(new_name) {
+            if replaced == ino {
+                return Ok(());
+            }
+            match (moved.kind == InodeKind::Directory, self.getattr(replaced)?.kind == InodeKind::Directory) {
+                (true, true) => self.rmdir(new_parent, new_name)?,
+                (false, false) => self.unlink(new_parent, new_name)?,
+                (true, false) => return errno(libc::ENOTDIR),
+                (false, true) => return errno(libc::EISDIR),
+            }
+        }
+        let mut from = self.dir(parent)?;
+        from.entries.remove(name);
+        self.store_dir(&from)?;
+        let mut to = self.dir(new_parent)?;
+        to.entries.insert(new_name.to_string(), ino);
+        self.store_dir(&to)?;
+        if moved.kind == InodeKind::Directory && parent != new_parent {
+            let mut own = self.dir(ino)?;
+            own.parent = new_parent;
+            self.store_dir(&own)?;
+            self.touch_links(parent, -1)?;
+            self.touch_links(new_parent, 1)?;
+        }
+        self.touch(parent)?;
+        self.touch(new_parent)?;
+        Ok(())
+    }
+
+    //the inode and its contents go with the last name pointing at them, or with the last
+    //handle when the file is still open
+    fn drop_link(&mut self, mut inode: Inode) -> FsResult<()> {
+        inode.nlink = inode.nlink.saturating_sub(1);
+        inode.ctime = FsTime::now();
+        if inode.nlink > 0 || self.open_handles.contains_key(&inode.ino) {
+            self.store_inode(&inode)?;
+            return Ok(());
+        }
+        self.purge(inode)
+    }
+
+    fn purge(&mut self, inode: Inode) -> FsResult<()> {
+        let kvs = self.kvs()?;
+        if let Some(content) = inode.content {
+            let allocated = match self.contents.remove(&inode.ino) {
+                Some(mut device) => {
+                    //delete refunds the pool by the stored record
+                    device.flush(&kvs)?;
+                    device.allocated_bytes()?
+                }
+                None => BlockDevice::load(&format!("{}{}", block_device::KVS_PREFIX, content), &kvs)?.allocated_bytes()?,
+            };
+            //pages and payloads are left to gc, like those of any deleted device
+            BlockDevice::delete(&kvs, content)?;
+            self.count(USAGE_PREFIX, -(allocated as i64))?;
+        }
+        kvs.delete(&inode.get_kvs_id())?;
+        self.count(FILES_PREFIX, -1)?;
+        Ok(())
+    }
+
+    fn touch(&self, ino: u64) -> FsResult<()> {
+        let mut inode = self.getattr(ino)?;
+        inode.mtime = FsTime::now();
+        inode.ctime = inode.mtime;
+        Ok(self.store_inode(&inode)?)
+    }
+
+    fn touch_links(&self, ino: u64, delta: i32) -> FsResult<()> {
+        let mut inode = self.getattr(ino)?;
+        inode.nlink = inode.nlink.saturating_add_signed(delta);
+        Ok(self.store_inode(&inode)?)
+    }
+
+    //the device holding the file's bytes, loaded or made on first use
+    fn content(&mut self, inode: &mut Inode) -> FsResult<&mut BlockDevice> {
+        if !self.contents.contains_key(&inode.ino) {
+            let kvs = self.kvs()?;
+            let mut device = match inode.content {
+                Some(id) => BlockDevice::load(&format!("{}{}", block_device::KVS_PREFIX, id), &kvs)?,
+                None => {
+                    let device = self.new_content(&kvs)?;
+                    inode.content = Some(device.id);
+                    self.store_inode(inode)?;
+                    device
+                }
+            };
+            device.enable_journal();
+            self.contents.insert(inode.ino, device);
+        }
+        Ok(self.contents.get_mut(&inode.ino).expect("content was just loaded"))
+    }
+
+    fn new_content(&self, kvs: &Kvs) -> StorageResult<BlockDevice> {
+        let mut id = rand::random::<u128>() | 1 << 127; //far above the ids devices are created with
+        while kvs.get_raw(&format!("{}{}", block_device::KVS_PREFIX, id))?.is_some() {
+            id = rand::random::<u128>() | 1 << 127;
+        }
+        let mut device = BlockDevice::new(id, self.block_size_bytes as u64);
+        device.block_size_bytes = self.block_size_bytes;
+        device.compression = self.compression;
+        device.checksum = self.checksum;
+        device.pool = self.pool.clone();
+        device.file_of = Some(self.name.clone());
+        device.attach(kvs.clone());
+        device.flush(kvs)?;
+        Ok(device)
+    }
+
+    fn content_size(&self, size: u64) -> u64 {
+        size.next_multiple_of(self.block_size_bytes as u64).max(self.block_size_bytes as u64)
+    }
+
+    pub fn read(&mut self, ino: u64, offset: u64, size: usize) -> FsResult<Vec<u8>> {
+        let mut inode = self.getattr(ino)?;
+        if inode.kind == InodeKind::Directory {
+            return errno(libc::EISDIR);
+        }
+        if offset >= inode.size || inode.content.is_none() {
+            let len = inode.size.saturating_sub(offset).min(size as u64) as usize;
+            return Ok(vec![0u8; len]);
+        }
+        let len = (inode.size - offset).min(size as u64) as usize;
+        Ok(self.content(&mut inode)?.read(offset, len)?)
+    }
+
+    //block map changes are journaled before returning, like acknowledged nbd writes
+    pub fn write(&mut self, ino: u64, offset: u64, data: &[u8]) -> FsResult<usize> {
+        let mut inode = self.getattr(ino)?;
+        if inode.kind == InodeKind::Directory {
+            return errno(libc::EISDIR);
+        }
+        let end = offset.checked_add(data.len() as u64).ok_or(FsError::Errno(libc::EFBIG))?;
+        let capacity = self.content_size(end);
+        let kvs = self.kvs()?;
+        let usage = format!("{}{}", USAGE_PREFIX, self.name);
+        let device = self.content(&mut inode)?;
+        let allocated = device.allocated_bytes()?;
+        if device.logical_size_bytes < capacity {
+            //the record carries the size, store it before journaled writes go past the old one
+            device.resize(capacity)?;
+            device.flush(&kvs)?;
+        }
+        //chunks stored before a failing one stay allocated
+        let written = device.write(offset, data);
+        kvs.incr_by(&usage, device.allocated_bytes()? as i64 - allocated as i64)?;
+        written?;
+        device.persist_journal(&kvs)?;
+        inode.size = inode.size.max(end);
+        inode.mtime = FsTime::now();
+        inode.ctime = inode.mtime;
+        self.store_inode(&inode)?;
+        Ok(data.len())
+    }
+
+    pub fn setattr(&mut self, ino: u64, changes: SetAttr) -> FsResult<Inode> {
+        let mut inode = self.getattr(ino)?;
+        if let Some(size) = changes.size {
+            self.truncate(&mut inode, size)?;
+        }
+        if let Some(perm) = changes.perm {
+            inode.perm = perm & 0o7777;
+        }
+        inode.uid = changes.uid.unwrap_or(inode.uid);
+        inode.gid = changes.gid.unwrap_or(inode.gid);
+        inode.atime = changes.atime.unwrap_or(inode.atime);
+        inode.mtime = changes.mtime.unwrap_or(inode.mtime);
+        inode.ctime = FsTime::now();
+        self.store_inode(&inode)?;
+        Ok(inode)
+    }
+
+    //bytes past the end of a file are kept zero, so growing it again reads zeros
+    fn truncate(&mut self, inode: &mut Inode, size: u64) -> FsResult<()> {
+        match inode.kind {
+            InodeKind::Directory => return errno(libc::EISDIR),
+            InodeKind::Symlink => return errno(libc::EINVAL),
+            InodeKind::File => {}
+        }
+        if inode.content.is_some() {
+            let capacity = self.content_size(size);
+            let kvs = self.kvs()?;
+            let usage = format!("{}{}", USAGE_PREFIX, self.name);
+            let device = self.content(inode)?;
+            let allocated = device.allocated_bytes()?;
+            let resized = if size < inode.size {
+                device.resize(capacity.min(device.logical_size_bytes)).and_then(|()| {
+                    let tail = (device.logical_size_bytes - size) as usize;
+                    device.trim(size, tail)
+                })
+            } else {
+                device.resize(capacity.max(device.logical_size_bytes))
+            };
+            kvs.incr_by(&usage, device.allocated_bytes()? as i64 - allocated as i64)?;
+            resized?;
+            device.flush(&kvs)?;
+        }
+        inode.size = size;
+        inode.mtime = FsTime::now();
+        Ok(())
+    }
+
+    //stores the file's block map, so its journal does not have to be replayed
+    pub fn fsync(&mut self, ino: u64) -> FsResult<()> {
+        let kvs = self.kvs()?;
+        if let Some(device) = self.contents.get_mut(&ino) {
+            device.flush(&kvs)?;
+        }
+        Ok(())
+    }
+
+    //a handle on the file, its contents outlive its names until the last one is released
+    pub fn open_file(&mut self, ino: u64) -> FsResult<Inode> {
+        let inode = self.getattr(ino)?;
+        *self.open_handles.entry(ino).or_insert(0) += 1;
+        Ok(inode)
+    }
+
+    //syncs and forgets the contents of a file once its last handle is closed,
+    //deleting it when it was unlinked while open
+    pub fn release(&mut self, ino: u64) -> FsResult<()> {
+        if let Some(handles) = self.open_handles.get_mut(&ino) {
+            *handles -= 1;
+            if *handles > 0 {
+                return Ok(());
+            }
+            self.open_handles.remove(&ino);
+        }
+        self.fsync(ino)?;
+        self.contents.remove(&ino);
+        match self.getattr(ino) {
+            Ok(inode) if inode.nlink == 0 => self.purge(inode),
+            Ok(_) | Err(FsError::Errno(libc::ENOENT)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn sync_all(&mut self) -> FsResult<()> {
+        let inos: Vec<u64> = self.contents.keys().copied().collect();
+        for ino in inos {
+            self.fsync(ino)?;
+        }
+        Ok(())
+    }
+
+    //read from the counters kept as files are made, written and deleted, statfs is frequent
+    pub fn stats(&self) -> FsResult<FsStats> {
+        let kvs = self.kvs()?;
+        let files = self.count(FILES_PREFIX, 0)?.max(0) as u64;
+        let allocated_bytes = self.count(USAGE_PREFIX, 0)?.max(0) as u64;
+        let available_bytes = match &self.pool {
+            Some(pool) => {
+                let capacity = Pool::load(&Pool::kvs_id(pool), &kvs)?.capacity_bytes;
+                Some(capacity.saturating_sub(Pool::allocated(&kvs, pool)?))
+            }
+            None => None,
+        };
+        Ok(FsStats { files, allocated_bytes, available_bytes })
+    }
+}
+
+impl KvsStorable for FileSystem {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        let mut fs: FileSystem = kvs.load(id)?;
+        fs.attach(kvs.clone());
+        Ok(fs)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        Self::kvs_id(&self.name)
+    }
+}
+
+impl KvsStorable for Inode {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        format!("{}{}:{}", INODE_PREFIX, self.filesystem, self.ino)
+    }
+}
+
+impl KvsStorable for Directory {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        format!("{}{}:{}", DIR_PREFIX, self.filesystem, self.ino)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_fs(kvs: &Kvs) -> FileSystem {
+        FileSystem::create(kvs, FileSystem::new("home")).unwrap()
+    }
+
+    #[test]
+    fn files_live_in_thin_devices() {
+        let kvs = Kvs::in_memory();
+        let mut fs = new_fs(&kvs);
+        let file = fs.create_file(ROOT_INO, "notes.txt", 0o644, 1000, 1000).unwrap();
+        assert_eq!(fs.read(file.ino, 0, 100).unwrap(), Vec::<u8>::new());
+
+        fs.write(file.ino, 5000, b"hello").unwrap();
+        let data = fs.read(file.ino, 4990, 100).unwrap();
+        assert_eq!(data.len(), 15);
+        assert_eq!(&data[..10], &[0u8; 10]);
+        assert_eq!(&data[10..], b"hello");
+        fs.release(file.ino).unwrap();
+
+        //reopened from the store, the content device stays out of the device registry
+        let mut fs = FileSystem::open(&kvs, "home").unwrap();
+        let inode = fs.lookup(ROOT_INO, "notes.txt").unwrap();
+        assert_eq!(inode.size, 5005);
+        assert_eq!(fs.read(inode.ino, 5000, 5).unwrap(), b"hello");
+        crate::manager::Registry::rebuild(&kvs).unwrap();
+        assert!(crate::manager::Registry::devices(&kvs, &Default::default()).unwrap().is_empty());
+
+        //shrinking zeroes what is cut off, growing again reads zeros
+        fs.setattr(inode.ino, SetAttr { size: Some(5002), ..SetAttr::default() }).unwrap();
+        fs.setattr(inode.ino, SetAttr { size: Some(5005), ..SetAttr::default() }).unwrap();
+        assert_eq!(fs.read(inode.ino, 5000, 10).unwrap(), b"he\0\0\0");
+
+        let device = inode.content.unwrap();
+        fs.unlink(ROOT_INO, "notes.txt").unwrap();
+        assert!(kvs.get_raw(&format!("{}{}", block_device::KVS_PREFIX, device)).unwrap().is_none());
+        assert_eq!(fs.lookup(ROOT_INO, "notes.txt").unwrap_err().errno(), libc::ENOENT);
+    }
+
+    #[test]
+    fn directories_links_and_renames() {
+        let kvs = Kvs::in_memory();
+        let mut fs = new_fs(&kvs);
+        let docs = fs.mkdir(ROOT_INO, "docs", 0o755, 0, 0).unwrap();
+        let sub = fs.mkdir(docs.ino, "sub", 0o755, 0, 0).unwrap();
+        let file = fs.create_file(docs.ino, "a", 0o644, 0, 0).unwrap();
+        fs.write(file.ino, 0, b"same bytes").unwrap();
+        fs.link(file.ino, ROOT_INO, "b").unwrap();
+        fs.symlink(ROOT_INO, "c", "docs/a", 0, 0).unwrap();
+        assert_eq!(fs.getattr(docs.ino).unwrap().nlink, 3);
+
+        let names: Vec<String> = fs.readdir(ROOT_INO).unwrap().into_iter().map(|(_, _, name)| name).collect();
+        assert_eq!(names, [".", "..", "b", "c", "docs"]);
+        assert_eq!(fs.readlink(fs.lookup(ROOT_INO, "c").unwrap().ino).unwrap(), "docs/a");
+        assert_eq!(fs.mkdir(ROOT_INO, "docs", 0o755, 0, 0).unwrap_err().errno(), libc::EEXIST);
+        assert_eq!(fs.rmdir(ROOT_INO, "docs").unwrap_err().errno(), libc::ENOTEMPTY);
+        assert_eq!(fs.rename(ROOT_INO, "docs", sub.ino, "loop").unwrap_err().errno(), libc::EINVAL);
+
+        //the second name keeps the contents alive
+        fs.unlink(docs.ino, "a").unwrap();
+        let b = fs.lookup(ROOT_INO, "b").unwrap();
+        assert_eq!((b.nlink, fs.read(b.ino, 0, 64).unwrap()), (1, b"same bytes".to_vec()));
+
+        fs.rename(docs.ino, "sub", ROOT_INO, "moved").unwrap();
+        assert_eq!(fs.lookup(fs.lookup(ROOT_INO, "moved").unwrap().ino, "..").unwrap().ino, ROOT_INO);
+        assert_eq!(fs.getattr(docs.ino).unwrap().nlink, 2);
+        fs.rename(ROOT_INO, "b", ROOT_INO, "c").unwrap();
+        assert_eq!(fs.lookup(ROOT_INO, "c").unwrap().ino, b.ino);
+        fs.rmdir(ROOT_INO, "moved").unwrap();
+        fs.rmdir(ROOT_INO, "docs").unwrap();
+        assert_eq!(fs.stats().unwrap().files, 2); //root and the renamed file
+    }
+
+    #[test]
+    fn unlinked_files_live_until_their_last_handle_is_released() {
+        let kvs = Kvs::in_memory();
+        let mut fs = new_fs(&kvs);
+        let file = fs.create_file(ROOT_INO, "scratch", 0o644, 0, 0).unwrap();
+        fs.open_file(file.ino).unwrap();
+        fs.open_file(file.ino).unwrap();
+        fs.write(file.ino, 0, &[7u8; 10000]).unwrap();
+        assert_eq!(fs.stats().unwrap(), FsStats { files: 2, allocated_bytes: 12288, available_bytes: None });
+
+        fs.unlink(ROOT_INO, "scratch").unwrap();
+        assert_eq!(fs.lookup(ROOT_INO, "scratch").unwrap_err().errno(), libc::ENOENT);
+        fs.write(file.ino, 10000, b"tail").unwrap();
+        assert_eq!(fs.read(file.ino, 9998, 10).unwrap(), [7, 7, b't', b'a', b'i', b'l']);
+
+        //the other handle still reads what was written
+        fs.release(file.ino).unwrap();
+        let device = fs.getattr(file.ino).unwrap().content.unwrap();
+        assert_eq!(fs.read(file.ino, 10000, 4).unwrap(), b"tail");
+
+        fs.release(file.ino).unwrap();
+        assert_eq!(fs.getattr(file.ino).unwrap_err().errno(), libc::ENOENT);
+        assert!(kvs.get_raw(&format!("{}{}", block_device::KVS_PREFIX, device)).unwrap().is_none());
+        assert_eq!(fs.stats().unwrap(), FsStats { files: 1, allocated_bytes: 0, available_bytes: None });
+    }
+
+    #[test]
+    fn filesystems_without_counters_are_recounted_on_open() {
+        let kvs = Kvs::in_memory();
+        let mut fs = new_fs(&kvs);
+        let file = fs.create_file(ROOT_INO, "a", 0o644, 0, 0).unwrap();
+        fs.write(file.ino, 0, b"bytes").unwrap();
+        fs.release(file.ino).unwrap();
+        kvs.delete(&format!("{}home", FILES_PREFIX)).unwrap();
+        kvs.delete(&format!("{}home", USAGE_PREFIX)).unwrap();
+
+        let fs = FileSystem::open(&kvs, "home").unwrap();
+        assert_eq!(fs.stats().unwrap(), FsStats { files: 2, allocated_bytes: 4096, available_bytes: None });
+    }
+}
@@ -0,0 +1,270 @@
+use fuse::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, ReplyStatfs, ReplyWrite, Request};
+use log::{debug, warn};
+use std::ffi::OsStr;
+use std::path::Path;
+use time::Timespec;
+use crate::filesystem::FileSystem::{FileSystem, FsError, FsTime, Inode, InodeKind, SetAttr, MAX_NAME_LEN};
+
+//how long the kernel may cache attributes and entries, short since other mounts of the same
+//filesystem can change them behind our back
+const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
+//Answers the kernel's requests from a FileSystem, one request at a time
+pub struct FuseFS {
+    fs: FileSystem,
+}
+
+impl FuseFS {
+    pub fn new(fs: FileSystem) -> Self {
+        FuseFS { fs }
+    }
+
+    fn attr(&self, inode: &Inode) -> FileAttr {
+        let block_size = self.fs.block_size_bytes as u64;
+        FileAttr {
+            ino: inode.ino,
+            size: inode.size,
+            blocks: inode.size.next_multiple_of(block_size) / 512, //st_blocks is in 512 byte units
+            atime: timespec(inode.atime),
+            mtime: timespec(inode.mtime),
+            ctime: timespec(inode.ctime),
+            crtime: timespec(inode.ctime),
+            kind: match inode.kind {
+                InodeKind::File => FileType::RegularFile,
+                InodeKind::Directory => FileType::Directory,
+                InodeKind::Symlink => FileType::Symlink,
+            },
+            perm: inode.perm,
+            nlink: inode.nlink,
+            uid: inode.uid,
+            gid: inode.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+}
+
+fn timespec(t: FsTime) -> Timespec {
+    Timespec { sec: t.sec, nsec: t.nsec as i32 }
+}
+
+fn fs_time(t: Timespec) -> FsTime {
+    FsTime { sec: t.sec, nsec: t.nsec as u32 }
+}
+
+//names the kernel hands us are bytes, the filesystem only stores utf-8 ones
+fn name(name: &OsStr) -> Result<&str, FsError> {
+    name.to_str().ok_or(FsError::Errno(libc::EINVAL))
+}
+
+fn errno(op: &str, e: FsError) -> i32 {
+    match &e {
+        FsError::Errno(_) => debug!("fuse {}: {}", op, e),
+        FsError::Storage(_) => warn!("fuse {}: {}", op, e),
+    }
+    e.errno()
+}
+
+impl Filesystem for FuseFS {
+    fn lookup(&mut self, _req: &Request, parent: u64, name_: &OsStr, reply: ReplyEntry) {
+        match name(name_).and_then(|n| self.fs.lookup(parent, n)) {
+            Ok(inode) => reply.entry(&TTL, &self.attr(&inode), 0),
+            Err(e) => reply.error(errno("lookup", e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.fs.getattr(ino) {
+            Ok(inode) => reply.attr(&TTL, &self.attr(&inode)),
+            Err(e) => reply.error(errno("getattr", e)),
+        }
+    }
+
+    fn setattr(&mut self, _req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>,
+        atime: Option<Timespec>, mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        let changes = SetAttr {
+            perm: mode.map(|mode| mode as u16),
+            uid,
+            gid,
+            size,
+            atime: atime.map(fs_time),
+            mtime: mtime.map(fs_time),
+        };
+        match self.fs.setattr(ino, changes) {
+            Ok(inode) => reply.attr(&TTL, &self.attr(&inode)),
+            Err(e) => reply.error(errno("setattr", e)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.fs.readlink(ino) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(errno("readlink", e)),
+        }
+    }
+
+    //only regular files, device nodes and fifos have nowhere to live
+    fn mknod(&mut self, req: &Request, parent: u64, name_: &OsStr, mode: u32, _rdev: u32, reply: ReplyEntry) {
+        if mode & libc::S_IFMT != libc::S_IFREG {
+            return reply.error(libc::EPERM);
+        }
+        match name(name_).and_then(|n| self.fs.create_file(parent, n, mode as u16, req.uid(), req.gid())) {
+            Ok(inode) => reply.entry(&TTL, &self.attr(&inode), 0),
+            Err(e) => reply.error(errno("mknod", e)),
+        }
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name_: &OsStr, mode: u32, reply: ReplyEntry) {
+        match name(name_).and_then(|n| self.fs.mkdir(parent, n, mode as u16, req.uid(), req.gid())) {
+            Ok(inode) => reply.entry(&TTL, &self.attr(&inode), 0),
+            Err(e) => reply.error(errno("mkdir", e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name_: &OsStr, reply: ReplyEmpty) {
+        match name(name_).and_then(|n| self.fs.unlink(parent, n)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno("unlink", e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name_: &OsStr, reply: ReplyEmpty) {
+        match name(name_).and_then(|n| self.fs.rmdir(parent, n)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno("rmdir", e)),
+        }
+    }
+
+    fn symlink(&mut self, req: &Request, parent: u64, name_: &OsStr, link: &Path, reply: ReplyEntry) {
+        let target = match link.to_str() {
+            Some(target) => target,
+            None => return reply.error(libc::EINVAL),
+        };
+        match name(name_).and_then(|n| self.fs.symlink(parent, n, target, req.uid(), req.gid())) {
+            Ok(inode) => reply.entry(&TTL, &self.attr(&inode), 0),
+            Err(e) => reply.error(errno("symlink", e)),
+        }
+    }
+
+    fn rename(&mut self, _req: &Request, parent: u64, name_: &OsStr, new_parent: u64, new_name: &OsStr, reply: ReplyEmpty) {
+        match name(name_).and_then(|n| Ok((n, name(new_name)?))).and_then(|(n, new)| self.fs.rename(parent, n, new_parent, new)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno("rename", e)),
+        }
+    }
+
+    fn link(&mut self, _req: &Request, ino: u64, new_parent: u64, new_name: &OsStr, reply: ReplyEntry) {
+        match name(new_name).and_then(|n| self.fs.link(ino, new_parent, n)) {
+            Ok(inode) => reply.entry(&TTL, &self.attr(&inode), 0),
+            Err(e) => reply.error(errno("link", e)),
+        }
+    }
+
+    //files are addressed by inode, only how many handles each has is kept
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        match self.fs.getattr(ino) {
+            Ok(inode) if inode.kind == InodeKind::Directory && flags as i32 & libc::O_ACCMODE != libc::O_RDONLY => {
+                reply.error(libc::EISDIR)
+            }
+            Ok(_) => match self.fs.open_file(ino) {
+                Ok(_) => reply.opened(0, 0),
+                Err(e) => reply.error(errno("open", e)),
+            },
+            Err(e) => reply.error(errno("open", e)),
+        }
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+        match self.fs.read(ino, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(errno("read", e)),
+        }
+    }
+
+    fn write(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        if offset < 0 {
+            return reply.error(libc::EINVAL);
+        }
+        match self.fs.write(ino, offset as u64, data) {
+            Ok(written) => reply.written(written as u32),
+            Err(e) => reply.error(errno("write", e)),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        match self.fs.fsync(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno("flush", e)),
+        }
+    }
+
+    fn release(&mut self, _req: &Request, ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        match self.fs.release(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno("release", e)),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.fs.fsync(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno("fsync", e)),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let listing = match self.fs.readdir(ino) {
+            Ok(listing) => listing,
+            Err(e) => return reply.error(errno("readdir", e)),
+        };
+        //offsets handed out are those of the next entry, so a full buffer resumes where it stopped
+        for (i, (child, kind, child_name)) in listing.into_iter().enumerate().skip(offset.max(0) as usize) {
+            let kind = match kind {
+                InodeKind::File => FileType::RegularFile,
+                InodeKind::Directory => FileType::Directory,
+                InodeKind::Symlink => FileType::Symlink,
+            };
+            if reply.add(child, i as i64 + 1, kind, child_name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let stats = match self.fs.stats() {
+            Ok(stats) => stats,
+            Err(e) => return reply.error(errno("statfs", e)),
+        };
+        let block_size = self.fs.block_size_bytes as u64;
+        let used = stats.allocated_bytes.div_ceil(block_size);
+        //without a pool the store is the limit, which we cannot see, so report as much free as used
+        let free = match stats.available_bytes {
+            Some(available) => available / block_size,
+            None => used.max(1),
+        };
+        reply.statfs(used + free, free, free, stats.files, u32::MAX as u64, block_size as u32, MAX_NAME_LEN as u32, block_size as u32);
+    }
+
+    fn create(&mut self, req: &Request, parent: u64, name_: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        let created = name(name_)
+            .and_then(|n| self.fs.create_file(parent, n, mode as u16, req.uid(), req.gid()))
+            .and_then(|inode| self.fs.open_file(inode.ino));
+        match created {
+            Ok(inode) => reply.created(&TTL, &self.attr(&inode), 0, 0, flags),
+            Err(e) => reply.error(errno("create", e)),
+        }
+    }
+
+    //unmounted, store what open files still hold
+    fn destroy(&mut self, _req: &Request) {
+        if let Err(e) = self.fs.sync_all() {
+            warn!("fuse destroy: {}", e);
+        }
+    }
+}
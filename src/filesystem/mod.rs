@@ -0,0 +1,3 @@
+pub mod FileSystem;
+#[cfg(feature = "fuse")]
+pub mod FuseFS;
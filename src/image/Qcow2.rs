@@ -0,0 +1,512 @@
+use flate2::{Decompress, FlushDecompress};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use crate::image::{merge_ranges, open_image_at_depth, ImageReader, TransferStats};
+use crate::image::Raw::RawReader;
+use crate::storage::BlockDevice::BlockDevice;
+use crate::utils::Error::{StorageError, StorageResult};
+
+// qcow2 as documented in qemu's docs/interop/qcow2.txt. All integers are big endian.
+// Guest offsets go through a two level table: L1 entries point at L2 tables, L2 entries at
+// host clusters. Compressed clusters, zero clusters and backing files are read; snapshots,
+// encryption, external data files and extended L2 entries are not supported. Devices have no
+// parents of their own, so an import flattens the backing chain into the new device.
+pub const MAGIC: [u8; 4] = *b"QFI\xfb";
+const WRITE_VERSION: u32 = 3;
+const WRITE_CLUSTER_BITS: u32 = 16;
+const WRITE_REFCOUNT_ORDER: u32 = 4; //16 bit refcounts
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+const MAX_L1_ENTRIES: u32 = 32 * 1024 * 1024;
+const MAX_BACKING_NAME: u32 = 1023;
+
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00; //bits 9-55 of L1 and standard L2 entries
+const FLAG_COPIED: u64 = 1 << 63;
+const FLAG_COMPRESSED: u64 = 1 << 62;
+const FLAG_ZERO: u64 = 1; //version 3 only
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cluster {
+    Unallocated, //falls through to the backing file
+    Zero,
+    Data(u64),
+    Compressed { offset: u64, len: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_table_offset: u64,
+    backing_format: Option<String>,
+}
+
+fn be_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().expect("slice of 4 bytes"))
+}
+
+fn be_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().expect("slice of 8 bytes"))
+}
+
+fn corrupt(msg: String) -> StorageError {
+    StorageError::Corruption(format!("qcow2: {}", msg))
+}
+
+fn read_header(file: &File) -> StorageResult<Header> {
+    let mut buf = vec![0u8; V3_HEADER_LEN];
+    let read = file.read_at(&mut buf, 0)?;
+    if read < V2_HEADER_LEN || buf[..4] != MAGIC {
+        return Err(corrupt("not a qcow2 image".into()));
+    }
+    let version = be_u32(&buf, 4);
+    let cluster_bits = be_u32(&buf, 20);
+    let crypt_method = be_u32(&buf, 32);
+    if version != 2 && version != 3 {
+        return Err(StorageError::InvalidArgument(format!("qcow2 version {} is not supported", version)));
+    }
+    if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+        return Err(corrupt(format!("cluster size 2^{} is out of range", cluster_bits)));
+    }
+    if crypt_method != 0 {
+        return Err(StorageError::InvalidArgument("encrypted qcow2 images are not supported".into()));
+    }
+    let mut header_len = V2_HEADER_LEN;
+    if version == 3 {
+        if read < V3_HEADER_LEN {
+            return Err(corrupt("version 3 header is truncated".into()));
+        }
+        let incompatible = be_u64(&buf, 72);
+        if incompatible & !INCOMPAT_DIRTY != 0 {
+            //dirty only means the refcounts may be stale, which a reader does not care about
+            return Err(StorageError::InvalidArgument(format!(
+                "qcow2 incompatible features {:#x} are not supported", incompatible & !INCOMPAT_DIRTY
+            )));
+        }
+        header_len = be_u32(&buf, 100) as usize;
+        if header_len < V3_HEADER_LEN {
+            return Err(corrupt(format!("header length {} is too short", header_len)));
+        }
+    }
+    let header = Header {
+        version,
+        backing_file_offset: be_u64(&buf, 8),
+        backing_file_size: be_u32(&buf, 16),
+        cluster_bits,
+        size: be_u64(&buf, 24),
+        l1_size: be_u32(&buf, 36),
+        l1_table_offset: be_u64(&buf, 40),
+        backing_format: read_backing_format(file, header_len, 1 << cluster_bits)?,
+    };
+    let l2_span = (1u64 << cluster_bits) * ((1u64 << cluster_bits) / 8);
+    if header.l1_size > MAX_L1_ENTRIES || (header.l1_size as u64) < header.size.div_ceil(l2_span) {
+        return Err(corrupt(format!("L1 table of {} entries does not fit a {} byte disk", header.l1_size, header.size)));
+    }
+    Ok(header)
+}
+
+//walks the header extensions in the first cluster, the only one we need names the backing format
+fn read_backing_format(file: &File, mut at: usize, cluster_size: usize) -> StorageResult<Option<String>> {
+    let mut cluster = vec![0u8; cluster_size];
+    let read = file.read_at(&mut cluster, 0)?;
+    cluster.truncate(read);
+    while at + 8 <= cluster.len() {
+        let kind = be_u32(&cluster, at);
+        let len = be_u32(&cluster, at + 4) as usize;
+        if kind == EXT_END {
+            break;
+        }
+        let data = cluster.get(at + 8..at + 8 + len)
+            .ok_or_else(|| corrupt("header extension runs past the first cluster".into()))?;
+        if kind == EXT_BACKING_FORMAT {
+            return Ok(Some(String::from_utf8_lossy(data).into_owned()));
+        }
+        at += 8 + len.div_ceil(8) * 8;
+    }
+    Ok(None)
+}
+
+pub struct Qcow2Reader {
+    file: File,
+    header: Header,
+    l1: Vec<u64>,
+    l2_cache: Option<(u64, Vec<u64>)>, //last L2 table read, lookups tend to stay in one
+    inflated: Option<(u64, Vec<u8>)>, //last compressed cluster, by host offset
+    backing: Option<Box<dyn ImageReader>>,
+}
+
+impl Qcow2Reader {
+    //`depth` counts the images above this one in a backing chain
+    pub fn open(file: File, path: &Path, depth: usize) -> StorageResult<Self> {
+        let header = read_header(&file)?;
+        let mut l1_bytes = vec![0u8; header.l1_size as usize * 8];
+        file.read_exact_at(&mut l1_bytes, header.l1_table_offset)?;
+        let l1 = l1_bytes.chunks(8).map(|e| be_u64(e, 0)).collect();
+        let backing = Self::open_backing(&file, &header, path, depth)?;
+        Ok(Qcow2Reader { file, header, l1, l2_cache: None, inflated: None, backing })
+    }
+
+    fn open_backing(file: &File, header: &Header, path: &Path, depth: usize) -> StorageResult<Option<Box<dyn ImageReader>>> {
+        if header.backing_file_offset == 0 {
+            return Ok(None);
+        }
+        if header.backing_file_size == 0 || header.backing_file_size > MAX_BACKING_NAME {
+            return Err(corrupt(format!("backing file name of {} bytes", header.backing_file_size)));
+        }
+        let mut name = vec![0u8; header.backing_file_size as usize];
+        file.read_exact_at(&mut name, header.backing_file_offset)?;
+        let name = String::from_utf8(name).map_err(|_| corrupt("backing file name is not utf-8".into()))?;
+        //relative names are relative to the image that refers to them
+        let backing_path = path.parent().unwrap_or(Path::new(".")).join(&name);
+        let backing: Box<dyn ImageReader> = match header.backing_format.as_deref() {
+            Some("raw") => Box::new(RawReader::new(File::open(&backing_path)?)?),
+            Some("qcow2") | None => open_image_at_depth(&backing_path, depth + 1)?,
+            Some(other) => {
+                return Err(StorageError::InvalidArgument(format!("backing file format {:?} is not supported", other)));
+            }
+        };
+        Ok(Some(backing))
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.header.cluster_bits
+    }
+
+    fn l2_table(&mut self, offset: u64) -> StorageResult<&[u64]> {
+        if self.l2_cache.as_ref().map(|(cached, _)| *cached) != Some(offset) {
+            let mut bytes = vec![0u8; self.cluster_size() as usize];
+            self.file.read_exact_at(&mut bytes, offset)?;
+            self.l2_cache = Some((offset, bytes.chunks(8).map(|e| be_u64(e, 0)).collect()));
+        }
+        Ok(&self.l2_cache.as_ref().expect("cache was just filled").1)
+    }
+
+    fn cluster(&mut self, cluster_index: u64) -> StorageResult<Cluster> {
+        let l2_entries = self.cluster_size() / 8;
+        let l2_offset = match self.l1.get((cluster_index / l2_entries) as usize) {
+            Some(entry) => entry & OFFSET_MASK,
+            None => return Ok(Cluster::Unallocated),
+        };
+        if l2_offset == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+        let entry = self.l2_table(l2_offset)?[(cluster_index % l2_entries) as usize];
+        self.decode_entry(entry)
+    }
+
+    fn decode_entry(&self, entry: u64) -> StorageResult<Cluster> {
+        let cluster_bits = self.header.cluster_bits;
+        if entry & FLAG_COMPRESSED != 0 {
+            let size_shift = 62 - (cluster_bits - 8);
+            let offset = entry & ((1 << size_shift) - 1);
+            let sectors = ((entry >> size_shift) & ((1 << (cluster_bits - 8)) - 1)) + 1;
+            return Ok(Cluster::Compressed { offset, len: sectors * 512 - (offset & 511) });
+        }
+        if self.header.version >= 3 && entry & FLAG_ZERO != 0 {
+            return Ok(Cluster::Zero);
+        }
+        let host = entry & OFFSET_MASK;
+        if host == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+        if !host.is_multiple_of(self.cluster_size()) {
+            return Err(corrupt(format!("data cluster at unaligned offset {:#x}", host)));
+        }
+        Ok(Cluster::Data(host))
+    }
+
+    //the compressed stream may end before its last sector does, whatever follows is ignored
+    fn inflate(&mut self, offset: u64, len: u64) -> StorageResult<&[u8]> {
+        if self.inflated.as_ref().map(|(cached, _)| *cached) != Some(offset) {
+            let mut compressed = vec![0u8; len as usize];
+            let read = self.file.read_at(&mut compressed, offset)?;
+            compressed.truncate(read);
+            let mut cluster = vec![0u8; self.cluster_size() as usize];
+            let mut inflater = Decompress::new(false);
+            inflater.decompress(&compressed, &mut cluster, FlushDecompress::Finish)
+                .map_err(|e| corrupt(format!("compressed cluster at {:#x}: {}", offset, e)))?;
+            if inflater.total_out() != self.cluster_size() {
+                return Err(corrupt(format!(
+                    "compressed cluster at {:#x} inflates to {} bytes", offset, inflater.total_out()
+                )));
+            }
+            self.inflated = Some((offset, cluster));
+        }
+        Ok(&self.inflated.as_ref().expect("cache was just filled").1)
+    }
+}
+
+impl ImageReader for Qcow2Reader {
+    fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn allocated(&mut self) -> StorageResult<Vec<(u64, u64)>> {
+        let cluster_size = self.cluster_size();
+        let l2_entries = cluster_size / 8;
+        let mut ranges = Vec::new();
+        for l1_index in 0..self.l1.len() as u64 {
+            if self.l1[l1_index as usize] & OFFSET_MASK == 0 {
+                continue;
+            }
+            for l2_index in 0..l2_entries {
+                let cluster_index = l1_index * l2_entries + l2_index;
+                let start = cluster_index * cluster_size;
+                if start >= self.header.size {
+                    break;
+                }
+                match self.cluster(cluster_index)? {
+                    Cluster::Unallocated | Cluster::Zero => {}
+                    _ => ranges.push((start, (start + cluster_size).min(self.header.size))),
+                }
+            }
+        }
+        if let Some(backing) = &mut self.backing {
+            let size = self.header.size;
+            ranges.extend(backing.allocated()?.into_iter()
+                .filter(|(start, _)| *start < size)
+                .map(|(start, end)| (start, end.min(size))));
+        }
+        Ok(merge_ranges(ranges))
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> StorageResult<()> {
+        let cluster_size = self.cluster_size();
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = pos % cluster_size;
+            let n = ((cluster_size - within) as usize).min(buf.len() - done);
+            let out = &mut buf[done..done + n];
+            if pos >= self.header.size {
+                out.fill(0);
+            } else {
+                match self.cluster(pos / cluster_size)? {
+                    Cluster::Zero => out.fill(0),
+                    Cluster::Data(host) => self.file.read_exact_at(out, host + within)?,
+                    Cluster::Compressed { offset: host, len } => {
+                        let cluster = self.inflate(host, len)?;
+                        out.copy_from_slice(&cluster[within as usize..within as usize + n]);
+                    }
+                    Cluster::Unallocated => match &mut self.backing {
+                        Some(backing) if pos < backing.size() => {
+                            let from_backing = ((backing.size() - pos) as usize).min(n);
+                            backing.read_at(&mut out[..from_backing], pos)?;
+                            out[from_backing..].fill(0);
+                        }
+                        _ => out.fill(0),
+                    },
+                }
+            }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+//Writes the device as a standalone qcow2 version 3 image with 64 KiB clusters.
+//Only clusters holding non-zero data are allocated, every refcount is 1.
+pub fn export(device: &mut BlockDevice, file: &File) -> StorageResult<TransferStats> {
+    let cluster_size = 1u64 << WRITE_CLUSTER_BITS;
+    let l2_entries = cluster_size / 8;
+    let size = device.logical_size_bytes;
+    let l1_size = size.div_ceil(cluster_size * l2_entries).max(1);
+    let l1_clusters = (l1_size * 8).div_ceil(cluster_size);
+    file.set_len(0)?;
+
+    //cluster 0 is the header, the L1 table follows, data and then the other metadata after it
+    let mut next_free = 1 + l1_clusters;
+    let mut l2_tables: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    let mut stats = TransferStats::default();
+    let block_size = device.block_size_bytes as u64;
+    let mut clusters: Vec<u64> = Vec::new();
+    for (first, end) in device.changed_ranges(None)? {
+        let (start, end) = (first * block_size / cluster_size, (end * block_size).div_ceil(cluster_size));
+        let from = clusters.last().map(|last| start.max(last + 1)).unwrap_or(start);
+        clusters.extend(from..end);
+    }
+    for cluster_index in clusters {
+        let guest = cluster_index * cluster_size;
+        let len = cluster_size.min(size - guest) as usize;
+        let mut data = device.read(guest, len)?;
+        if data.iter().all(|&b| b == 0) {
+            continue;
+        }
+        data.resize(cluster_size as usize, 0);
+        let host = next_free * cluster_size;
+        file.write_all_at(&data, host)?;
+        next_free += 1;
+        stats.data_bytes += len as u64;
+        let table = l2_tables.entry(cluster_index / l2_entries)
+            .or_insert_with(|| vec![0u64; l2_entries as usize]);
+        table[(cluster_index % l2_entries) as usize] = host | FLAG_COPIED;
+    }
+    stats.hole_bytes = size - stats.data_bytes;
+
+    let mut l1 = vec![0u64; l1_size as usize];
+    for (l1_index, table) in &l2_tables {
+        let host = next_free * cluster_size;
+        file.write_all_at(&to_be_bytes(table), host)?;
+        next_free += 1;
+        l1[*l1_index as usize] = host | FLAG_COPIED;
+    }
+    file.write_all_at(&to_be_bytes(&l1), cluster_size)?;
+
+    //refcount blocks and the table pointing at them count themselves, grow them until they fit
+    let refcounts_per_block = cluster_size * 8 / (1 << WRITE_REFCOUNT_ORDER);
+    let (mut blocks, mut table_clusters) = (0u64, 0u64);
+    loop {
+        let total = next_free + blocks + table_clusters;
+        let needed_blocks = total.div_ceil(refcounts_per_block);
+        let needed_table = (needed_blocks * 8).div_ceil(cluster_size);
+        if (needed_blocks, needed_table) == (blocks, table_clusters) {
+            break;
+        }
+        (blocks, table_clusters) = (needed_blocks, needed_table);
+    }
+    let total = next_free + blocks + table_clusters;
+    let mut refcount_table = vec![0u64; (table_clusters * cluster_size / 8) as usize];
+    for block in 0..blocks {
+        let host = (next_free + block) * cluster_size;
+        let covered = (total - block * refcounts_per_block).min(refcounts_per_block);
+        let mut refcounts = vec![0u8; cluster_size as usize];
+        for entry in refcounts.chunks_mut(2).take(covered as usize) {
+            entry.copy_from_slice(&1u16.to_be_bytes());
+        }
+        file.write_all_at(&refcounts, host)?;
+        refcount_table[block as usize] = host;
+    }
+    let refcount_table_offset = (next_free + blocks) * cluster_size;
+    file.write_all_at(&to_be_bytes(&refcount_table), refcount_table_offset)?;
+
+    let mut header = vec![0u8; V3_HEADER_LEN + 8]; //the zeroed tail is the end of header extensions
+    header[0..4].copy_from_slice(&MAGIC);
+    header[4..8].copy_from_slice(&WRITE_VERSION.to_be_bytes());
+    header[20..24].copy_from_slice(&WRITE_CLUSTER_BITS.to_be_bytes());
+    header[24..32].copy_from_slice(&size.to_be_bytes());
+    header[36..40].copy_from_slice(&(l1_size as u32).to_be_bytes());
+    header[40..48].copy_from_slice(&cluster_size.to_be_bytes());
+    header[48..56].copy_from_slice(&refcount_table_offset.to_be_bytes());
+    header[56..60].copy_from_slice(&(table_clusters as u32).to_be_bytes());
+    header[96..100].copy_from_slice(&WRITE_REFCOUNT_ORDER.to_be_bytes());
+    header[100..104].copy_from_slice(&(V3_HEADER_LEN as u32).to_be_bytes());
+    file.write_all_at(&header, 0)?;
+    file.set_len(total * cluster_size)?;
+    file.sync_all()?;
+    Ok(stats)
+}
+
+fn to_be_bytes(entries: &[u64]) -> Vec<u8> {
+    entries.iter().flat_map(|e| e.to_be_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression as Level;
+    use std::io::Write;
+    use crate::manager::Kvs::Kvs;
+
+    const CLUSTER: u64 = 1 << WRITE_CLUSTER_BITS;
+
+    fn scratch_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("qcow2-{}-{}", name, std::process::id()))
+    }
+
+    fn exported(name: &str, device: &mut BlockDevice) -> std::path::PathBuf {
+        let path = scratch_path(name);
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        export(device, &file).unwrap();
+        path
+    }
+
+    fn device_with(kvs: &Kvs, size: u64, writes: &[(u64, Vec<u8>)]) -> BlockDevice {
+        let mut device = BlockDevice::new(1, size);
+        device.attach(kvs.clone());
+        for (offset, data) in writes {
+            device.write(*offset, data).unwrap();
+        }
+        device
+    }
+
+    fn read_all(path: &Path) -> Vec<u8> {
+        let mut image = open_image_at_depth(path, 0).unwrap();
+        let mut buf = vec![0u8; image.size() as usize];
+        image.read_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let kvs = Kvs::in_memory();
+        let size = 3 * CLUSTER + 4096;
+        let mut device = device_with(&kvs, size, &[(100, vec![0x5au8; 70_000]), (3 * CLUSTER, vec![0xa5u8; 4096])]);
+        let path = exported("plain", &mut device);
+
+        let file = File::open(&path).unwrap();
+        let mut reader = Qcow2Reader::open(file, &path, 0).unwrap();
+        assert_eq!(reader.size(), size);
+        assert_eq!(reader.allocated().unwrap(), vec![(0, 2 * CLUSTER), (3 * CLUSTER, size)]);
+        assert_eq!(read_all(&path), device.read(0, size as usize).unwrap());
+
+        let mut imported = BlockDevice::new(2, size);
+        imported.attach(kvs.clone());
+        crate::image::import(&mut imported, &mut reader, &kvs).unwrap();
+        assert_eq!(imported.read(0, size as usize).unwrap(), device.read(0, size as usize).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_compressed_clusters_and_backing_files() {
+        let kvs = Kvs::in_memory();
+        let size = 2 * CLUSTER;
+        let first = vec![0x77u8; CLUSTER as usize];
+        let mut device = device_with(&kvs, size, &[(0, first.clone())]);
+        let path = exported("overlay", &mut device);
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let header = read_header(&file).unwrap();
+
+        //move cluster 0 into a compressed cluster at the end of the file
+        let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(&first).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let at = file.metadata().unwrap().len() + 512 - 7; //unaligned on purpose
+        file.write_all_at(&compressed, at).unwrap();
+        let sectors = (at % 512 + compressed.len() as u64).div_ceil(512);
+        let size_shift = 62 - (WRITE_CLUSTER_BITS - 8);
+        let entry = FLAG_COMPRESSED | ((sectors - 1) << size_shift) | at;
+        let mut l1_entry = [0u8; 8];
+        file.read_exact_at(&mut l1_entry, header.l1_table_offset).unwrap();
+        let l2_offset = u64::from_be_bytes(l1_entry) & OFFSET_MASK;
+        file.write_all_at(&entry.to_be_bytes(), l2_offset).unwrap();
+
+        //the second cluster is unallocated and comes from a raw backing file
+        let backing_path = scratch_path("backing");
+        std::fs::write(&backing_path, vec![0x99u8; (CLUSTER + 100) as usize]).unwrap();
+        let name = backing_path.file_name().unwrap().to_str().unwrap().as_bytes();
+        let name_at = 512u64;
+        file.write_all_at(name, name_at).unwrap();
+        file.write_all_at(&name_at.to_be_bytes(), 8).unwrap();
+        file.write_all_at(&(name.len() as u32).to_be_bytes(), 16).unwrap();
+
+        let mut expected = first.clone();
+        expected.extend(vec![0x99u8; 100]);
+        expected.resize(size as usize, 0);
+        assert_eq!(read_all(&path), expected);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(backing_path).unwrap();
+    }
+}
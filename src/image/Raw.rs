@@ -0,0 +1,207 @@
+use nix::errno::Errno;
+use nix::fcntl::{fallocate, FallocateFlags};
+use nix::unistd::{lseek, Whence};
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use crate::image::{ImageReader, TransferStats};
+use crate::storage::BlockDevice::BlockDevice;
+use crate::storage::Payload::MAX_PAYLOAD_BLOCKS;
+use crate::utils::Error::{StorageError, StorageResult};
+
+//size of a regular file or block device
+pub fn image_size(file: &File) -> StorageResult<u64> {
+    let mut file = file;
+    Ok(file.seek(SeekFrom::End(0))?)
+}
+
+//Byte ranges of the file that may hold data, found with SEEK_DATA/SEEK_HOLE.
+//Filesystems and devices without hole support report the whole file.
+pub fn data_regions(file: &File, len: u64) -> StorageResult<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut regions = Vec::new();
+    let mut pos = 0u64;
+    while pos < len {
+        let start = match lseek(fd, pos as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            Err(Errno::ENXIO) => break, //only a hole left
+            Err(Errno::EINVAL) | Err(Errno::EOPNOTSUPP) if pos == 0 => return Ok(vec![(0, len)]),
+            Err(e) => return Err(StorageError::Io(e.into())),
+        };
+        let end = match lseek(fd, start as i64, Whence::SeekHole) {
+            Ok(end) => (end as u64).min(len),
+            Err(e) => return Err(StorageError::Io(e.into())),
+        };
+        if start >= end {
+            break;
+        }
+        regions.push((start, end));
+        pos = end;
+    }
+    Ok(regions)
+}
+
+//Raw image or block device read through as it is
+pub struct RawReader {
+    file: File,
+    size: u64,
+}
+
+impl RawReader {
+    pub fn new(file: File) -> StorageResult<Self> {
+        let size = image_size(&file)?;
+        Ok(RawReader { file, size })
+    }
+}
+
+impl ImageReader for RawReader {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn allocated(&mut self) -> StorageResult<Vec<(u64, u64)>> {
+        data_regions(&self.file, self.size)
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> StorageResult<()> {
+        //reads past the end of the file come back as zeros
+        let readable = self.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        self.file.read_exact_at(&mut buf[..readable], offset)?;
+        buf[readable..].fill(0);
+        Ok(())
+    }
+}
+
+//Writes the device into a raw image. Regular files are sized to the device and keep its holes
+//sparse; holes over existing data are punched, or zeroed where punching is not supported.
+pub fn export(device: &mut BlockDevice, file: &File) -> StorageResult<TransferStats> {
+    let size = device.logical_size_bytes;
+    let block_size = device.block_size_bytes as u64;
+    let mut punch = true;
+    let mut needs_clear = true;
+    if file.metadata()?.is_file() {
+        //nothing to clear in a file that was empty, set_len leaves it all hole
+        needs_clear = file.metadata()?.len() > 0;
+        file.set_len(size)?;
+    } else if image_size(file)? < size {
+        return Err(StorageError::InvalidArgument(format!(
+            "target is smaller than the {} bytes of device {}", size, device.id
+        )));
+    }
+
+    let mut stats = TransferStats::default();
+    let mut pos = 0;
+    let mut mapped = device.changed_ranges(None)?;
+    mapped.push((size.div_ceil(block_size), size.div_ceil(block_size))); //sentinel so the tail hole gets handled
+    for (first, end) in mapped {
+        //the last block of a device whose size is not block aligned is only partly inside it
+        let (start, end) = ((first * block_size).min(size), (end * block_size).min(size));
+        if start > pos {
+            if needs_clear {
+                clear(file, pos, start - pos, &mut punch)?;
+            }
+            stats.hole_bytes += start - pos;
+        }
+        let mut chunk_start = start;
+        while chunk_start < end {
+            let chunk_end = (chunk_start + MAX_PAYLOAD_BLOCKS * block_size).min(end);
+            let data = device.read(chunk_start, (chunk_end - chunk_start) as usize)?;
+            if data.iter().all(|&b| b == 0) {
+                if needs_clear {
+                    clear(file, chunk_start, data.len() as u64, &mut punch)?;
+                }
+                stats.hole_bytes += data.len() as u64;
+            } else {
+                file.write_all_at(&data, chunk_start)?;
+                stats.data_bytes += data.len() as u64;
+            }
+            chunk_start = chunk_end;
+        }
+        pos = pos.max(end);
+    }
+    file.sync_all()?;
+    Ok(stats)
+}
+
+//makes [offset, offset+len) read as zeros, punching a hole when the target supports it
+fn clear(file: &File, offset: u64, len: u64, punch: &mut bool) -> StorageResult<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    if *punch {
+        let mode = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+        match fallocate(file.as_raw_fd(), mode, offset as i64, len as i64) {
+            Ok(()) => return Ok(()),
+            Err(Errno::EOPNOTSUPP) => *punch = false,
+            Err(e) => return Err(StorageError::Io(e.into())),
+        }
+    }
+    let zeros = vec![0u8; (MAX_PAYLOAD_BLOCKS * 512).min(len) as usize];
+    let mut pos = offset;
+    while pos < offset + len {
+        let n = (offset + len - pos).min(zeros.len() as u64) as usize;
+        file.write_all_at(&zeros[..n], pos)?;
+        pos += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::Kvs::Kvs;
+
+    fn scratch_file(name: &str) -> (std::path::PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("raw-{}-{}", name, std::process::id()));
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn round_trips_sparse_images() {
+        let (source_path, source) = scratch_file("source");
+        source.set_len(3 * 1024 * 1024 + 100).unwrap();
+        source.write_all_at(&[0xabu8; 5000], 4096).unwrap();
+        source.write_all_at(&[0xcdu8; 100], 3 * 1024 * 1024).unwrap();
+
+        let kvs = Kvs::in_memory();
+        let size = image_size(&source).unwrap().div_ceil(512) * 512;
+        let mut device = BlockDevice::new(1, size);
+        device.attach(kvs.clone());
+        let mut reader = RawReader::new(source.try_clone().unwrap()).unwrap();
+        let stats = crate::image::import(&mut device, &mut reader, &kvs).unwrap();
+        assert_eq!(stats.data_bytes, 5120 + 512);
+        assert_eq!(device.usage().unwrap().allocated_bytes, 5120 + 512);
+
+        //exporting over a file full of data has to punch the holes back in
+        let (target_path, target) = scratch_file("target");
+        target.write_all_at(&vec![0xffu8; 4 * 1024 * 1024], 0).unwrap();
+        let stats = export(&mut device, &target).unwrap();
+        assert_eq!(stats.data_bytes, 5120 + 512);
+        let exported = std::fs::read(&target_path).unwrap();
+        let mut expected = std::fs::read(&source_path).unwrap();
+        expected.resize(size as usize, 0);
+        assert_eq!(exported, expected);
+
+        std::fs::remove_file(source_path).unwrap();
+        std::fs::remove_file(target_path).unwrap();
+    }
+
+    #[test]
+    fn exports_devices_ending_mid_block() {
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(1, 3000);
+        device.attach(kvs.clone());
+        device.write(0, &[0x5au8; 700]).unwrap();
+        device.write(2600, &[0xa5u8; 400]).unwrap();
+
+        let (path, target) = scratch_file("unaligned");
+        target.write_all_at(&[0xffu8; 4096], 0).unwrap();
+        let stats = export(&mut device, &target).unwrap();
+        assert_eq!(stats.data_bytes + stats.hole_bytes, 3000);
+        let exported = std::fs::read(&path).unwrap();
+        assert_eq!(exported, device.read(0, 3000).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
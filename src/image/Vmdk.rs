@@ -0,0 +1,346 @@
+use flate2::{Decompress, FlushDecompress};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use crate::image::{merge_ranges, ImageReader};
+use crate::utils::Error::{StorageError, StorageResult};
+
+// Hosted sparse extents as described in VMware's "Virtual Disk Format 5.0". All integers are
+// little endian and offsets count 512 byte sectors. A grain directory points at grain tables,
+// whose entries point at grains. monolithicSparse and streamOptimized files are read, split
+// extents and delta disks with a parent are not.
+pub const MAGIC: [u8; 4] = *b"KDMV";
+const SECTOR: u64 = 512;
+const HEADER_LEN: usize = 512;
+const GD_AT_END: u64 = u64::MAX; //streamOptimized, the real header is in the footer
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const COMPRESS_DEFLATE: u16 = 1;
+const MAX_GRAIN_SECTORS: u64 = 2048; //1 MiB
+const MAX_GTES_PER_GT: u32 = 4096;
+const MAX_DESCRIPTOR_SECTORS: u64 = 2048;
+const MAX_GD_ENTRIES: u64 = 32 * 1024 * 1024; //128 MiB grain directory
+const GRAIN_MARKER_LEN: usize = 12; //lba (u64) and compressed size (u32) ahead of a compressed grain
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    flags: u32,
+    capacity: u64, //sectors
+    grain_size: u64, //sectors
+    descriptor_offset: u64,
+    descriptor_size: u64,
+    gtes_per_gt: u32,
+    gd_offset: u64,
+    compress_algorithm: u16,
+}
+
+fn le_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().expect("slice of 2 bytes"))
+}
+
+fn le_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().expect("slice of 4 bytes"))
+}
+
+fn le_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().expect("slice of 8 bytes"))
+}
+
+fn corrupt(msg: String) -> StorageError {
+    StorageError::Corruption(format!("vmdk: {}", msg))
+}
+
+fn parse_header(buf: &[u8]) -> StorageResult<Header> {
+    if buf.len() < HEADER_LEN || buf[..4] != MAGIC {
+        return Err(corrupt("not a sparse extent".into()));
+    }
+    let version = le_u32(buf, 4);
+    if !(1..=3).contains(&version) {
+        return Err(StorageError::InvalidArgument(format!("vmdk sparse extent version {} is not supported", version)));
+    }
+    Ok(Header {
+        flags: le_u32(buf, 8),
+        capacity: le_u64(buf, 12),
+        grain_size: le_u64(buf, 20),
+        descriptor_offset: le_u64(buf, 28),
+        descriptor_size: le_u64(buf, 36),
+        gtes_per_gt: le_u32(buf, 44),
+        gd_offset: le_u64(buf, 56),
+        compress_algorithm: le_u16(buf, 77),
+    })
+}
+
+fn read_header(file: &File) -> StorageResult<Header> {
+    let mut buf = vec![0u8; HEADER_LEN];
+    file.read_exact_at(&mut buf, 0)?;
+    let mut header = parse_header(&buf)?;
+    if header.gd_offset == GD_AT_END {
+        //footer header sits ahead of the end-of-stream marker: ... | footer | end of stream
+        let len = file.metadata()?.len();
+        if len < 3 * SECTOR {
+            return Err(corrupt("stream is too short for a footer".into()));
+        }
+        file.read_exact_at(&mut buf, len - 2 * SECTOR)?;
+        header = parse_header(&buf)?;
+        if header.gd_offset == GD_AT_END {
+            return Err(corrupt("footer does not locate the grain directory".into()));
+        }
+    }
+    if !header.grain_size.is_power_of_two() || header.grain_size < 8 || header.grain_size > MAX_GRAIN_SECTORS {
+        return Err(corrupt(format!("grain size of {} sectors", header.grain_size)));
+    }
+    if header.gtes_per_gt == 0 || header.gtes_per_gt > MAX_GTES_PER_GT {
+        return Err(corrupt(format!("{} entries per grain table", header.gtes_per_gt)));
+    }
+    if header.capacity.checked_mul(SECTOR).is_none() {
+        return Err(corrupt(format!("capacity of {} sectors", header.capacity)));
+    }
+    if header.flags & FLAG_COMPRESSED != 0 && header.compress_algorithm != COMPRESS_DEFLATE {
+        return Err(StorageError::InvalidArgument(format!(
+            "vmdk compression algorithm {} is not supported", header.compress_algorithm
+        )));
+    }
+    Ok(header)
+}
+
+//refuses delta disks, their unallocated grains would have to come from the parent
+fn check_descriptor(file: &File, header: &Header) -> StorageResult<()> {
+    if header.descriptor_offset == 0 || header.descriptor_size == 0 {
+        return Ok(());
+    }
+    if header.descriptor_size > MAX_DESCRIPTOR_SECTORS {
+        return Err(corrupt(format!("descriptor of {} sectors", header.descriptor_size)));
+    }
+    let mut buf = vec![0u8; (header.descriptor_size * SECTOR) as usize];
+    file.read_exact_at(&mut buf, header.descriptor_offset * SECTOR)?;
+    let text = String::from_utf8_lossy(&buf);
+    for line in text.lines() {
+        if let Some(cid) = line.trim().strip_prefix("parentCID=")
+            && !cid.trim().eq_ignore_ascii_case("ffffffff")
+        {
+            return Err(StorageError::InvalidArgument("vmdk delta disks with a parent are not supported".into()));
+        }
+    }
+    Ok(())
+}
+
+pub struct VmdkReader {
+    file: File,
+    header: Header,
+    directory: Vec<u32>, //grain table offsets in sectors, 0 for none
+    table_cache: Option<(u32, Vec<u32>)>, //last grain table read
+    grain_cache: Option<(u32, Vec<u8>)>, //last compressed grain inflated
+}
+
+impl VmdkReader {
+    pub fn open(file: File) -> StorageResult<Self> {
+        let header = read_header(&file)?;
+        check_descriptor(&file, &header)?;
+        let grains = header.capacity.div_ceil(header.grain_size);
+        let tables = grains.div_ceil(header.gtes_per_gt as u64);
+        //sized from the header, so check it against the file before allocating
+        let file_len = file.metadata()?.len();
+        let gd_end = header.gd_offset.checked_mul(SECTOR).and_then(|at| at.checked_add(tables * 4));
+        if tables > MAX_GD_ENTRIES || gd_end.is_none_or(|end| end > file_len) {
+            return Err(corrupt(format!(
+                "grain directory of {} entries at sector {} does not fit the file", tables, header.gd_offset
+            )));
+        }
+        let mut directory = vec![0u8; (tables * 4) as usize];
+        file.read_exact_at(&mut directory, header.gd_offset * SECTOR)?;
+        let directory = directory.chunks(4).map(|e| le_u32(e, 0)).collect();
+        Ok(VmdkReader { file, header, directory, table_cache: None, grain_cache: None })
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.header.grain_size * SECTOR
+    }
+
+    //sector offset of the grain, None when it is not allocated
+    fn grain(&mut self, grain_index: u64) -> StorageResult<Option<u32>> {
+        let per_table = self.header.gtes_per_gt as u64;
+        let table_offset = match self.directory.get((grain_index / per_table) as usize) {
+            Some(&offset) if offset != 0 => offset,
+            _ => return Ok(None),
+        };
+        if self.table_cache.as_ref().map(|(cached, _)| *cached) != Some(table_offset) {
+            let mut bytes = vec![0u8; (per_table * 4) as usize];
+            self.file.read_exact_at(&mut bytes, table_offset as u64 * SECTOR)?;
+            self.table_cache = Some((table_offset, bytes.chunks(4).map(|e| le_u32(e, 0)).collect()));
+        }
+        let table = &self.table_cache.as_ref().expect("cache was just filled").1;
+        //1 marks a grain that reads as zeros
+        Ok(match table[(grain_index % per_table) as usize] {
+            0 | 1 => None,
+            offset => Some(offset),
+        })
+    }
+
+    fn compressed_grain(&mut self, offset: u32) -> StorageResult<&[u8]> {
+        if self.grain_cache.as_ref().map(|(cached, _)| *cached) != Some(offset) {
+            let at = offset as u64 * SECTOR;
+            let mut marker = [0u8; GRAIN_MARKER_LEN];
+            self.file.read_exact_at(&mut marker, at)?;
+            let len = le_u32(&marker, 8) as u64;
+            if len > 2 * self.grain_bytes() {
+                return Err(corrupt(format!("compressed grain at sector {} claims {} bytes", offset, len)));
+            }
+            let mut compressed = vec![0u8; len as usize];
+            self.file.read_exact_at(&mut compressed, at + GRAIN_MARKER_LEN as u64)?;
+            let mut grain = vec![0u8; self.grain_bytes() as usize];
+            let mut inflater = Decompress::new(true);
+            inflater.decompress(&compressed, &mut grain, FlushDecompress::Finish)
+                .map_err(|e| corrupt(format!("compressed grain at sector {}: {}", offset, e)))?;
+            //the last grain of a disk may inflate short, the rest of it reads as zeros
+            self.grain_cache = Some((offset, grain));
+        }
+        Ok(&self.grain_cache.as_ref().expect("cache was just filled").1)
+    }
+}
+
+impl ImageReader for VmdkReader {
+    fn size(&self) -> u64 {
+        self.header.capacity * SECTOR
+    }
+
+    fn allocated(&mut self) -> StorageResult<Vec<(u64, u64)>> {
+        let grain_bytes = self.grain_bytes();
+        let size = self.size();
+        let grains = self.header.capacity.div_ceil(self.header.grain_size);
+        let per_table = self.header.gtes_per_gt as u64;
+        let mut ranges = Vec::new();
+        for table in 0..self.directory.len() as u64 {
+            if self.directory[table as usize] == 0 {
+                continue;
+            }
+            for grain_index in table * per_table..((table + 1) * per_table).min(grains) {
+                if self.grain(grain_index)?.is_some() {
+                    let start = grain_index * grain_bytes;
+                    ranges.push((start, (start + grain_bytes).min(size)));
+                }
+            }
+        }
+        Ok(merge_ranges(ranges))
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> StorageResult<()> {
+        let grain_bytes = self.grain_bytes();
+        let size = self.size();
+        let compressed = self.header.flags & FLAG_COMPRESSED != 0;
+        let mut done = 0usize;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = pos % grain_bytes;
+            let n = ((grain_bytes - within) as usize).min(buf.len() - done);
+            let out = &mut buf[done..done + n];
+            let grain = if pos < size { self.grain(pos / grain_bytes)? } else { None };
+            match grain {
+                None => out.fill(0),
+                Some(offset) if compressed => {
+                    let grain = self.compressed_grain(offset)?;
+                    out.copy_from_slice(&grain[within as usize..within as usize + n]);
+                }
+                Some(offset) => self.file.read_exact_at(out, offset as u64 * SECTOR + within)?,
+            }
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression as Level;
+    use std::io::Write;
+
+    const GRAIN: u64 = 16; //sectors
+    const CAPACITY: u64 = 100; //sectors, the last grain is partial
+
+    //grain 0 and the partial grain 6 hold data, grain 2 is marked zero
+    fn build(name: &str, compressed: bool, descriptor: &str) -> (std::path::PathBuf, Vec<u8>) {
+        let mut expected = vec![0u8; (CAPACITY * SECTOR) as usize];
+        expected[..(GRAIN * SECTOR) as usize].fill(0x31);
+        expected[(6 * GRAIN * SECTOR) as usize..].fill(0x62);
+
+        let mut image = vec![0u8; 8 * SECTOR as usize];
+        image[..4].copy_from_slice(&MAGIC);
+        image[4..8].copy_from_slice(&(if compressed { 3u32 } else { 1 }).to_le_bytes());
+        image[8..12].copy_from_slice(&(if compressed { FLAG_COMPRESSED } else { 0 }).to_le_bytes());
+        image[12..20].copy_from_slice(&CAPACITY.to_le_bytes());
+        image[20..28].copy_from_slice(&GRAIN.to_le_bytes());
+        image[28..36].copy_from_slice(&1u64.to_le_bytes());
+        image[36..44].copy_from_slice(&1u64.to_le_bytes());
+        image[44..48].copy_from_slice(&512u32.to_le_bytes());
+        image[56..64].copy_from_slice(&2u64.to_le_bytes());
+        image[77..79].copy_from_slice(&(if compressed { COMPRESS_DEFLATE } else { 0 }).to_le_bytes());
+        image[SECTOR as usize..SECTOR as usize + descriptor.len()].copy_from_slice(descriptor.as_bytes());
+        image[2 * SECTOR as usize..2 * SECTOR as usize + 4].copy_from_slice(&3u32.to_le_bytes());
+        image.resize(((3 + 4) * SECTOR) as usize, 0); //grain table of 512 entries at sector 3
+        let gte = |image: &mut Vec<u8>, index: usize, value: u32| {
+            let at = 3 * SECTOR as usize + index * 4;
+            image[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        gte(&mut image, 2, 1);
+        for grain_index in [0usize, 6] {
+            let start = grain_index * (GRAIN * SECTOR) as usize;
+            let data = &expected[start..(start + (GRAIN * SECTOR) as usize).min(expected.len())];
+            let offset = (image.len() as u64 / SECTOR) as u32;
+            gte(&mut image, grain_index, offset);
+            if compressed {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(data).unwrap();
+                let stream = encoder.finish().unwrap();
+                image.extend((grain_index as u64 * GRAIN).to_le_bytes());
+                image.extend((stream.len() as u32).to_le_bytes());
+                image.extend(stream);
+            } else {
+                image.extend(data);
+            }
+            image.resize(image.len().div_ceil(SECTOR as usize) * SECTOR as usize, 0);
+        }
+        let path = std::env::temp_dir().join(format!("vmdk-{}-{}", name, std::process::id()));
+        std::fs::write(&path, image).unwrap();
+        (path, expected)
+    }
+
+    fn read_all(reader: &mut VmdkReader) -> Vec<u8> {
+        let mut buf = vec![0u8; reader.size() as usize];
+        reader.read_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_allocated_grains() {
+        for compressed in [false, true] {
+            let (path, expected) = build(&format!("sparse-{}", compressed), compressed, "parentCID=ffffffff\n");
+            let mut reader = VmdkReader::open(File::open(&path).unwrap()).unwrap();
+            assert_eq!(reader.size(), CAPACITY * SECTOR);
+            assert_eq!(
+                reader.allocated().unwrap(),
+                vec![(0, GRAIN * SECTOR), (6 * GRAIN * SECTOR, CAPACITY * SECTOR)]
+            );
+            assert_eq!(read_all(&mut reader), expected);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_grain_directories_larger_than_the_file() {
+        let (path, _) = build("huge", false, "");
+        let mut image = std::fs::read(&path).unwrap();
+        for (capacity, gtes_per_gt) in [(1u64 << 55, 1u32), (1 << 30, 512)] {
+            image[12..20].copy_from_slice(&capacity.to_le_bytes());
+            image[44..48].copy_from_slice(&gtes_per_gt.to_le_bytes());
+            std::fs::write(&path, &image).unwrap();
+            assert!(matches!(VmdkReader::open(File::open(&path).unwrap()), Err(StorageError::Corruption(_))));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_delta_disks() {
+        let (path, _) = build("delta", false, "parentCID=1234abcd\n");
+        assert!(matches!(VmdkReader::open(File::open(&path).unwrap()), Err(StorageError::InvalidArgument(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+pub mod Qcow2;
+pub mod Raw;
+pub mod Vmdk;
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use crate::manager::Kvs::Kvs;
+use crate::storage::BlockDevice::BlockDevice;
+use crate::storage::Payload::MAX_PAYLOAD_BLOCKS;
+use crate::utils::Error::{StorageError, StorageResult};
+
+//bytes imported between flushes, so dirty pages of large images do not pile up in memory
+pub const FLUSH_EVERY_BYTES: u64 = 1 << 30;
+//backing chains deeper than this are taken for a loop
+pub const MAX_BACKING_DEPTH: usize = 16;
+
+//What an import or export moved, holes are logical bytes that were skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    pub data_bytes: u64,
+    pub hole_bytes: u64,
+}
+
+//Read side of an image format, as seen by the guest
+pub trait ImageReader {
+    //virtual size in bytes
+    fn size(&self) -> u64;
+    //sorted, non-overlapping byte ranges that may hold data, everything else reads as zeros
+    fn allocated(&mut self) -> StorageResult<Vec<(u64, u64)>>;
+    //fills `buf` from the virtual disk at `offset`, unallocated parts read as zeros
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> StorageResult<()>;
+}
+
+//opens an image by looking at its first bytes, anything unrecognised is taken as raw
+pub fn open_image(path: &Path) -> StorageResult<Box<dyn ImageReader>> {
+    open_image_at_depth(path, 0)
+}
+
+pub(crate) fn open_image_at_depth(path: &Path, depth: usize) -> StorageResult<Box<dyn ImageReader>> {
+    if depth > MAX_BACKING_DEPTH {
+        return Err(StorageError::InvalidArgument(format!(
+            "backing chain of {} is deeper than {} images", path.display(), MAX_BACKING_DEPTH
+        )));
+    }
+    let file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    let read = file.read_at(&mut magic, 0)?;
+    if read == magic.len() && magic == Qcow2::MAGIC {
+        return Ok(Box::new(Qcow2::Qcow2Reader::open(file, path, depth)?));
+    }
+    if read == magic.len() && magic == Vmdk::MAGIC {
+        return Ok(Box::new(Vmdk::VmdkReader::open(file)?));
+    }
+    Ok(Box::new(Raw::RawReader::new(file)?))
+}
+
+//Copies an image into `device`, reading only its allocated ranges and leaving zero blocks unmapped.
+//The device has to be at least as large as the image.
+pub fn import(device: &mut BlockDevice, image: &mut dyn ImageReader, kvs: &Kvs) -> StorageResult<TransferStats> {
+    let len = image.size();
+    if len > device.logical_size_bytes {
+        return Err(StorageError::InvalidArgument(format!(
+            "image of {} bytes does not fit device {} of {} bytes", len, device.id, device.logical_size_bytes
+        )));
+    }
+    let block_size = device.block_size_bytes as u64;
+    let chunk_bytes = MAX_PAYLOAD_BLOCKS * block_size;
+    let mut stats = TransferStats::default();
+    let mut flushed_at = 0;
+    let mut covered = 0;
+    for (start, end) in image.allocated()? {
+        let start = (start / block_size * block_size).max(covered);
+        let end = end.min(len).div_ceil(block_size) * block_size;
+        if start >= end {
+            continue;
+        }
+        stats.hole_bytes += start - covered;
+        let mut pos = start;
+        while pos < end {
+            let chunk_end = (pos + chunk_bytes).min(end);
+            let mut chunk = vec![0u8; (chunk_end - pos) as usize];
+            //the last block of an image that is not block aligned reads short and stays zero padded
+            let readable = len.saturating_sub(pos).min(chunk.len() as u64) as usize;
+            image.read_at(&mut chunk[..readable], pos)?;
+            write_nonzero_blocks(device, pos, &chunk, &mut stats)?;
+            flush_periodically(device, kvs, &stats, &mut flushed_at)?;
+            pos = chunk_end;
+        }
+        covered = end;
+    }
+    stats.hole_bytes += device.logical_size_bytes.saturating_sub(covered);
+    device.flush(kvs)?;
+    Ok(stats)
+}
+
+//Writes the runs of non-zero blocks in `data` at `byte_offset`, all-zero blocks stay holes.
+//`byte_offset` and the length of `data` have to be block aligned.
+pub fn write_nonzero_blocks(device: &mut BlockDevice, byte_offset: u64, data: &[u8], stats: &mut TransferStats) -> StorageResult<()> {
+    let block_size = device.block_size_bytes;
+    let mut run_start: Option<usize> = None;
+    for (i, block) in data.chunks(block_size).enumerate() {
+        let zero = block.iter().all(|&b| b == 0);
+        match (zero, run_start) {
+            (false, None) => run_start = Some(i),
+            (true, Some(start)) => {
+                device.write(byte_offset + (start * block_size) as u64, &data[start * block_size..i * block_size])?;
+                run_start = None;
+            }
+            _ => {}
+        }
+        if zero {
+            stats.hole_bytes += block.len() as u64;
+        } else {
+            stats.data_bytes += block.len() as u64;
+        }
+    }
+    if let Some(start) = run_start {
+        device.write(byte_offset + (start * block_size) as u64, &data[start * block_size..])?;
+    }
+    Ok(())
+}
+
+//flushes once another FLUSH_EVERY_BYTES of data went in since the last call that flushed
+pub fn flush_periodically(device: &mut BlockDevice, kvs: &Kvs, stats: &TransferStats, flushed_at: &mut u64) -> StorageResult<()> {
+    if stats.data_bytes - *flushed_at >= FLUSH_EVERY_BYTES {
+        device.flush(kvs)?;
+        *flushed_at = stats.data_bytes;
+    }
+    Ok(())
+}
+
+//merges sorted ranges that touch or overlap
+pub fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
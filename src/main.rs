@@ -1,14 +1,52 @@
+#![allow(non_snake_case)]
+
 use anyhow::{bail, Context, Result};
-use libc::{c_ulong, ioctl, ENOTTY, EOPNOTSUPP};
+use log::{debug, error, info, log, warn, Level};
+use libc::{c_ulong, ioctl, EOPNOTSUPP};
 use nix::fcntl::{open, OFlag};
 use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
 use nix::sys::stat::Mode;
+use std::collections::BTreeMap;
 use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::UnixStream;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 
+#[cfg_attr(not(feature = "fuse"), allow(dead_code))] // without fuse only mkfs uses it
+mod filesystem;
+mod image;
+mod manager;
+mod nbd;
+mod storage;
+mod utils;
+
+use crate::filesystem::FileSystem::FileSystem;
+use crate::image::{Qcow2, Raw};
+use crate::manager::Config::ServeConfig;
+use crate::manager::Control::{self, Controller, Request};
+use crate::manager::Exporter;
+use crate::manager::Gc::{self, GcOptions};
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::manager::Pool::Pool;
+use crate::manager::Qos::{self, Held, IoKind, Limit, QosLimits, Throttle};
+use crate::manager::Registry::{self, DeviceEntry, DeviceFilter};
+use crate::manager::Replay;
+use crate::manager::Scrub::Scrubber;
+use crate::nbd::Netlink::NbdNetlink;
+use crate::nbd::Trace::{TraceReader, TraceWriter};
+use crate::storage::BlockDevice::{self as block_device, BlockDevice};
+use crate::storage::Delta;
+use crate::storage::Encryption::MasterKey;
+use crate::storage::Snapshot::Snapshot;
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::Logging;
+use crate::utils::Metrics::DeviceMetrics;
+
 // ===== Linux UAPI: include/uapi/linux/nbd.h =====
 // _IO(0xab, X)
 // Linux _IOC encoding:
@@ -24,6 +62,19 @@ const NBD_SET_SIZE: c_ulong    = ioc_none(0xab, 2);
 const NBD_DO_IT: c_ulong       = ioc_none(0xab, 3);
 const NBD_CLEAR_SOCK: c_ulong  = ioc_none(0xab, 4);
 const NBD_CLEAR_QUE: c_ulong   = ioc_none(0xab, 5);
+const NBD_DISCONNECT: c_ulong  = ioc_none(0xab, 8);
+const NBD_SET_FLAGS: c_ulong   = ioc_none(0xab, 10);
+
+// Transmission flags advertised through NBD_SET_FLAGS
+const NBD_FLAG_HAS_FLAGS: c_ulong  = 1 << 0;
+const NBD_FLAG_SEND_FLUSH: c_ulong = 1 << 2;
+const NBD_FLAG_SEND_TRIM: c_ulong  = 1 << 5;
+
+// How often serve rereads the QoS limits of its device
+const QOS_REFRESH: Duration = Duration::from_secs(10);
+
+// How long a netlink attached device waits for a new daemon after its socket dies
+const DEFAULT_DEAD_CONN_TIMEOUT_SECS: u64 = 60;
 
 // NBD protocol magics
 const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
@@ -34,7 +85,8 @@ const NBD_CMD_READ: u32 = 0;
 const NBD_CMD_WRITE: u32 = 1;
 const NBD_CMD_DISC: u32 = 2;
 const NBD_CMD_FLUSH: u32 = 3;
-// others exist (TRIM, WRITE_ZEROES, etc). We’ll return EOPNOTSUPP.
+const NBD_CMD_TRIM: u32 = 4;
+// others exist (WRITE_ZEROES, etc). We’ll return EOPNOTSUPP.
 
 #[derive(Debug)]
 struct Req {
@@ -46,22 +98,704 @@ struct Req {
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    Logging::init();
+    let rest = args.get(2..).unwrap_or_default();
+    match args.get(1).map(String::as_str) {
+        None | Some("serve") => serve(rest).await,
+        Some("migrate") => migrate(),
+        Some("usage") => usage(rest),
+        Some("keygen") => keygen(rest),
+        Some("rotate-key") => rotate_key(rest),
+        Some("scrub") => scrub(rest),
+        Some("gc") => gc(rest),
+        Some("snapshot") => snapshot(rest),
+        Some("export-delta") => export_delta(rest),
+        Some("import-delta") => import_delta(rest),
+        Some("import") => import_image(rest),
+        Some("export") => export_image(rest),
+        Some("daemon") => daemon(rest).await,
+        Some("ctl") => ctl(rest),
+        Some("replay") => replay(rest),
+        Some("mkfs") => mkfs(rest),
+        Some("mount") => mount(rest).await,
+        Some(other) => bail!("unknown command {other:?}, expected one of: serve, migrate, usage, keygen, rotate-key, scrub, gc, snapshot, export-delta, import-delta, import, export, daemon, ctl, replay, mkfs, mount"),
+    }
+}
 
-    let dev_path = "/dev/nbd0";
-    let size_mib: u64 = 512; // 512 MiB
-    let size_bytes = size_mib * 1024 * 1024;
-    let blksize: u64 = 4096;
+// mkfs <name> [--block-size <size>] [--compression <codec>] [--checksum <kind>] [--pool <pool>]
+fn mkfs(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage mkfs <name> [--block-size <size>] [--compression <codec>] [--checksum <kind>] [--pool <pool>]";
+    let name = args.first().filter(|a| !a.starts_with("--")).context(USAGE)?;
+    let kvs = Kvs::new().context("connect kvs")?;
+    let mut fs = FileSystem::new(name);
+    if let Some(size) = flag_value(args, "--block-size") {
+        fs.block_size_bytes = parse_size(size)? as usize;
+    }
+    if let Some(codec) = flag_value(args, "--compression") {
+        fs.compression = codec.parse().context("--compression")?;
+    }
+    if let Some(checksum) = flag_value(args, "--checksum") {
+        fs.checksum = checksum.parse().context("--checksum")?;
+    }
+    if let Some(pool) = flag_value(args, "--pool") {
+        Pool::load(&Pool::kvs_id(pool), &kvs).with_context(|| format!("pool {pool}"))?;
+        fs.pool = Some(pool.to_string());
+    }
+    let fs = FileSystem::create(&kvs, fs).context("create filesystem")?;
+    info!("created filesystem {} with {} byte blocks", fs.name, fs.block_size_bytes);
+    Ok(())
+}
 
-    if size_bytes % blksize != 0 {
-        bail!("size must be multiple of {}", blksize);
+// mount <filesystem> <mountpoint>, until interrupted
+#[cfg(feature = "fuse")]
+async fn mount(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage mount <filesystem> <mountpoint>";
+    let (name, mountpoint) = match args {
+        [name, mountpoint, ..] => (name, Path::new(mountpoint)),
+        _ => bail!(USAGE),
+    };
+    let kvs = Kvs::new().context("connect kvs")?;
+    let fs = FileSystem::open(&kvs, name).with_context(|| format!("filesystem {name}"))?;
+    let options = ["-o", "fsname=storage", "-o", "default_permissions"].map(std::ffi::OsStr::new);
+    // the session answers the kernel on its own thread and unmounts when dropped
+    let session = unsafe { fuse::spawn_mount(filesystem::FuseFS::FuseFS::new(fs), &mountpoint, &options) }
+        .with_context(|| format!("mount {}", mountpoint.display()))?;
+    info!("mounted filesystem {} on {}", name, mountpoint.display());
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
     }
+    drop(session);
+    info!("unmounted filesystem {}", name);
+    Ok(())
+}
 
-    // backing store
-    let store = Arc::new(Mutex::new(vec![0u8; size_bytes as usize]));
+#[cfg(not(feature = "fuse"))]
+async fn mount(_args: &[String]) -> Result<()> {
+    bail!("this binary was built without fuse support, rebuild with the `fuse` feature")
+}
 
-    // open /dev/nbdX
-    let nbd_fd = open(dev_path, OFlag::O_RDWR, Mode::empty()).context("open nbd dev")?;
-    let nbd_raw = nbd_fd.as_raw_fd();
+// replay <trace> (--device <id or name> | --target <path>) [--speed <factor>] [--keyfile <keyfile>] [--report <file>]
+fn replay(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage replay <trace> (--device <id or name> | --target <path>) [--speed <factor>] [--keyfile <keyfile>] [--report <file>]";
+    let path = args.first().filter(|a| !a.starts_with("--")).context(USAGE)?;
+    let trace = TraceReader::open(Path::new(path)).with_context(|| format!("open trace {path}"))?;
+    let speed = flag_value(args, "--speed").map(|s| s.parse::<f64>().context("--speed takes a factor like 1 or 0.5")).transpose()?;
+    if speed.is_some_and(|speed| speed <= 0.0) {
+        bail!("--speed has to be above 0, leave it out to replay as fast as possible");
+    }
+    let options = Replay::ReplayOptions { speed };
+    let report = match (flag_value(args, "--device"), flag_value(args, "--target")) {
+        (Some(device), None) => {
+            let kvs = Kvs::new().context("connect kvs")?;
+            let id = resolve_device(&kvs, device)?;
+            let mut device = BlockDevice::load(&format!("BlockDevice:{}", id), &kvs).context("load block device")?;
+            if let Some(keyfile) = flag_value(args, "--keyfile") {
+                device.unlock(&MasterKey::load(Path::new(keyfile)).context("load master key")?).context("unlock data key")?;
+            }
+            let mut target = Replay::DeviceTarget::new(device, kvs.clone());
+            let report = Replay::replay(trace, &mut target, options).context("replay")?;
+            target.device.flush(&kvs).context("flush device")?;
+            report
+        }
+        (None, Some(target)) => {
+            let file = std::fs::OpenOptions::new().read(true).write(true).open(target).with_context(|| format!("open {target}"))?;
+            Replay::replay(trace, &mut Replay::FileTarget::new(file), options).context("replay")?
+        }
+        _ => bail!(USAGE),
+    };
+    info!(
+        "replayed {} request(s) in {:.2}s, recorded over {:.2}s: {} error(s), {} answered differently",
+        report.requests, report.elapsed_secs, report.recorded_secs, report.errors, report.errno_mismatches
+    );
+    if let Some(out) = flag_value(args, "--report") {
+        std::fs::write(out, serde_json::to_vec_pretty(&report)?).with_context(|| format!("write {out}"))?;
+    }
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+// Value following `--name` on the command line, if any
+fn flag_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+// Writes a new master keyfile
+fn keygen(args: &[String]) -> Result<()> {
+    let path = args.first().context("usage: storage keygen <keyfile>")?;
+    let master = MasterKey::generate();
+    master.save(Path::new(path)).context("write keyfile")?;
+    info!("wrote master key {} to {}", master.id(), path);
+    Ok(())
+}
+
+// Re-wraps the data keys of all devices sealed by the old master key, without touching payloads
+fn rotate_key(args: &[String]) -> Result<()> {
+    let old = flag_value(args, "--old").context("usage: storage rotate-key --old <keyfile> --new <keyfile>")?;
+    let new = flag_value(args, "--new").context("usage: storage rotate-key --old <keyfile> --new <keyfile>")?;
+    let old = MasterKey::load(Path::new(old)).context("load old master key")?;
+    let new = MasterKey::load(Path::new(new)).context("load new master key")?;
+    let kvs = Kvs::new().context("connect kvs")?;
+    let mut rotated = 0;
+    let mut attached = 0;
+    for key in kvs.scan_keys(&format!("{}*", block_device::KVS_PREFIX))? {
+        let mut device = BlockDevice::load(&key, &kvs).with_context(|| format!("load {key}"))?;
+        match &device.encryption {
+            Some(wrapped) if wrapped.master_key_id == old.id() => {}
+            _ => continue,
+        }
+        // the serve process holding it would store its old record over ours
+        if let Some(attachment) = Registry::Attachment::find(&kvs, device.id)? {
+            warn!(device:% = device.id; "attached to {} on {}, skipped", attachment.nbd, attachment.host);
+            attached += 1;
+            continue;
+        }
+        device.rewrap_key(&old, &new).with_context(|| format!("rewrap {key}"))?;
+        device.flush(&kvs).with_context(|| format!("store {key}"))?;
+        rotated += 1;
+    }
+    for mut snapshot in Snapshot::list_all(&kvs)? {
+        match &snapshot.device.encryption {
+            Some(wrapped) if wrapped.master_key_id == old.id() => {}
+            _ => continue,
+        }
+        let key = snapshot.get_kvs_id();
+        snapshot.device.rewrap_key(&old, &new).with_context(|| format!("rewrap {key}"))?;
+        snapshot.store(&kvs).with_context(|| format!("store {key}"))?;
+        rotated += 1;
+    }
+    info!("re-wrapped {} data key(s) from master key {} to {}", rotated, old.id(), new.id());
+    if attached > 0 {
+        bail!("{attached} attached device(s) still use master key {}, detach them and run rotate-key again", old.id());
+    }
+    Ok(())
+}
+
+// Prints logical vs physical bytes of a stored device
+fn usage(args: &[String]) -> Result<()> {
+    let device = args.first().context("usage: storage usage <device id or name>")?;
+    let kvs = Kvs::new().context("connect kvs")?;
+    let id = resolve_device(&kvs, device)?;
+    let mut device = BlockDevice::load(&format!("BlockDevice:{}", id), &kvs).context("load block device")?;
+    let usage = device.usage().context("walk block map")?;
+    println!("device {} ({})", device.id, device.compression);
+    println!("  logical size    {:>16} bytes", usage.logical_size_bytes);
+    println!("  allocated       {:>16} bytes", usage.allocated_bytes);
+    println!("  stored          {:>16} bytes in {} payload(s)", usage.stored_bytes, usage.payloads);
+    if usage.stored_bytes > 0 {
+        println!("  ratio           {:>16.2}x", usage.allocated_bytes as f64 / usage.stored_bytes as f64);
+    }
+    Ok(())
+}
+
+// Verifies every referenced payload, optionally forever, resuming an interrupted pass
+fn scrub(args: &[String]) -> Result<()> {
+    let rate = flag_value(args, "--rate")
+        .map(|r| r.parse::<u64>().context("--rate takes bytes per second"))
+        .transpose()?;
+    let interval = flag_value(args, "--interval")
+        .map(|i| i.parse::<u64>().context("--interval takes seconds"))
+        .transpose()?
+        .unwrap_or(3600);
+    let master = flag_value(args, "--keyfile")
+        .map(|path| MasterKey::load(Path::new(path)))
+        .transpose()
+        .context("load master key")?;
+    let continuous = args.iter().any(|a| a == "--continuous");
+    let kvs = Kvs::new().context("connect kvs")?;
+    let mut scrubber = Scrubber::new(kvs, master, rate);
+    loop {
+        let report = scrubber.run_pass().context("scrub pass")?;
+        info!(
+            "scrubbed {} device(s): {} payload(s), {} bytes verified, {} locked, {} corrupt, {} dangling, {} orphaned",
+            report.devices, report.payloads_verified, report.bytes_verified, report.unverified_locked,
+            report.corrupt.len(), report.dangling.len(), report.orphaned.len()
+        );
+        if let Some(path) = flag_value(args, "--report") {
+            std::fs::write(path, serde_json::to_vec_pretty(&report)?).with_context(|| format!("write {path}"))?;
+        }
+        if !continuous {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
+// Deletes pages and payloads no device or snapshot references any more
+fn gc(args: &[String]) -> Result<()> {
+    let grace_secs = flag_value(args, "--grace")
+        .map(|g| g.parse::<u64>().context("--grace takes seconds"))
+        .transpose()?
+        .unwrap_or(Gc::DEFAULT_GRACE_SECS);
+    let options = GcOptions {
+        dry_run: args.iter().any(|a| a == "--dry-run"),
+        grace_secs,
+    };
+    let kvs = Kvs::new().context("connect kvs")?;
+    let report = Gc::collect(&kvs, options).context("collect garbage")?;
+    println!("scanned {} page(s) and {} payload(s)", report.pages_scanned, report.payloads_scanned);
+    println!("  unreferenced    {:>8} page(s), {} payload(s)", report.unreferenced_pages, report.unreferenced_payloads);
+    println!("  reclaimable     {:>16} bytes", report.reclaimable_bytes);
+    if !report.dry_run {
+        println!("  deleted         {:>8} page(s), {} payload(s)", report.deleted_pages, report.deleted_payloads);
+        println!("  reclaimed       {:>16} bytes", report.reclaimed_bytes);
+    }
+    Ok(())
+}
+
+// snapshot create <id> | list <id> | delete <id> <generation>
+fn snapshot(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage snapshot create <device> | list <device> | delete <device> <generation>";
+    let kvs = Kvs::new().context("connect kvs")?;
+    let id = resolve_device(&kvs, args.get(1).context(USAGE)?)?;
+    match args.first().map(String::as_str) {
+        Some("create") => {
+            let mut device = BlockDevice::load(&format!("BlockDevice:{}", id), &kvs).context("load block device")?;
+            let snapshot = device.snapshot(&kvs).context("take snapshot")?;
+            println!("device {} snapshot at generation {}", id, snapshot.generation);
+        }
+        Some("list") => {
+            for snapshot in Snapshot::list(&kvs, id)? {
+                println!("{:>10}  created {}  {} page(s)", snapshot.generation, snapshot.created_at, snapshot.device.page_roots.len());
+            }
+        }
+        Some("delete") => {
+            let generation: u32 = args.get(2).context(USAGE)?.parse().context("generation must be a number")?;
+            Snapshot::delete(&kvs, id, generation).context("delete snapshot")?;
+            println!("deleted snapshot {} of device {}, run gc to reclaim its space", generation, id);
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+// Opens a snapshot for reading, unlocking it when a keyfile was given
+fn open_snapshot(kvs: &Kvs, id: u128, generation: u32, master: Option<&MasterKey>) -> Result<BlockDevice> {
+    let key = Snapshot::kvs_id(id, generation);
+    let mut view = Snapshot::load(&key, kvs).with_context(|| format!("load {key}"))?.open(kvs);
+    if let Some(master) = master {
+        view.unlock(master).with_context(|| format!("unlock {key}"))?;
+    }
+    Ok(view)
+}
+
+// export-delta <device> <file> --to <generation> [--from <generation>] [--keyfile <keyfile>]
+fn export_delta(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage export-delta <device> <file> --to <generation> [--from <generation>] [--keyfile <keyfile>]";
+    let device = args.first().context(USAGE)?;
+    let path = args.get(1).context(USAGE)?;
+    let to: u32 = flag_value(args, "--to").context(USAGE)?.parse().context("--to takes a generation")?;
+    let from = flag_value(args, "--from")
+        .map(|g| g.parse::<u32>().context("--from takes a generation"))
+        .transpose()?;
+    let master = flag_value(args, "--keyfile")
+        .map(|path| MasterKey::load(Path::new(path)))
+        .transpose()
+        .context("load master key")?;
+    let kvs = Kvs::new().context("connect kvs")?;
+    let id = resolve_device(&kvs, device)?;
+    let mut to_view = open_snapshot(&kvs, id, to, master.as_ref())?;
+    let mut from_view = from.map(|g| open_snapshot(&kvs, id, g, master.as_ref())).transpose()?;
+
+    //written next to the target and renamed, a failed export never leaves a partial file behind
+    let partial = format!("{path}.partial");
+    let file = std::fs::File::create(&partial).with_context(|| format!("create {partial}"))?;
+    let stats = Delta::export(&mut to_view, from_view.as_mut(), std::io::BufWriter::new(file))
+        .context("export delta")?;
+    std::fs::rename(&partial, path).with_context(|| format!("rename {partial}"))?;
+    info!(
+        "exported device {} generations {}..{}: {} data block(s), {} zeroed block(s)",
+        id, from.unwrap_or(0), to, stats.data_blocks, stats.zero_blocks
+    );
+    Ok(())
+}
+
+// import-delta <device> <file> [--keyfile <keyfile>] [--force]
+fn import_delta(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage import-delta <device> <file> [--keyfile <keyfile>] [--force]";
+    let device = args.first().context(USAGE)?;
+    let path = args.get(1).context(USAGE)?;
+    let open = || std::fs::File::open(path).map(std::io::BufReader::new).with_context(|| format!("open {path}"));
+    let header = Delta::read_header(open()?).context("read delta header")?;
+    let kvs = Kvs::new().context("connect kvs")?;
+    let id = resolve_device(&kvs, device)?;
+    let (mut device, created) = match BlockDevice::load(&format!("BlockDevice:{}", id), &kvs) {
+        Ok(device) => (device, false),
+        Err(StorageError::NotFound(_)) if header.from_generation == 0 => {
+            let mut device = BlockDevice::new(id, header.logical_size_bytes);
+            device.block_size_bytes = header.block_size_bytes;
+            device.attach(kvs.clone());
+            (device, true)
+        }
+        Err(e) => return Err(e).context("load block device"),
+    };
+    if let Some(path) = flag_value(args, "--keyfile") {
+        let master = MasterKey::load(Path::new(path)).context("load master key")?;
+        device.unlock(&master).context("unlock device")?;
+    }
+    let force = args.iter().any(|a| a == "--force");
+    let (header, stats) = Delta::apply(&mut device, open()?, force).context("apply delta")?;
+    device.flush(&kvs).context("store device")?;
+    if created {
+        Registry::register(&kvs, &DeviceEntry::for_device(&device)).context("register device")?;
+    }
+    info!(
+        "applied device {} generations {}..{} to device {}: {} data block(s), {} zeroed block(s)",
+        header.device_id, header.from_generation, header.to_generation, id, stats.data_blocks, stats.zero_blocks
+    );
+    Ok(())
+}
+
+// import <id> <image in any supported format> [--compression c] [--checksum c] [--encrypt --keyfile k] [--name n] [--label k=v]
+fn import_image(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage import <device id> <image> [--compression <codec>] [--checksum <kind>] [--encrypt --keyfile <keyfile>] \
+        [--pool <pool>] [--quota <size>] [--name <name>] [--label <key>=<value>]...";
+    let id: u128 = args.first().context(USAGE)?.parse().context("device id must be a number")?;
+    let path = args.get(1).context(USAGE)?;
+    let master = flag_value(args, "--keyfile")
+        .map(|path| MasterKey::load(Path::new(path)))
+        .transpose()
+        .context("load master key")?;
+    let mut image = image::open_image(Path::new(path)).with_context(|| format!("open {path}"))?;
+    let kvs = Kvs::new().context("connect kvs")?;
+    if kvs.get_raw(&format!("BlockDevice:{}", id))?.is_some() {
+        bail!("device {} already exists", id);
+    }
+    let block_size = block_device::DEFAULT_BLOCK_SIZE as u64;
+    let mut device = new_device(id, image.size().div_ceil(block_size) * block_size, args, master.as_ref(), &kvs)?;
+    let stats = match image::import(&mut device, image.as_mut(), &kvs) {
+        Ok(stats) => stats,
+        Err(e) => {
+            // a half imported device is not kept, emptied first so deleting it gives back all it was charged
+            let size = device.logical_size_bytes as usize;
+            let discarded = device.trim(0, size)
+                .and_then(|()| device.flush(&kvs))
+                .and_then(|()| BlockDevice::delete(&kvs, id))
+                .and_then(|()| Registry::unregister(&kvs, id));
+            if let Err(cleanup) = discarded {
+                error!(device:% = id; "could not remove the device of the failed import: {}", cleanup);
+            }
+            return Err(e).context("import image");
+        }
+    };
+    info!("imported {} into device {}: {} data bytes, {} bytes left as holes", path, id, stats.data_bytes, stats.hole_bytes);
+    Ok(())
+}
+
+// export <device> <image> [--format raw|qcow2] [--snapshot <generation>] [--keyfile k]
+fn export_image(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage export <device> <image> [--format raw|qcow2] [--snapshot <generation>] [--keyfile <keyfile>]";
+    let device = args.first().context(USAGE)?;
+    let path = args.get(1).context(USAGE)?;
+    let master = flag_value(args, "--keyfile")
+        .map(|path| MasterKey::load(Path::new(path)))
+        .transpose()
+        .context("load master key")?;
+    let kvs = Kvs::new().context("connect kvs")?;
+    let id = resolve_device(&kvs, device)?;
+    let mut device = match flag_value(args, "--snapshot") {
+        Some(generation) => {
+            let generation = generation.parse().context("--snapshot takes a generation")?;
+            open_snapshot(&kvs, id, generation, master.as_ref())?
+        }
+        None => {
+            let mut device = BlockDevice::load(&format!("BlockDevice:{}", id), &kvs).context("load block device")?;
+            if let Some(master) = &master {
+                device.unlock(master).context("unlock device")?;
+            }
+            device
+        }
+    };
+    //the format defaults to what the file name says
+    let format = flag_value(args, "--format")
+        .unwrap_or(if path.ends_with(".qcow2") { "qcow2" } else { "raw" });
+    let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+        .with_context(|| format!("open {path}"))?;
+    let stats = match format {
+        "raw" => Raw::export(&mut device, &file),
+        "qcow2" => Qcow2::export(&mut device, &file),
+        other => bail!("unknown image format {other:?}, expected raw or qcow2"),
+    }
+    .context("export image")?;
+    info!("exported device {} to {}: {} data bytes, {} bytes of holes", id, path, stats.data_bytes, stats.hole_bytes);
+    Ok(())
+}
+
+// daemon [--socket <path>] [--keyfile <keyfile>]
+async fn daemon(args: &[String]) -> Result<()> {
+    let socket = PathBuf::from(flag_value(args, "--socket").unwrap_or(Control::DEFAULT_SOCKET));
+    let kvs = Kvs::new().context("connect kvs")?;
+    let controller = Controller::new(kvs, flag_value(args, "--keyfile").map(PathBuf::from))
+        .context("load master key")?;
+    info!("listening on {}", socket.display());
+    tokio::select! {
+        res = Control::listen(&socket, controller) => res.with_context(|| format!("listen on {}", socket.display()))?,
+        _ = tokio::signal::ctrl_c() => info!("stopping, attached devices keep being served"),
+    }
+    let _ = std::fs::remove_file(&socket);
+    Ok(())
+}
+
+// ctl <op> [args] [--socket <path>], a thin client of the daemon printing its JSON result
+fn ctl(args: &[String]) -> Result<()> {
+    const USAGE: &str = "usage: storage ctl create <id> <size> [--compression <codec>] [--checksum <kind>] [--encrypt] \
+        [--name <name>] [--label <key>=<value>]... [--pool <pool>] [--quota <size>] | list [--label <key>=<value>]... [--host <host>] [--attached|--detached] \
+        | inspect <device> | resize <device> <size> | snapshot <device> \
+        | clone <device> <new id> [--name <name>] [--snapshot <generation>] | rename <device> <name>|--clear \
+        | label <device> [--label <key>=<value>]... [--remove <key>]... | quota <device> <size>|--clear \
+        | qos <device> [--read-iops <rate>[:<burst>]] [--write-iops ..] [--read-bps <size>[:<size>]] [--write-bps ..] \
+        | pool create <name> <capacity> [--watermark <percent>] | pool list | pool delete <name> | pool recount \
+        | attach <device> <nbd device> [--metrics <addr:port>] | detach <device> | delete <device>  [--socket <path>] \
+        where <device> is a device id or name";
+    let id = |i: usize| -> Result<u128> { args.get(i).context(USAGE)?.parse().context("device id must be a number") };
+    let device = |i: usize| -> Result<String> { args.get(i).cloned().context(USAGE) };
+    let size = |i: usize| -> Result<u64> { parse_size(args.get(i).context(USAGE)?) };
+    let request = match args.first().map(String::as_str) {
+        Some("create") => Request::Create {
+            id: id(1)?,
+            size_bytes: size(2)?,
+            compression: flag_value(args, "--compression").map(String::from),
+            checksum: flag_value(args, "--checksum").map(String::from),
+            encrypt: args.iter().any(|a| a == "--encrypt"),
+            name: flag_value(args, "--name").map(String::from),
+            labels: labels(args)?,
+            pool: flag_value(args, "--pool").map(String::from),
+            quota_bytes: flag_value(args, "--quota").map(parse_size).transpose()?,
+        },
+        Some("list") => Request::List {
+            filter: DeviceFilter {
+                labels: labels(args)?,
+                host: flag_value(args, "--host").map(String::from),
+                attached: match (args.iter().any(|a| a == "--attached"), args.iter().any(|a| a == "--detached")) {
+                    (true, true) => bail!("--attached and --detached exclude each other"),
+                    (true, false) => Some(true),
+                    (false, true) => Some(false),
+                    (false, false) => None,
+                },
+            },
+        },
+        Some("inspect") => Request::Inspect { id: device(1)? },
+        Some("resize") => Request::Resize { id: device(1)?, size_bytes: size(2)? },
+        Some("snapshot") => Request::Snapshot { id: device(1)? },
+        Some("clone") => Request::Clone {
+            id: device(1)?,
+            new_id: id(2)?,
+            new_name: flag_value(args, "--name").map(String::from),
+            generation: flag_value(args, "--snapshot")
+                .map(|g| g.parse().context("--snapshot takes a generation"))
+                .transpose()?,
+        },
+        Some("rename") => Request::Rename {
+            id: device(1)?,
+            name: match args.get(2).context(USAGE)?.as_str() {
+                "--clear" => None,
+                name => Some(name.to_string()),
+            },
+        },
+        Some("label") => Request::Label {
+            id: device(1)?,
+            set: labels(args)?,
+            remove: args.windows(2).filter(|pair| pair[0] == "--remove").map(|pair| pair[1].clone()).collect(),
+        },
+        Some("quota") => Request::SetQuota {
+            id: device(1)?,
+            quota_bytes: match args.get(2).context(USAGE)?.as_str() {
+                "--clear" => None,
+                quota => Some(parse_size(quota)?),
+            },
+        },
+        Some("pool") => match args.get(1).map(String::as_str) {
+            Some("create") => Request::CreatePool {
+                name: device(2)?,
+                capacity_bytes: size(3)?,
+                high_watermark_percent: flag_value(args, "--watermark")
+                    .map(|p| p.parse().context("--watermark takes a percentage"))
+                    .transpose()?,
+            },
+            Some("list") => Request::ListPools,
+            Some("delete") => Request::DeletePool { name: device(2)? },
+            Some("recount") => Request::RecountPools,
+            _ => bail!(USAGE),
+        },
+        Some("qos") => {
+            let count = |s: &str| s.parse().ok();
+            let bytes = |s: &str| parse_size(s).ok();
+            let limit = |flag: &str, amount: &dyn Fn(&str) -> Option<u64>| {
+                flag_value(args, flag).map(|l| Limit::parse(l, amount).with_context(|| flag.to_string())).transpose()
+            };
+            Request::SetQos {
+                id: device(1)?,
+                read_iops: limit("--read-iops", &count)?,
+                write_iops: limit("--write-iops", &count)?,
+                read_bytes: limit("--read-bps", &bytes)?,
+                write_bytes: limit("--write-bps", &bytes)?,
+            }
+        }
+        Some("attach") => Request::Attach {
+            id: device(1)?,
+            nbd: args.get(2).context(USAGE)?.clone(),
+            metrics: flag_value(args, "--metrics").map(str::to_string),
+        },
+        Some("detach") => Request::Detach { id: device(1)? },
+        Some("delete") => Request::Delete { id: device(1)? },
+        _ => bail!(USAGE),
+    };
+    let socket = flag_value(args, "--socket").unwrap_or(Control::DEFAULT_SOCKET);
+    let response = Control::call(Path::new(socket), &request).with_context(|| format!("call daemon on {socket}"))?;
+    if !response.ok {
+        bail!("{}", response.error.unwrap_or_default());
+    }
+    if !response.result.is_null() {
+        println!("{}", serde_json::to_string_pretty(&response.result)?);
+    }
+    Ok(())
+}
+
+// Device id, or the name of a registered device
+fn resolve_device(kvs: &Kvs, device: &str) -> Result<u128> {
+    Registry::resolve(kvs, device).with_context(|| format!("look up device {device:?}"))
+}
+
+// Every --label key=value on the command line
+fn labels(args: &[String]) -> Result<BTreeMap<String, String>> {
+    args.windows(2)
+        .filter(|pair| pair[0] == "--label")
+        .map(|pair| {
+            let (key, value) = pair[1].split_once('=').with_context(|| format!("--label takes key=value, got {:?}", pair[1]))?;
+            Ok((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// Byte count with an optional K, M, G or T suffix (powers of 1024)
+fn parse_size(s: &str) -> Result<u64> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let n: u64 = digits.parse().with_context(|| format!("bad size {s:?}"))?;
+    n.checked_mul(1 << shift).with_context(|| format!("size {s:?} is too large"))
+}
+
+// Rewrites every stored BlockDevice record in the current format
+fn migrate() -> Result<()> {
+    let kvs = Kvs::new().context("connect kvs")?;
+    let migrated = BlockDevice::migrate_all(&kvs).context("migrate block devices")?;
+    info!("migrated {} BlockDevice record(s)", migrated);
+    Ok(())
+}
+
+// New device configured by --compression, --checksum, --encrypt, --pool, --quota, --name and --label,
+// stored and registered
+fn new_device(id: u128, size_bytes: u64, args: &[String], master: Option<&MasterKey>, kvs: &Kvs) -> Result<BlockDevice> {
+    let mut device = BlockDevice::new(id, size_bytes);
+    if let Some(codec) = flag_value(args, "--compression") {
+        device.compression = codec.parse().context("--compression")?;
+    }
+    if let Some(checksum) = flag_value(args, "--checksum") {
+        device.checksum = checksum.parse().context("--checksum")?;
+    }
+    if let Some(pool) = flag_value(args, "--pool") {
+        Pool::load(&Pool::kvs_id(pool), kvs).with_context(|| format!("pool {pool}"))?;
+        device.pool = Some(pool.to_string());
+    }
+    device.quota_bytes = flag_value(args, "--quota").map(parse_size).transpose()?;
+    if args.iter().any(|a| a == "--encrypt") {
+        let master = master.context("--encrypt needs --keyfile")?;
+        device.enable_encryption(master).context("enable encryption")?;
+    }
+    device.attach(kvs.clone());
+    let entry = DeviceEntry {
+        name: flag_value(args, "--name").map(String::from),
+        labels: labels(args)?,
+        ..DeviceEntry::for_device(&device)
+    };
+    if let Some(name) = &entry.name {
+        Registry::check_name(kvs, name).context("--name")?;
+    }
+    device.flush(kvs).context("store device")?;
+    Registry::register(kvs, &entry).context("register device")?;
+    Ok(device)
+}
+
+// serve [--device <id or name>] [--nbd <path>] [--size <size>] [--netlink [--dead-conn-timeout <secs>]] [--config <file>] [--keyfile k]
+//       [--metrics <addr:port>] [--trace <file> [--trace-hashes]]
+async fn serve(args: &[String]) -> Result<()> {
+    let dev_path = flag_value(args, "--nbd").unwrap_or("/dev/nbd0");
+    let size = flag_value(args, "--size").map(parse_size).transpose()?;
+    // checked before a new device gets stored with it, like ctl create and resize do
+    let block_size = block_device::DEFAULT_BLOCK_SIZE as u64;
+    if size.is_some_and(|size| size == 0 || !size.is_multiple_of(block_size)) {
+        bail!("--size must be a non-zero multiple of the {} byte block size", block_size);
+    }
+
+    // backing store
+    let kvs = Kvs::new().context("connect kvs")?;
+    let device_id = resolve_device(&kvs, flag_value(args, "--device").unwrap_or("1"))?;
+    let master = flag_value(args, "--keyfile")
+        .map(|path| MasterKey::load(Path::new(path)))
+        .transpose()
+        .context("load master key")?;
+    let mut device = match BlockDevice::load(&format!("BlockDevice:{}", device_id), &kvs) {
+        Ok(device) if size.is_none_or(|size| size == device.logical_size_bytes) => device,
+        Ok(device) => bail!(
+            "stored device {} is {} bytes, expected {}", device_id, device.logical_size_bytes, size.unwrap_or_default()
+        ),
+        // 512 MiB unless told otherwise
+        Err(StorageError::NotFound(_)) => new_device(device_id, size.unwrap_or(512 << 20), args, master.as_ref(), &kvs)?,
+        Err(e) => return Err(e).context("load block device"),
+    };
+    let size_bytes = device.logical_size_bytes;
+    let size_mib = size_bytes >> 20;
+    // the kernel wants page sized blocks, devices not made of whole pages fall back to their own block size
+    let blksize: u64 = if size_bytes.is_multiple_of(4096) { 4096 } else { device.block_size_bytes as u64 };
+    if !size_bytes.is_multiple_of(blksize) {
+        bail!("size must be multiple of {}", blksize);
+    }
+    if let Some(master) = &master {
+        device.unlock(master).context("unlock data key")?;
+    }
+    if device.encryption.is_some() && master.is_none() {
+        bail!("device {} is encrypted, pass --keyfile", device_id);
+    }
+    let config_path = flag_value(args, "--config").map(PathBuf::from);
+    let mut config = match &config_path {
+        Some(path) => ServeConfig::load(path).with_context(|| format!("load {}", path.display()))?,
+        None => ServeConfig::default(),
+    };
+    config.apply(&mut device).context("apply config")?;
+    device.enable_journal();
+    let mut throttle = Throttle::new(QosLimits::for_device(&kvs, device_id).context("load qos limits")?);
+    let metrics = Arc::new(DeviceMetrics::new(device_id));
+    metrics.allocated_blocks.store(device.allocated_bytes()? / device.block_size_bytes as u64, Ordering::Relaxed);
+    if let Some(addr) = flag_value(args, "--metrics") {
+        let listener = tokio::net::TcpListener::bind(addr).await.with_context(|| format!("bind metrics endpoint {}", addr))?;
+        info!(device:% = device_id; "serving metrics on http://{}/metrics", listener.local_addr()?);
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Exporter::listen(listener, metrics).await {
+                error!("metrics endpoint stopped: {e}");
+            }
+        });
+    }
+    let trace = match flag_value(args, "--trace") {
+        Some(path) => {
+            let with_hashes = args.iter().any(|a| a == "--trace-hashes");
+            info!(device:% = device_id; "recording a trace of all requests to {}", path);
+            Some(TraceWriter::create(Path::new(path), with_hashes).with_context(|| format!("create trace {}", path))?)
+        }
+        None => None,
+    };
+    let mut recorder = Recorder { metrics, trace };
+    let store = Arc::new(Mutex::new(device));
 
     // socketpair kernel<->userspace
     let (k_sock, u_sock) = socketpair(
@@ -72,6 +806,207 @@ async fn main() -> Result<()> {
     )
     .context("socketpair")?;
 
+    let server_flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM;
+    let mut attachment = if args.iter().any(|a| a == "--netlink") {
+        let dead_conn_timeout = flag_value(args, "--dead-conn-timeout")
+            .map(|t| t.parse::<u64>().context("--dead-conn-timeout takes seconds"))
+            .transpose()?
+            .unwrap_or(DEFAULT_DEAD_CONN_TIMEOUT_SECS);
+        attach_netlink(dev_path, k_sock.as_raw_fd(), size_bytes, blksize, server_flags, dead_conn_timeout)?
+    } else {
+        attach_ioctl(dev_path, k_sock.as_raw_fd(), size_bytes, blksize, server_flags)?
+    };
+
+    // Wrap the userspace end in Tokio
+    let user_stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(u_sock.as_raw_fd()) };
+    user_stream
+        .set_nonblocking(true)
+        .context("set_nonblocking")?;
+    // IMPORTANT: we “forget” u_sock so it doesn’t close the fd we just moved
+    std::mem::forget(u_sock);
+
+    let io = UnixStream::from_std(user_stream).context("tokio UnixStream")?;
+    let (mut rd, mut wr) = io.into_split();
+
+    info!(
+        device:% = device_id;
+        "attached {} ({} MiB). In another shell: mkfs.ext4 {} && mount {} /mnt",
+        dev_path, size_mib, dev_path, dev_path
+    );
+
+    // Requests are read whole on their own task so a signal never interrupts one halfway
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Result<(Req, Vec<u8>)>>(64);
+    let reader = tokio::spawn(async move {
+        loop {
+            let item = read_req_with_payload(&mut rd).await;
+            let last = match &item {
+                Ok((req, _)) => req.cmd == NBD_CMD_DISC,
+                Err(_) => true,
+            };
+            if tx.send(item).await.is_err() || last {
+                break;
+            }
+        }
+    });
+
+    let mut sigint = signal(SignalKind::interrupt()).context("install SIGINT handler")?;
+    let mut sigterm = signal(SignalKind::terminate()).context("install SIGTERM handler")?;
+    let mut sighup = signal(SignalKind::hangup()).context("install SIGHUP handler")?;
+    let mut sigusr2 = signal(SignalKind::user_defined2()).context("install SIGUSR2 handler")?;
+    let mut drain_deadline: Option<tokio::time::Instant> = None;
+    let mut handover = false;
+    // limits changed on another host arrive without a SIGHUP
+    let mut qos_refresh = tokio::time::interval_at(tokio::time::Instant::now() + QOS_REFRESH, QOS_REFRESH);
+    // a request the throttle holds back, no new ones are taken until it has been answered
+    let mut held: Option<Held<(Req, Vec<u8>)>> = None;
+
+    // main request loop
+    loop {
+        let item = tokio::select! {
+            item = rx.recv(), if held.is_none() => item,
+            (req, payload) = Qos::release(&mut held) => {
+                serve_request(&store, &kvs, &config, &mut recorder, &mut wr, &req, &payload).await?;
+                recorder.metrics.queue_depth.store(rx.len() as u64, Ordering::Relaxed);
+                continue;
+            }
+            _ = async { tokio::select! { _ = sigint.recv() => {}, _ = sigterm.recv() => {} } } => {
+                if drain_deadline.is_some() {
+                    warn!("second signal, stopping without waiting for the kernel");
+                    break;
+                }
+                // the kernel stops queueing requests and sends DISC after the ones already sent
+                info!(device:% = device_id; "shutting down: draining in-flight requests");
+                attachment.disconnect();
+                drain_deadline = Some(tokio::time::Instant::now() + Duration::from_secs(config.drain_timeout_secs));
+                continue;
+            }
+            _ = sigusr2.recv() => {
+                if !attachment.survives_exit() {
+                    warn!("SIGUSR2: handing the device over needs --netlink, ignoring");
+                    continue;
+                }
+                // the kernel holds on to /dev/nbdX and resends unanswered requests to the next daemon
+                info!(device:% = device_id; "handing over: leaving {} attached for the next daemon", dev_path);
+                handover = true;
+                break;
+            }
+            _ = sighup.recv() => {
+                reload_config(config_path.as_deref(), &mut config, &store).await;
+                reload_qos(&kvs, device_id, &mut throttle);
+                continue;
+            }
+            _ = qos_refresh.tick() => {
+                reload_qos(&kvs, device_id, &mut throttle);
+                continue;
+            }
+            _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)), if drain_deadline.is_some() => {
+                warn!(device:% = device_id; "kernel did not disconnect within {}s, stopping", config.drain_timeout_secs);
+                break;
+            }
+        };
+        let (req, payload) = match item {
+            Some(Ok(item)) => item,
+            Some(Err(e)) => {
+                warn!("read_req ended: {e:?}");
+                break;
+            }
+            None => break,
+        };
+
+        if req.cmd == NBD_CMD_DISC {
+            debug!("got DISC");
+            // reply is not required for DISC in many setups; we just break
+            break;
+        }
+        // held back before touching the store, the kernel queues what comes in meanwhile
+        recorder.metrics.queue_depth.store(rx.len() as u64 + 1, Ordering::Relaxed);
+        let wait = qos_delay(&mut throttle, &req);
+        if !wait.is_zero() {
+            held = Some(Held::new((req, payload), wait));
+            continue;
+        }
+        serve_request(&store, &kvs, &config, &mut recorder, &mut wr, &req, &payload).await?;
+        recorder.metrics.queue_depth.store(rx.len() as u64, Ordering::Relaxed);
+    }
+
+    // requests the reader already took off the socket are answered before it closes, a held one
+    // first and without waiting out its delay
+    reader.abort();
+    let _ = reader.await;
+    let mut held = held.take().map(|held| held.item);
+    while let Some((req, payload)) = held.take().or_else(|| rx.try_recv().ok().and_then(Result::ok)) {
+        if req.cmd == NBD_CMD_DISC {
+            break;
+        }
+        if let Err(e) = serve_request(&store, &kvs, &config, &mut recorder, &mut wr, &req, &payload).await {
+            error!(device:% = device_id; "answering buffered requests: {e:?}");
+            break;
+        }
+    }
+
+    // everything acknowledged so far has to be in the store before the device goes away
+    let flushed = store.lock().await.flush(&kvs);
+    if let Some(trace) = &mut recorder.trace
+        && let Err(e) = trace.flush()
+    {
+        error!(device:% = device_id; "trace is incomplete: {e}");
+    }
+    if let Err(e) = &flushed {
+        error!(device:% = device_id; "final flush failed: {e}");
+    }
+
+    // closing our end makes NBD_DO_IT return, the thread then clears queue and socket
+    drop(rx);
+    drop(wr);
+    if drain_deadline.is_none() && !handover {
+        attachment.disconnect();
+    }
+    attachment.finish().await;
+    if !handover {
+        info!(device:% = device_id; "detached {}", dev_path);
+    }
+
+    flushed.context("flush block device")?;
+    Ok(())
+}
+
+// How the kernel side of /dev/nbdX was set up, which decides how it is torn down
+enum Attachment {
+    // NBD_SET_SOCK and a thread blocked in NBD_DO_IT, the device goes away with this process
+    Ioctl { nbd_raw: RawFd, do_it: std::thread::JoinHandle<i32> },
+    // generic netlink, the kernel keeps the device for dead_conn_timeout after this process exits
+    Netlink { netlink: NbdNetlink, index: u32 },
+}
+
+impl Attachment {
+    fn survives_exit(&self) -> bool {
+        matches!(self, Attachment::Netlink { .. })
+    }
+
+    fn disconnect(&mut self) {
+        let res = match self {
+            Attachment::Ioctl { nbd_raw, .. } => unsafe {
+                if ioctl(*nbd_raw, NBD_DISCONNECT, 0) != 0 { Err(std::io::Error::last_os_error()) } else { Ok(()) }
+            },
+            Attachment::Netlink { netlink, index } => netlink.disconnect(*index),
+        };
+        if let Err(e) = res {
+            warn!("disconnect: {}", e);
+        }
+    }
+
+    async fn finish(self) {
+        if let Attachment::Ioctl { do_it, .. } = self {
+            let _ = tokio::task::spawn_blocking(move || do_it.join()).await;
+        }
+    }
+}
+
+fn attach_ioctl(dev_path: &str, sock: RawFd, size_bytes: u64, blksize: u64, server_flags: c_ulong) -> Result<Attachment> {
+    // open /dev/nbdX
+    let nbd_fd = open(dev_path, OFlag::O_RDWR, Mode::empty()).context("open nbd dev")?;
+    let nbd_raw = nbd_fd.as_raw_fd();
+
     // configure NBD
     unsafe {
         if ioctl(nbd_raw, NBD_SET_BLKSIZE, blksize as c_ulong) != 0 {
@@ -80,7 +1015,10 @@ async fn main() -> Result<()> {
         if ioctl(nbd_raw, NBD_SET_SIZE, size_bytes as c_ulong) != 0 {
             bail!("NBD_SET_SIZE: {}", std::io::Error::last_os_error());
         }
-        if ioctl(nbd_raw, NBD_SET_SOCK, k_sock.as_raw_fd() as c_ulong) != 0 {
+        if ioctl(nbd_raw, NBD_SET_FLAGS, server_flags) != 0 {
+            bail!("NBD_SET_FLAGS: {}", std::io::Error::last_os_error());
+        }
+        if ioctl(nbd_raw, NBD_SET_SOCK, sock as c_ulong) != 0 {
             bail!("NBD_SET_SOCK: {}", std::io::Error::last_os_error());
         }
     }
@@ -99,93 +1037,165 @@ async fn main() -> Result<()> {
             r
         }
     });
+    Ok(Attachment::Ioctl { nbd_raw, do_it })
+}
 
-    // Wrap the userspace end in Tokio
-    let user_stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(u_sock.as_raw_fd()) };
-    user_stream
-        .set_nonblocking(true)
-        .context("set_nonblocking")?;
-    // IMPORTANT: we “forget” u_sock so it doesn’t close the fd we just moved
-    std::mem::forget(u_sock);
+// Takes over a device whose previous daemon went away, or configures it afresh
+fn attach_netlink(dev_path: &str, sock: RawFd, size_bytes: u64, blksize: u64, server_flags: u64, dead_conn_timeout: u64) -> Result<Attachment> {
+    let index: u32 = dev_path.trim_start_matches("/dev/nbd").parse()
+        .with_context(|| format!("{dev_path} is not an nbd device"))?;
+    let mut netlink = NbdNetlink::open().context("open nbd netlink")?;
+    match netlink.reconfigure(index, sock, dead_conn_timeout) {
+        Ok(()) => info!("reattached {} to the running kernel device", dev_path),
+        Err(reconfigure) => {
+            netlink.connect(index, sock, size_bytes, blksize, server_flags, dead_conn_timeout)
+                .with_context(|| format!("NBD_CMD_CONNECT (NBD_CMD_RECONFIGURE failed: {reconfigure})"))?;
+        }
+    }
+    Ok(Attachment::Netlink { netlink, index })
+}
 
-    let mut io = UnixStream::from_std(user_stream).context("tokio UnixStream")?;
+// Where answered requests are accounted: the metrics, and a trace when serve records one
+struct Recorder {
+    metrics: Arc<DeviceMetrics>,
+    trace: Option<TraceWriter>,
+}
 
-    eprintln!(
-        "attached {} ({} MiB). In another shell: mkfs.ext4 {} && mount {} /mnt",
-        dev_path, size_mib, dev_path, dev_path
+impl Recorder {
+    fn record(&mut self, device_id: u128, req: &Req, err: u32, elapsed: Duration, data: &[u8]) {
+        self.metrics.record(command_name(req.cmd), req.len as u64, elapsed, err);
+        if let Some(trace) = &mut self.trace
+            && let Err(e) = trace.record(req.cmd, req.offset, req.len, err, data)
+        {
+            // a full disk should not take the device down with it
+            error!(device:% = device_id; "stopped recording the trace: {e}");
+            self.trace = None;
+        }
+    }
+}
+
+// Answers one request, logging it when the config asks for that
+async fn serve_request<W: AsyncWrite + Unpin>(
+    store: &Mutex<BlockDevice>, kvs: &Kvs, config: &ServeConfig, recorder: &mut Recorder, wr: &mut W, req: &Req, payload: &[u8],
+) -> Result<()> {
+    let started = std::time::Instant::now();
+    let (device_id, err, data) = {
+        let mut s = store.lock().await;
+        let (err, data) = handle_request(&mut s, kvs, req, payload);
+        if matches!(req.cmd, NBD_CMD_WRITE | NBD_CMD_TRIM)
+            && let Ok(bytes) = s.allocated_bytes()
+        {
+            recorder.metrics.allocated_blocks.store(bytes / s.block_size_bytes as u64, Ordering::Relaxed);
+        }
+        (s.id, err, data)
+    };
+    let replied = write_reply(wr, req.handle, err, data.as_deref()).await;
+    let elapsed = started.elapsed();
+    recorder.record(device_id, req, err, elapsed, data.as_deref().unwrap_or(payload));
+    // every request at debug, the config lifts them to info
+    let level = if config.log_requests { Level::Info } else { Level::Debug };
+    log!(
+        level,
+        device:% = device_id, cmd = command_name(req.cmd), handle = u64::from_be_bytes(req.handle), offset = req.offset,
+        len = req.len, errno = err, latency_us = elapsed.as_micros() as u64;
+        "request answered"
     );
+    replied
+}
 
-    // main request loop
-    loop {
-        let req = match read_req(&mut io).await {
-            Ok(r) => r,
-            Err(e) => {
-                eprintln!("read_req ended: {e:?}");
-                break;
-            }
-        };
+// How long a request has to wait under the device's QoS limits, flushes are not limited
+fn qos_delay(throttle: &mut Throttle, req: &Req) -> Duration {
+    let (kind, bytes) = match req.cmd {
+        NBD_CMD_READ => (IoKind::Read, req.len as u64),
+        NBD_CMD_WRITE => (IoKind::Write, req.len as u64),
+        NBD_CMD_TRIM => (IoKind::Write, 0),
+        _ => return Duration::ZERO,
+    };
+    throttle.delay(kind, bytes, std::time::Instant::now())
+}
 
-        if req.cmd == NBD_CMD_DISC {
-            eprintln!("got DISC");
-            // reply is not required for DISC in many setups; we just break
-            break;
+// Picks up QoS limits changed through the management interface
+fn reload_qos(kvs: &Kvs, device_id: u128, throttle: &mut Throttle) {
+    match QosLimits::for_device(kvs, device_id) {
+        Ok(limits) if &limits != throttle.limits() => {
+            info!(device:% = device_id; "qos limits changed to {:?}", limits);
+            throttle.update(limits);
         }
+        Ok(_) => {}
+        Err(e) => warn!(device:% = device_id; "keeping qos limits: {e}"),
+    }
+}
 
-        match req.cmd {
-            NBD_CMD_READ => {
-                let data = {
-                    let s = store.lock().await;
-                    let off = req.offset as usize;
-                    let len = req.len as usize;
-                    if off + len > s.len() {
-                        // EINVAL
-                        (libc::EINVAL as u32, Vec::new())
-                    } else {
-                        (0u32, s[off..off + len].to_vec())
-                    }
-                };
-                println!("READ @{} len {} => err {}", req.offset, req.len, data.0);
-                write_reply(&mut io, req.handle, data.0, Some(&data.1)).await?;
-            }
-            NBD_CMD_WRITE => {
-                let mut buf = vec![0u8; req.len as usize];
-                io.read_exact(&mut buf).await.context("read write payload")?;
-
-                let err = {
-                    let mut s = store.lock().await;
-                    let off = req.offset as usize;
-                    if off + buf.len() > s.len() {
-                        libc::EINVAL as u32
-                    } else {
-                        s[off..off + buf.len()].copy_from_slice(&buf);
-                        0u32
-                    }
-                };
-                println!("WRITE @{} len {} => err {}", req.offset, req.len, err);
-                write_reply(&mut io, req.handle, err, None).await?;
-            }
-            NBD_CMD_FLUSH => {
-                // our backing store is memory; nothing to do
-                println!("FLUSH");
-                write_reply(&mut io, req.handle, 0, None).await?;
-            }
-            _ => {
-                // This is where TRIM / WRITE_ZEROES would land if the kernel sends them.
-                // “Advertise not implemented”: don’t set the flags in the ioctl handshake.
-                // If you still receive it, return EOPNOTSUPP.
-                write_reply(&mut io, req.handle, EOPNOTSUPP as u32, None).await?;
-            }
+// Runs one request against the device, returning the NBD error and the data to send back
+fn handle_request(device: &mut BlockDevice, kvs: &Kvs, req: &Req, payload: &[u8]) -> (u32, Option<Vec<u8>>) {
+    match req.cmd {
+        NBD_CMD_READ => {
+            let data = device.read(req.offset, req.len as usize);
+            (errno_of(&data), data.ok())
+        }
+        // block map changes are journaled before the reply, a restarted daemon replays them
+        NBD_CMD_WRITE => {
+            let res = device.write(req.offset, payload).and_then(|_| device.persist_journal(kvs));
+            (errno_of(&res), None)
+        }
+        NBD_CMD_FLUSH => (errno_of(&device.flush(kvs)), None),
+        NBD_CMD_TRIM => {
+            let res = device.trim(req.offset, req.len as usize).and_then(|_| device.persist_journal(kvs));
+            (errno_of(&res), None)
         }
+        // This is where WRITE_ZEROES would land if the kernel sends them.
+        // “Advertise not implemented”: don’t set the flags in the ioctl handshake.
+        // If you still receive it, return EOPNOTSUPP.
+        _ => (EOPNOTSUPP as u32, None),
     }
+}
 
-    // dropping the user socket should make NBD_DO_IT return
-    drop(io);
-    let _ = do_it.join();
+fn command_name(cmd: u32) -> &'static str {
+    match cmd {
+        NBD_CMD_READ => "READ",
+        NBD_CMD_WRITE => "WRITE",
+        NBD_CMD_DISC => "DISC",
+        NBD_CMD_FLUSH => "FLUSH",
+        NBD_CMD_TRIM => "TRIM",
+        _ => "UNKNOWN",
+    }
+}
 
-    Ok(())
+// Re-reads the config file on SIGHUP, keeping the running config when the file is bad
+async fn reload_config(path: Option<&Path>, config: &mut ServeConfig, store: &Mutex<BlockDevice>) {
+    let Some(path) = path else {
+        warn!("SIGHUP: no --config given, nothing to reload");
+        return;
+    };
+    match config.reload(path, &mut *store.lock().await) {
+        Ok(()) => info!("SIGHUP: reloaded {}", path.display()),
+        Err(e) => error!("SIGHUP: keeping the running config, {}: {e}", path.display()),
+    }
+}
+
+// Maps a storage result onto the error field of an nbd_reply (0 on success)
+fn errno_of<T>(res: &StorageResult<T>) -> u32 {
+    match res {
+        Ok(_) => 0,
+        Err(e) => {
+            warn!("request failed: {e}");
+            e.errno() as u32
+        }
+    }
+}
+
+// Reads a request and, for writes, the data that follows it
+async fn read_req_with_payload<R: AsyncRead + Unpin>(io: &mut R) -> Result<(Req, Vec<u8>)> {
+    let req = read_req(io).await?;
+    let mut payload = Vec::new();
+    if req.cmd == NBD_CMD_WRITE {
+        payload = vec![0u8; req.len as usize];
+        io.read_exact(&mut payload).await.context("read write payload")?;
+    }
+    Ok((req, payload))
 }
 
-async fn read_req(io: &mut UnixStream) -> Result<Req> {
+async fn read_req<R: AsyncRead + Unpin>(io: &mut R) -> Result<Req> {
     // nbd_request is 28 bytes packed
     // __be32 magic; __be32 type; char handle[8]; __be64 from; __be32 len;
     let mut hdr = [0u8; 28];
@@ -210,7 +1220,7 @@ async fn read_req(io: &mut UnixStream) -> Result<Req> {
     })
 }
 
-async fn write_reply(io: &mut UnixStream, handle: [u8; 8], err: u32, data: Option<&[u8]>) -> Result<()> {
+async fn write_reply<W: AsyncWrite + Unpin>(io: &mut W, handle: [u8; 8], err: u32, data: Option<&[u8]>) -> Result<()> {
     // nbd_reply: __be32 magic; __be32 error; char handle[8];
     let mut rep = [0u8; 16];
     rep[0..4].copy_from_slice(&NBD_REPLY_MAGIC.to_be_bytes());
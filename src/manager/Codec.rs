@@ -0,0 +1,94 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::utils::Error::{StorageError, StorageResult};
+
+// Binary record layout: MAGIC | FORMAT_VERSION (u16, little endian) | CBOR body
+// Records written before the binary format are plain JSON and never start with the magic.
+pub const MAGIC: [u8; 4] = *b"KVSB";
+pub const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+pub fn encode<T: Serialize>(item: &T) -> StorageResult<Vec<u8>> {
+    let mut out = Vec::with_capacity(256);
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    ciborium::into_writer(item, &mut out)
+        .map_err(|e| StorageError::Corruption(format!("encode failed: {}", e)))?;
+    Ok(out)
+}
+
+pub fn decode<T: DeserializeOwned>(raw: &[u8]) -> StorageResult<T> {
+    if !raw.starts_with(&MAGIC) {
+        //legacy record, written by the serde_json based store
+        return Ok(serde_json::from_slice(raw)?);
+    }
+    let version = format_version(raw)
+        .ok_or_else(|| StorageError::Corruption("truncated record header".into()))?;
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(StorageError::Corruption(format!(
+            "record format version {} is not one of the supported versions 1 to {}", version, FORMAT_VERSION
+        )));
+    }
+    ciborium::from_reader(&raw[HEADER_LEN..])
+        .map_err(|e| StorageError::Corruption(format!("decode failed: {}", e)))
+}
+
+pub fn format_version(raw: &[u8]) -> Option<u16> {
+    if raw.len() < HEADER_LEN || !raw.starts_with(&MAGIC) {
+        return None;
+    }
+    Some(u16::from_le_bytes([raw[4], raw[5]]))
+}
+
+//true when the record is already in the current binary format and needs no migration
+pub fn is_current(raw: &[u8]) -> bool {
+    format_version(raw) == Some(FORMAT_VERSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        id: u128,
+        name: String,
+        blocks: Vec<u64>,
+    }
+
+    fn record() -> Record {
+        Record { id: u128::MAX, name: "disk".into(), blocks: vec![1, 2, 3] }
+    }
+
+    fn with_version(version: u16) -> Vec<u8> {
+        let mut raw = encode(&record()).unwrap();
+        raw[4..6].copy_from_slice(&version.to_le_bytes());
+        raw
+    }
+
+    #[test]
+    fn round_trips_and_reads_legacy_json() {
+        let raw = encode(&record()).unwrap();
+        assert!(raw.starts_with(&MAGIC));
+        assert_eq!(decode::<Record>(&raw).unwrap(), record());
+        assert_eq!((format_version(&raw), is_current(&raw)), (Some(FORMAT_VERSION), true));
+
+        let legacy = br#"{"id":7,"name":"old","blocks":[]}"#;
+        let decoded: Record = decode(legacy).unwrap();
+        assert_eq!((decoded.id, decoded.name.as_str()), (7, "old"));
+        assert_eq!((format_version(legacy), is_current(legacy)), (None, false));
+    }
+
+    #[test]
+    fn rejects_truncated_headers_and_unknown_versions() {
+        let truncated = &encode(&record()).unwrap()[..5];
+        assert!(matches!(decode::<Record>(truncated), Err(StorageError::Corruption(_))));
+        assert_eq!(format_version(truncated), None);
+        for version in [0, FORMAT_VERSION + 1] {
+            let raw = with_version(version);
+            assert!(matches!(decode::<Record>(&raw), Err(StorageError::Corruption(_))), "version {version}");
+            assert!(!is_current(&raw));
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use crate::storage::BlockDevice::BlockDevice;
+use crate::storage::Compression::Compression;
+use crate::storage::Payload::WriteOverrides;
+use crate::utils::checksum::ChecksumKind;
+use crate::utils::Error::StorageResult;
+
+//Settings of the serve daemon that can change while it runs, read from a JSON file and
+//reloaded on SIGHUP. Geometry, device id and keys only take effect on restart.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServeConfig {
+    pub compression: Option<String>, //codec for new payloads, the device setting when unset
+    pub checksum: Option<String>, //checksum for new payloads, the device setting when unset
+    pub log_requests: bool, //log every request at info instead of debug
+    pub drain_timeout_secs: u64, //how long shutdown waits for the kernel to disconnect
+}
+
+impl Default for ServeConfig {
+    fn default() -> Self {
+        ServeConfig {
+            compression: None,
+            checksum: None,
+            log_requests: false,
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+impl ServeConfig {
+    //reads and validates the file, so a bad reload leaves the running config alone
+    pub fn load(path: &Path) -> StorageResult<Self> {
+        let config: ServeConfig = serde_json::from_slice(&std::fs::read(path)?)?;
+        config.compression()?;
+        config.checksum()?;
+        Ok(config)
+    }
+
+    pub fn compression(&self) -> StorageResult<Option<Compression>> {
+        self.compression.as_deref().map(str::parse).transpose()
+    }
+
+    pub fn checksum(&self) -> StorageResult<Option<ChecksumKind>> {
+        self.checksum.as_deref().map(str::parse).transpose()
+    }
+
+    //payloads already stored keep the codec and checksum they were written with. The overrides only
+    //live in the serve process, so dropping one from the file goes back to the device setting.
+    pub fn apply(&self, device: &mut BlockDevice) -> StorageResult<()> {
+        device.write_overrides = WriteOverrides {
+            compression: self.compression()?,
+            checksum: self.checksum()?,
+        };
+        Ok(())
+    }
+
+    //replaces the running config with the file's, leaving both it and the device alone when the file is bad
+    pub fn reload(&mut self, path: &Path, device: &mut BlockDevice) -> StorageResult<()> {
+        let reloaded = ServeConfig::load(path)?;
+        reloaded.apply(device)?;
+        *self = reloaded;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Error::StorageError;
+
+    fn config_file(name: &str, json: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("serve-config-{}-{}", name, std::process::id()));
+        std::fs::write(&path, json).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_and_validates_files() {
+        let path = config_file("valid", r#"{"compression": "zstd", "log_requests": true}"#);
+        let config = ServeConfig::load(&path).unwrap();
+        assert_eq!(config.compression().unwrap(), Some(Compression::Zstd));
+        assert_eq!(config.checksum().unwrap(), None);
+        assert!(config.log_requests);
+        assert_eq!(config.drain_timeout_secs, ServeConfig::default().drain_timeout_secs);
+
+        for (name, json) in [
+            ("unknown", r#"{"compresion": "zstd"}"#),
+            ("codec", r#"{"compression": "brotli"}"#),
+            ("checksum", r#"{"checksum": "md5"}"#),
+        ] {
+            let bad = config_file(name, json);
+            assert!(ServeConfig::load(&bad).is_err(), "{json}");
+            std::fs::remove_file(bad).unwrap();
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn overrides_stay_out_of_the_device_record() {
+        let mut device = BlockDevice::new(1, 4096);
+        let path = config_file("reload", r#"{"compression": "lz4", "checksum": "xxh3"}"#);
+        let mut config = ServeConfig::default();
+        config.reload(&path, &mut device).unwrap();
+        assert_eq!(device.write_overrides.compression, Some(Compression::Lz4));
+        assert_eq!((device.compression, device.checksum), (Compression::None, ChecksumKind::Sha256));
+
+        //a bad file keeps what is running
+        std::fs::write(&path, r#"{"compression": "lz5"}"#).unwrap();
+        assert!(matches!(config.reload(&path, &mut device), Err(StorageError::InvalidArgument(_))));
+        assert_eq!(config.compression.as_deref(), Some("lz4"));
+        assert_eq!(device.write_overrides.checksum, Some(ChecksumKind::Xxh3));
+
+        //dropping the override goes back to the device setting
+        std::fs::write(&path, "{}").unwrap();
+        config.reload(&path, &mut device).unwrap();
+        assert_eq!(device.write_overrides, WriteOverrides::default());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,634 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::manager::Pool::Pool;
+use crate::manager::Qos::{Limit, QosLimits};
+use crate::manager::Registry::{self, Attachment, DeviceEntry, DeviceFilter};
+use crate::storage::BlockDevice::{self as block_device, BlockDevice, DeviceUsage};
+use crate::storage::Encryption::MasterKey;
+use crate::storage::Snapshot::Snapshot;
+use crate::utils::clock::unix_now;
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::id_string;
+
+pub const DEFAULT_SOCKET: &str = "/run/storage.sock";
+//how long detach waits for a serve process to drain and exit, requests queue behind it meanwhile
+const DETACH_TIMEOUT: Duration = Duration::from_secs(30);
+
+//Device id or name, ids are written as strings since JSON numbers cannot hold a u128
+pub type DeviceRef = String;
+
+//One management call. On the wire each request is a single line of JSON such as
+//{"op":"resize","id":"ci-golden-ubuntu","size_bytes":1073741824}, answered by a single Response line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Request {
+    Create {
+        #[serde(with = "id_string")]
+        id: u128,
+        size_bytes: u64,
+        #[serde(default)]
+        compression: Option<String>,
+        #[serde(default)]
+        checksum: Option<String>,
+        #[serde(default)]
+        encrypt: bool,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+        #[serde(default)]
+        pool: Option<String>,
+        #[serde(default)]
+        quota_bytes: Option<u64>,
+    },
+    List {
+        #[serde(default)]
+        filter: DeviceFilter,
+    },
+    Inspect {
+        id: DeviceRef,
+    },
+    Resize {
+        id: DeviceRef,
+        size_bytes: u64,
+    },
+    Snapshot {
+        id: DeviceRef,
+    },
+    Clone {
+        id: DeviceRef,
+        #[serde(with = "id_string")]
+        new_id: u128,
+        #[serde(default)]
+        new_name: Option<String>,
+        #[serde(default)]
+        generation: Option<u32>, //the live device when unset
+    },
+    Rename {
+        id: DeviceRef,
+        name: Option<String>, //null takes the name away
+    },
+    Label {
+        id: DeviceRef,
+        #[serde(default)]
+        set: BTreeMap<String, String>,
+        #[serde(default)]
+        remove: Vec<String>,
+    },
+    SetQuota {
+        id: DeviceRef,
+        quota_bytes: Option<u64>, //null lifts the quota
+    },
+    //replaces all limits of the device, unset ones are lifted; a serving process picks them up right away
+    SetQos {
+        id: DeviceRef,
+        #[serde(default)]
+        read_iops: Option<Limit>,
+        #[serde(default)]
+        write_iops: Option<Limit>,
+        #[serde(default)]
+        read_bytes: Option<Limit>,
+        #[serde(default)]
+        write_bytes: Option<Limit>,
+    },
+    CreatePool {
+        name: String,
+        capacity_bytes: u64,
+        #[serde(default)]
+        high_watermark_percent: Option<u8>,
+    },
+    ListPools,
+    DeletePool {
+        name: String,
+    },
+    RecountPools,
+    Attach {
+        id: DeviceRef,
+        nbd: String,
+        #[serde(default)]
+        metrics: Option<String>, //address the serve process exports prometheus metrics on
+    },
+    Detach {
+        id: DeviceRef,
+    },
+    Delete {
+        id: DeviceRef,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub result: Value,
+}
+
+impl Response {
+    fn from_result(result: StorageResult<Value>) -> Self {
+        match result {
+            Ok(result) => Response { ok: true, error: None, result },
+            Err(e) => Response { ok: false, error: Some(e.to_string()), result: Value::Null },
+        }
+    }
+}
+
+//What list reports about a device, straight from the registry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListedDevice {
+    #[serde(flatten)]
+    pub entry: DeviceEntry,
+    pub attached: Option<Attachment>,
+}
+
+//What inspect reports about a device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    #[serde(with = "id_string")]
+    pub id: u128,
+    pub name: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub created_at: u64,
+    pub size_bytes: u64,
+    pub block_size_bytes: usize,
+    pub generation: u32,
+    pub compression: String,
+    pub checksum: String,
+    pub encrypted: bool,
+    pub pool: Option<String>,
+    pub quota_bytes: Option<u64>,
+    pub allocated_bytes: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qos: Option<QosLimits>,
+    pub attached: Option<Attachment>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<DeviceUsage>,
+}
+
+//Executes requests against the devices in the store. Serving a device to the kernel is left to a
+//`serve` child process per attached device, so one stuck device cannot stall the control plane.
+//Attachments are recorded in the registry, serve processes outlive a restarted daemon.
+pub struct Controller {
+    kvs: Kvs,
+    keyfile: Option<PathBuf>,
+    master: Option<MasterKey>,
+    children: BTreeMap<u128, Child>, //serve processes started by this daemon
+}
+
+impl Controller {
+    pub fn new(kvs: Kvs, keyfile: Option<PathBuf>) -> StorageResult<Self> {
+        let master = keyfile.as_deref().map(MasterKey::load).transpose()?;
+        let report = Registry::rebuild(&kvs)?;
+        if report.added + report.removed > 0 {
+            info!("registry: indexed {} device(s), dropped {} stale entries", report.added, report.removed);
+        }
+        //attachments whose serve process died with the previous daemon's host session
+        for attachment in Attachment::list(&kvs)? {
+            if attachment.is_local() && !serves_device(attachment.pid, attachment.device_id) {
+                warn!(device:% = attachment.device_id; "dropping stale attachment to {}", attachment.nbd);
+                kvs.delete(&attachment.get_kvs_id())?;
+            }
+        }
+        Ok(Controller { kvs, keyfile, master, children: BTreeMap::new() })
+    }
+
+    pub fn handle(&mut self, request: Request) -> StorageResult<Value> {
+        self.reap();
+        match request {
+            Request::Create { id, size_bytes, compression, checksum, encrypt, name, labels, pool, quota_bytes } => {
+                let mut device = BlockDevice::new(id, size_bytes);
+                if self.kvs.get_raw(&device.get_kvs_id())?.is_some() {
+                    return Err(StorageError::Conflict(format!("device {} already exists", id)));
+                }
+                if size_bytes == 0 || !size_bytes.is_multiple_of(device.block_size_bytes as u64) {
+                    return Err(StorageError::InvalidArgument(format!(
+                        "size must be a non-zero multiple of the {} byte block size", device.block_size_bytes
+                    )));
+                }
+                if let Some(name) = &name {
+                    Registry::check_name(&self.kvs, name)?;
+                }
+                if let Some(codec) = compression {
+                    device.compression = codec.parse()?;
+                }
+                if let Some(kind) = checksum {
+                    device.checksum = kind.parse()?;
+                }
+                if let Some(pool) = &pool {
+                    Pool::load(&Pool::kvs_id(pool), &self.kvs)?;
+                }
+                device.pool = pool;
+                device.quota_bytes = quota_bytes;
+                if encrypt {
+                    let master = self.master.as_ref().ok_or_else(|| {
+                        StorageError::InvalidArgument("encryption needs the daemon to run with --keyfile".into())
+                    })?;
+                    device.enable_encryption(master)?;
+                }
+                device.attach(self.kvs.clone());
+                device.flush(&self.kvs)?;
+                Registry::register(&self.kvs, &DeviceEntry { name, labels, ..DeviceEntry::for_device(&device) })?;
+                Ok(json!(self.info(&mut device, false)?))
+            }
+            Request::List { filter } => {
+                let devices: Vec<ListedDevice> = Registry::devices(&self.kvs, &filter)?
+                    .into_iter()
+                    .map(|(entry, attached)| ListedDevice { entry, attached })
+                    .collect();
+                Ok(json!(devices))
+            }
+            Request::Inspect { id } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                let mut device = self.load(id)?;
+                Ok(json!(self.info(&mut device, true)?))
+            }
+            Request::Resize { id, size_bytes } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                self.check_detached(id)?;
+                let mut device = self.load(id)?;
+                device.resize(size_bytes)?;
+                device.flush(&self.kvs)?;
+                let mut entry = self.entry(&device)?;
+                entry.size_bytes = size_bytes;
+                Registry::register(&self.kvs, &entry)?;
+                Ok(json!(self.info(&mut device, false)?))
+            }
+            Request::Snapshot { id } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                self.check_detached(id)?;
+                let snapshot = self.load(id)?.snapshot(&self.kvs)?;
+                Ok(json!({ "generation": snapshot.generation, "created_at": snapshot.created_at }))
+            }
+            Request::Clone { id, new_id, new_name, generation } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                let mut source = match generation {
+                    Some(generation) => Snapshot::load(&Snapshot::kvs_id(id, generation), &self.kvs)?.open(&self.kvs),
+                    None => {
+                        self.check_detached(id)?;
+                        self.load(id)?
+                    }
+                };
+                if let Some(master) = &self.master {
+                    source.unlock(master)?;
+                }
+                if let Some(name) = &new_name {
+                    Registry::check_name(&self.kvs, name)?;
+                }
+                let mut clone = source.clone_to(new_id, &self.kvs, self.master.as_ref())?;
+                Registry::register(&self.kvs, &DeviceEntry { name: new_name, ..DeviceEntry::for_device(&clone) })?;
+                Ok(json!(self.info(&mut clone, false)?))
+            }
+            Request::Rename { id, name } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                Ok(json!(Registry::rename(&self.kvs, id, name)?))
+            }
+            Request::Label { id, set, remove } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                Ok(json!(Registry::relabel(&self.kvs, id, set, &remove)?))
+            }
+            Request::SetQuota { id, quota_bytes } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                self.check_detached(id)?;
+                let mut device = self.load(id)?;
+                device.quota_bytes = quota_bytes;
+                device.flush(&self.kvs)?;
+                Ok(json!(self.info(&mut device, false)?))
+            }
+            Request::SetQos { id, read_iops, write_iops, read_bytes, write_bytes } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                self.load(id)?;
+                let limits = QosLimits { device_id: id, read_iops, write_iops, read_bytes, write_bytes };
+                limits.save(&self.kvs)?;
+                //a serve process on another host rereads its limits within a few seconds
+                if let Some(attachment) = Attachment::find(&self.kvs, id)?
+                    && attachment.is_local()
+                    && serves_device(attachment.pid, id)
+                {
+                    unsafe { libc::kill(attachment.pid as libc::pid_t, libc::SIGHUP) };
+                }
+                Ok(json!(limits))
+            }
+            Request::CreatePool { name, capacity_bytes, high_watermark_percent } => {
+                let mut pool = Pool::new(&name, capacity_bytes);
+                if let Some(percent) = high_watermark_percent {
+                    pool.high_watermark_percent = percent;
+                }
+                Pool::create(&self.kvs, &pool)?;
+                Ok(json!(pool))
+            }
+            Request::ListPools => Ok(json!(Pool::list(&self.kvs)?)),
+            Request::DeletePool { name } => {
+                Pool::delete(&self.kvs, &name)?;
+                Ok(Value::Null)
+            }
+            Request::RecountPools => Ok(json!(Pool::recount(&self.kvs)?)),
+            Request::Attach { id, nbd, metrics } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                self.check_detached(id)?;
+                let host = Registry::hostname();
+                if let Some(other) = Attachment::list(&self.kvs)?.iter().find(|a| a.host == host && a.nbd == nbd) {
+                    return Err(StorageError::Conflict(format!("{} already serves device {}", nbd, other.device_id)));
+                }
+                self.load(id)?;
+                let child = self.spawn_serve(id, &nbd, metrics.as_deref())?;
+                let attachment = Attachment { device_id: id, host, nbd, pid: child.id(), attached_at: unix_now() };
+                info!(device:% = id, pid = attachment.pid; "attached to {}", attachment.nbd);
+                self.children.insert(id, child);
+                attachment.store(&self.kvs)?;
+                Ok(json!(attachment))
+            }
+            Request::Detach { id } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                let attachment = Attachment::find(&self.kvs, id)?
+                    .ok_or_else(|| StorageError::NotFound(format!("device {} is not attached", id)))?;
+                if !attachment.is_local() {
+                    return Err(StorageError::Conflict(format!(
+                        "device {} is attached on {}, detach it there", id, attachment.host
+                    )));
+                }
+                let mut child = self.children.remove(&id);
+                //a serve process started by an earlier daemon may be long gone and its pid reused
+                if child.is_none() && !serves_device(attachment.pid, id) {
+                    warn!(device:% = id; "serve process {} is gone, dropping the attachment", attachment.pid);
+                    self.kvs.delete(&attachment.get_kvs_id())?;
+                    return Ok(Value::Null);
+                }
+                //serve drains, flushes and disconnects on SIGTERM
+                if unsafe { libc::kill(attachment.pid as libc::pid_t, libc::SIGTERM) } != 0 {
+                    let e = std::io::Error::last_os_error();
+                    if let Some(child) = child {
+                        self.children.insert(id, child);
+                    }
+                    return Err(e.into());
+                }
+                let deadline = Instant::now() + DETACH_TIMEOUT;
+                loop {
+                    let exited = match &mut child {
+                        Some(child) => child.try_wait()?.is_some(),
+                        //not ours to wait for, all we can do is watch it go away
+                        None => !serves_device(attachment.pid, id),
+                    };
+                    if exited {
+                        break;
+                    }
+                    if Instant::now() >= deadline {
+                        if let Some(child) = child {
+                            self.children.insert(id, child);
+                        }
+                        return Err(StorageError::Conflict(format!(
+                            "serve process {} of device {} did not exit within {}s", attachment.pid, id, DETACH_TIMEOUT.as_secs()
+                        )));
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                info!(device:% = id; "detached from {}", attachment.nbd);
+                self.kvs.delete(&attachment.get_kvs_id())?;
+                Ok(Value::Null)
+            }
+            Request::Delete { id } => {
+                let id = Registry::resolve(&self.kvs, &id)?;
+                self.check_detached(id)?;
+                BlockDevice::delete(&self.kvs, id)?;
+                self.kvs.delete(&QosLimits::kvs_id(id))?;
+                Registry::unregister(&self.kvs, id)?;
+                Ok(Value::Null)
+            }
+        }
+    }
+
+    fn load(&self, id: u128) -> StorageResult<BlockDevice> {
+        BlockDevice::load(&format!("{}{}", block_device::KVS_PREFIX, id), &self.kvs)
+    }
+
+    //registry entry of a device, recreated for records written without going through the registry
+    fn entry(&self, device: &BlockDevice) -> StorageResult<DeviceEntry> {
+        match Registry::entry(&self.kvs, device.id) {
+            Err(StorageError::NotFound(_)) => Ok(DeviceEntry::for_device(device)),
+            other => other,
+        }
+    }
+
+    //a served device has its state in the serve process, changing the record under it would be lost
+    fn check_detached(&self, id: u128) -> StorageResult<()> {
+        match Attachment::find(&self.kvs, id)? {
+            Some(attachment) => Err(StorageError::Conflict(format!(
+                "device {} is attached to {} on {}, detach it first", id, attachment.nbd, attachment.host
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    //forgets serve processes that exited on their own, along with their attachments
+    fn reap(&mut self) {
+        let kvs = &self.kvs;
+        self.children.retain(|id, child| {
+            match child.try_wait() {
+                Ok(None) => return true,
+                Ok(Some(status)) => warn!(device:% = id; "serve process exited: {}", status),
+                Err(e) => error!(device:% = id; "serve process: {}", e),
+            }
+            if let Err(e) = kvs.delete(&Attachment::kvs_id(*id)) {
+                error!(device:% = id; "dropping attachment: {}", e);
+            }
+            false
+        });
+    }
+
+    fn spawn_serve(&self, id: u128, nbd: &str, metrics: Option<&str>) -> StorageResult<Child> {
+        let mut command = Command::new(std::env::current_exe()?);
+        command.arg("serve")
+            .args(["--device", &id.to_string(), "--nbd", nbd, "--netlink"])
+            .stdin(Stdio::null());
+        if let Some(keyfile) = &self.keyfile {
+            command.arg("--keyfile").arg(keyfile);
+        }
+        if let Some(addr) = metrics {
+            command.args(["--metrics", addr]);
+        }
+        Ok(command.spawn()?)
+    }
+
+    fn info(&self, device: &mut BlockDevice, detailed: bool) -> StorageResult<DeviceInfo> {
+        let (snapshots, usage) = if detailed {
+            let snapshots = Snapshot::list(&self.kvs, device.id)?.iter().map(|s| s.generation).collect();
+            (snapshots, Some(device.usage()?))
+        } else {
+            (Vec::new(), None)
+        };
+        let entry = self.entry(device)?;
+        Ok(DeviceInfo {
+            id: device.id,
+            name: entry.name,
+            labels: entry.labels,
+            created_at: entry.created_at,
+            size_bytes: device.logical_size_bytes,
+            block_size_bytes: device.block_size_bytes,
+            generation: device.generation,
+            compression: device.compression.to_string(),
+            checksum: device.checksum.to_string(),
+            encrypted: device.encryption.is_some(),
+            pool: device.pool.clone(),
+            quota_bytes: device.quota_bytes,
+            allocated_bytes: device.allocated_bytes()?,
+            qos: Some(QosLimits::for_device(&self.kvs, device.id)?).filter(|qos| !qos.is_unlimited()),
+            attached: Attachment::find(&self.kvs, device.id)?,
+            snapshots,
+            usage,
+        })
+    }
+}
+
+//whether `pid` is still the serve process of the device, as spawn_serve starts them
+fn serves_device(pid: u32, id: u128) -> bool {
+    let Ok(cmdline) = std::fs::read(format!("/proc/{}/cmdline", pid)) else {
+        return false;
+    };
+    let args: Vec<&[u8]> = cmdline.split(|&b| b == 0).collect();
+    let id = id.to_string();
+    args.get(1) == Some(&&b"serve"[..]) && args.windows(2).any(|pair| pair[0] == b"--device" && pair[1] == id.as_bytes())
+}
+
+//Accepts connections until the process is stopped, requests on all connections are executed
+//one at a time
+pub async fn listen(path: &Path, controller: Controller) -> std::io::Result<()> {
+    //a socket left behind by a daemon that was killed would make bind fail
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    let controller = Arc::new(Mutex::new(controller));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let controller = controller.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, controller).await {
+                warn!("control connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(stream: UnixStream, controller: Arc<Mutex<Controller>>) -> std::io::Result<()> {
+    let (rd, mut wr) = stream.into_split();
+    let mut lines = BufReader::new(rd).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let mut controller = controller.lock().await;
+                //store access and waiting for a detached serve process block
+                Response::from_result(tokio::task::block_in_place(|| controller.handle(request)))
+            }
+            Err(e) => Response::from_result(Err(StorageError::InvalidArgument(format!("bad request: {}", e)))),
+        };
+        let mut out = serde_json::to_vec(&response).map_err(std::io::Error::other)?;
+        out.push(b'\n');
+        wr.write_all(&out).await?;
+    }
+    Ok(())
+}
+
+//Sends one request to a running daemon and waits for its response
+pub fn call(socket: &Path, request: &Request) -> std::io::Result<Response> {
+    use std::io::{BufRead, Write};
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    let mut line = serde_json::to_vec(request).map_err(std::io::Error::other)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    let mut reply = String::new();
+    std::io::BufReader::new(stream).read_line(&mut reply)?;
+    serde_json::from_str(&reply).map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(controller: &mut Controller, line: &str) -> Value {
+        let request: Request = serde_json::from_str(line).unwrap();
+        controller.handle(request).unwrap()
+    }
+
+    #[test]
+    fn manages_devices_through_requests() {
+        let mut controller = Controller::new(Kvs::in_memory(), None).unwrap();
+        call(&mut controller, r#"{"op":"create","id":"1","size_bytes":1048576,"compression":"lz4","labels":{"os":"debian"}}"#);
+        let created = call(&mut controller, r#"{"op":"inspect","id":"1"}"#);
+        assert_eq!(created["compression"], "lz4");
+        assert_eq!(created["size_bytes"], 1048576);
+
+        let snapshot = call(&mut controller, r#"{"op":"snapshot","id":"1"}"#);
+        assert_eq!(snapshot["generation"], 1);
+        call(&mut controller, r#"{"op":"clone","id":"1","new_id":"2","new_name":"golden","generation":1}"#);
+        call(&mut controller, r#"{"op":"resize","id":"golden","size_bytes":2097152}"#);
+        let err = controller.handle(Request::Rename { id: "1".into(), name: Some("golden".into()) }).unwrap_err();
+        assert!(matches!(err, StorageError::Conflict(_)));
+        let labeled = call(&mut controller, r#"{"op":"label","id":"golden","set":{"os":"debian","tier":"ci"},"remove":["tier"]}"#);
+        assert_eq!(labeled["labels"], json!({ "os": "debian" }));
+        call(&mut controller, r#"{"op":"set-qos","id":"golden","write_iops":{"rate":100,"burst":400}}"#);
+        assert_eq!(call(&mut controller, r#"{"op":"inspect","id":"golden"}"#)["qos"]["write_iops"]["burst"], 400);
+        call(&mut controller, r#"{"op":"set-qos","id":"golden"}"#);
+        assert!(call(&mut controller, r#"{"op":"inspect","id":"golden"}"#).get("qos").is_none());
+        let listed = call(&mut controller, r#"{"op":"list"}"#);
+        let sizes: Vec<&Value> = listed.as_array().unwrap().iter().map(|d| &d["size_bytes"]).collect();
+        assert_eq!(sizes, [1048576, 2097152]);
+        let listed = call(&mut controller, r#"{"op":"list","filter":{"labels":{"os":"debian"}}}"#);
+        let ids: Vec<&Value> = listed.as_array().unwrap().iter().map(|d| &d["id"]).collect();
+        assert_eq!(ids, ["1", "2"]);
+
+        call(&mut controller, r#"{"op":"delete","id":"golden"}"#);
+        let err = controller.handle(Request::Inspect { id: "2".into() }).unwrap_err();
+        assert!(matches!(err, StorageError::NotFound(_)));
+        assert_eq!(call(&mut controller, r#"{"op":"list"}"#).as_array().unwrap().len(), 1);
+        let err = controller.handle(Request::Create {
+            id: 1, size_bytes: 4096, compression: None, checksum: None, encrypt: false, name: None, labels: BTreeMap::new(),
+            pool: None, quota_bytes: None,
+        }).unwrap_err();
+        assert!(matches!(err, StorageError::Conflict(_)));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(serde_json::from_str::<Request>(r#"{"op":"inspect","id":1}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"op":"create","id":"golden","size_bytes":512}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"op":"shrink","id":"1"}"#).is_err());
+        assert_eq!(
+            serde_json::to_string(&Request::Detach { id: u128::MAX.to_string() }).unwrap(),
+            format!(r#"{{"op":"detach","id":"{}"}}"#, u128::MAX)
+        );
+    }
+
+    #[test]
+    fn reused_pids_of_attachments_are_never_signalled() {
+        let mut controller = Controller::new(Kvs::in_memory(), None).unwrap();
+        call(&mut controller, r#"{"op":"create","id":"1","size_bytes":1048576}"#);
+        //the pid now belongs to this test, which a SIGTERM would kill
+        let attachment = Attachment {
+            device_id: 1,
+            host: Registry::hostname(),
+            nbd: "/dev/nbd0".into(),
+            pid: std::process::id(),
+            attached_at: 0,
+        };
+        attachment.store(&controller.kvs).unwrap();
+        call(&mut controller, r#"{"op":"set-qos","id":"1","write_iops":{"rate":100,"burst":400}}"#);
+        call(&mut controller, r#"{"op":"detach","id":"1"}"#);
+        assert_eq!(Attachment::find(&controller.kvs, 1).unwrap(), None);
+    }
+}
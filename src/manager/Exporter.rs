@@ -0,0 +1,81 @@
+use log::warn;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use crate::utils::Metrics::{DeviceMetrics, METRICS};
+
+//Serves GET /metrics in prometheus text format, meant for a local address the node's
+//scraper can reach. Everything else gets a 404.
+pub async fn listen(listener: TcpListener, device: Arc<DeviceMetrics>) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let device = device.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(stream, &device).await {
+                warn!("metrics connection: {}", e);
+            }
+        });
+    }
+}
+
+//the process wide metrics followed by the device's
+pub fn render(device: &DeviceMetrics) -> String {
+    let mut out = String::new();
+    METRICS.render(&mut out);
+    device.render(&mut out);
+    out
+}
+
+//one request per connection, the body is small enough to not bother with keep-alive
+async fn serve_connection(stream: TcpStream, device: &DeviceMetrics) -> std::io::Result<()> {
+    let (rd, mut wr) = stream.into_split();
+    let mut lines = BufReader::new(rd).lines();
+    let request_line = lines.next_line().await?.unwrap_or_default();
+    //headers are not needed, but read them so the client is not reset mid request
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render(device)),
+        _ => ("404 Not Found", "text/plain", "only GET /metrics is served\n".to_string()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len()
+    );
+    wr.write_all(head.as_bytes()).await?;
+    wr.write_all(body.as_bytes()).await?;
+    wr.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn answers_scrapes_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let device = Arc::new(DeviceMetrics::new(3));
+        device.record("FLUSH", 0, Duration::from_millis(1), 0);
+        tokio::spawn(listen(listener, device));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("storage_requests_total{device=\"3\",command=\"FLUSH\"} 1\n"));
+        assert!(response.contains("# TYPE storage_kvs_request_duration_seconds histogram\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::storage::BlockDevice::{self as block_device, BlockDevice};
+use crate::storage::BlockPage::{self as block_page, BlockPage};
+use crate::storage::Journal::{self as journal, JournalEntry, JournalOp};
+use crate::storage::Payload::{self, gc_candidate_key, GC_CANDIDATE_PREFIX};
+use crate::storage::Snapshot::Snapshot;
+use crate::utils::clock::unix_now;
+use crate::utils::Error::{StorageError, StorageResult};
+
+pub const DEFAULT_GRACE_SECS: u64 = 3600;
+
+//Page and payload keys reachable from the stored device records, their snapshots and journals
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Marks {
+    pub pages: BTreeSet<String>,
+    pub payloads: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcOptions {
+    pub dry_run: bool,
+    //how long a key has to stay unreferenced before it is deleted. Attached devices reference
+    //new pages and reused payloads before their next flush stores the record, so this has to
+    //be longer than the time between flushes.
+    pub grace_secs: u64,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        GcOptions { dry_run: false, grace_secs: DEFAULT_GRACE_SECS }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub pages_scanned: u64,
+    pub payloads_scanned: u64,
+    pub unreferenced_pages: u64,
+    pub unreferenced_payloads: u64,
+    pub reclaimable_bytes: u64, //stored bytes of everything unreferenced, whether or not its grace period is over
+    pub deleted_pages: u64,
+    pub deleted_payloads: u64,
+    pub reclaimed_bytes: u64,
+}
+
+//walks every device record and snapshot, each page is read once even when several generations share it
+pub fn mark(kvs: &Kvs) -> StorageResult<Marks> {
+    let mut marks = Marks::default();
+    for key in kvs.scan_keys(&format!("{}*", block_device::KVS_PREFIX))? {
+        //plain decode, BlockDevice::load would convert legacy records and write payloads
+        let device: BlockDevice = match kvs.load(&key) {
+            Ok(device) => device,
+            Err(StorageError::NotFound(_)) => continue, //deleted while we were scanning
+            Err(e) => return Err(e),
+        };
+        mark_roots(&device, &mut marks);
+    }
+    for snapshot in Snapshot::list_all(kvs)? {
+        mark_roots(&snapshot.device, &mut marks);
+    }
+    //payloads written by acknowledged but unflushed requests
+    for key in kvs.scan_keys(&format!("{}*", journal::KVS_PREFIX))? {
+        let entry = match JournalEntry::load(&key, kvs) {
+            Ok(entry) => entry,
+            Err(StorageError::NotFound(_)) => continue, //flushed while we were scanning
+            Err(e) => return Err(e),
+        };
+        for op in entry.ops {
+            if let JournalOp::Map(extent) = op {
+                marks.payloads.insert(Payload::kvs_id(&extent.content.hash, extent.content.codec));
+            }
+        }
+    }
+    for page_key in &marks.pages {
+        let page = match BlockPage::load(page_key, kvs) {
+            Ok(page) => page,
+            Err(StorageError::NotFound(_)) => continue, //dangling, the scrubber reports those
+            Err(e) => return Err(e),
+        };
+        for extent in page.extents.values() {
+            marks.payloads.insert(Payload::kvs_id(&extent.content.hash, extent.content.codec));
+        }
+    }
+    Ok(marks)
+}
+
+fn mark_roots(device: &BlockDevice, marks: &mut Marks) {
+    for (&page_index, &generation) in &device.page_roots {
+        marks.pages.insert(BlockPage::kvs_id(device.id, page_index, generation));
+    }
+}
+
+//Mark and sweep over pages and payloads. Keys are listed before marking so anything written
+//during the pass is left alone, and an unreferenced key is only deleted once it has stayed
+//unreferenced for the grace period. Payload::put drops the candidate mark of payloads it reuses.
+pub fn collect(kvs: &Kvs, options: GcOptions) -> StorageResult<GcReport> {
+    let now = unix_now();
+    let pages = kvs.scan_keys(&format!("{}*", block_page::KVS_PREFIX))?;
+    let payloads = kvs.scan_keys(&format!("{}*", Payload::KVS_PREFIX))?;
+    let marks = mark(kvs)?;
+
+    let mut report = GcReport {
+        dry_run: options.dry_run,
+        pages_scanned: pages.len() as u64,
+        payloads_scanned: payloads.len() as u64,
+        ..GcReport::default()
+    };
+    for key in pages.iter().filter(|key| !marks.pages.contains(*key)) {
+        report.unreferenced_pages += 1;
+        if let Some(bytes) = sweep(kvs, key, now, options, &mut report)? {
+            report.deleted_pages += 1;
+            report.reclaimed_bytes += bytes;
+        }
+    }
+    for key in payloads.iter().filter(|key| !marks.payloads.contains(*key)) {
+        report.unreferenced_payloads += 1;
+        if let Some(bytes) = sweep(kvs, key, now, options, &mut report)? {
+            report.deleted_payloads += 1;
+            report.reclaimed_bytes += bytes;
+        }
+    }
+    if !options.dry_run {
+        //candidates that got referenced again, or whose key is gone, are forgotten
+        for marker in kvs.scan_keys(&format!("{}*", GC_CANDIDATE_PREFIX))? {
+            let key = &marker[GC_CANDIDATE_PREFIX.len()..];
+            if marks.pages.contains(key) || marks.payloads.contains(key) || kvs.get_raw(key)?.is_none() {
+                kvs.delete(&marker)?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+//deletes an unreferenced key whose grace period is over, returns its size if it did
+fn sweep(kvs: &Kvs, key: &str, now: u64, options: GcOptions, report: &mut GcReport) -> StorageResult<Option<u64>> {
+    let bytes = kvs.value_len(key)?;
+    report.reclaimable_bytes += bytes;
+    if options.dry_run {
+        return Ok(None);
+    }
+    let marker = gc_candidate_key(key);
+    let first_seen = match kvs.get_raw(&marker)? {
+        Some(raw) => String::from_utf8_lossy(&raw).parse().unwrap_or(now),
+        None => {
+            kvs.set_raw(&marker, now.to_string().as_bytes())?;
+            now
+        }
+    };
+    if now.saturating_sub(first_seen) < options.grace_secs {
+        return Ok(None);
+    }
+    //the marker is gone when a writer reused the payload since we looked, that check and the
+    //delete have to be one step or a reuse landing in between would lose its payload
+    if !kvs.delete_if_marked(&marker, key)? {
+        return Ok(None);
+    }
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: GcOptions = GcOptions { dry_run: false, grace_secs: 0 };
+
+    fn device(kvs: &Kvs) -> BlockDevice {
+        let mut device = BlockDevice::new(7, 64 * 1024);
+        device.attach(kvs.clone());
+        device
+    }
+
+    #[test]
+    fn keeps_what_snapshots_reference() {
+        let kvs = Kvs::in_memory();
+        let mut device = device(&kvs);
+        let old = vec![0x11u8; 4096];
+        device.write(0, &old).unwrap();
+        let snapshot = device.snapshot(&kvs).unwrap();
+        device.write(0, &vec![0x22u8; 4096]).unwrap();
+        device.flush(&kvs).unwrap();
+
+        let report = collect(&kvs, NOW).unwrap();
+        assert_eq!(report.deleted_pages + report.deleted_payloads, 0);
+        let mut view = Snapshot::load(&snapshot.get_kvs_id(), &kvs).unwrap().open(&kvs);
+        assert_eq!(view.read(0, 4096).unwrap(), old);
+        assert!(matches!(view.write(0, &old), Err(StorageError::ReadOnly)));
+
+        Snapshot::delete(&kvs, device.id, snapshot.generation).unwrap();
+        let report = collect(&kvs, GcOptions { dry_run: true, ..NOW }).unwrap();
+        assert_eq!((report.unreferenced_pages, report.unreferenced_payloads), (1, 1));
+        assert_eq!(report.deleted_pages + report.deleted_payloads, 0);
+
+        let report = collect(&kvs, NOW).unwrap();
+        assert_eq!((report.deleted_pages, report.deleted_payloads), (1, 1));
+        assert!(report.reclaimed_bytes > 0);
+        let mut reloaded = BlockDevice::load(&device.get_kvs_id(), &kvs).unwrap();
+        assert_eq!(reloaded.read(0, 4096).unwrap(), vec![0x22u8; 4096]);
+    }
+
+    #[test]
+    fn waits_out_the_grace_period() {
+        let kvs = Kvs::in_memory();
+        let mut device = device(&kvs);
+        device.write(0, &vec![0x33u8; 4096]).unwrap();
+        device.flush(&kvs).unwrap();
+        device.trim(0, 4096).unwrap();
+        device.flush(&kvs).unwrap();
+
+        let options = GcOptions { dry_run: false, grace_secs: 3600 };
+        let report = collect(&kvs, options).unwrap();
+        assert_eq!((report.unreferenced_payloads, report.deleted_payloads), (1, 0));
+        let marker = kvs.scan_keys(&format!("{}*", GC_CANDIDATE_PREFIX)).unwrap();
+        assert_eq!(marker.len(), 1);
+        let payload = marker[0][GC_CANDIDATE_PREFIX.len()..].to_string();
+
+        //writing the same data again reuses the payload and takes it off the candidate list,
+        //a sweep racing with it then leaves the payload alone
+        device.write(0, &vec![0x33u8; 4096]).unwrap();
+        assert!(kvs.scan_keys(&format!("{}*", GC_CANDIDATE_PREFIX)).unwrap().is_empty());
+        assert!(!kvs.delete_if_marked(&marker[0], &payload).unwrap());
+        assert!(kvs.get_raw(&payload).unwrap().is_some());
+        device.flush(&kvs).unwrap();
+        let report = collect(&kvs, NOW).unwrap();
+        assert_eq!(report.unreferenced_payloads, 0);
+        assert_eq!(device.read(0, 4096).unwrap(), vec![0x33u8; 4096]);
+    }
+
+    #[test]
+    fn stale_device_handles_cannot_overwrite_snapshots() {
+        let kvs = Kvs::in_memory();
+        let mut device = device(&kvs);
+        device.write(0, &vec![0x44u8; 512]).unwrap();
+        device.flush(&kvs).unwrap();
+        let mut stale = BlockDevice::load(&device.get_kvs_id(), &kvs).unwrap();
+        device.snapshot(&kvs).unwrap();
+        stale.write(0, &vec![0x55u8; 512]).unwrap();
+        assert!(matches!(stale.flush(&kvs), Err(StorageError::Conflict(_))));
+    }
+}
@@ -1,46 +1,214 @@
-use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
-use std::error::Error;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
 use redis;
+use crate::manager::Codec;
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::Metrics::METRICS;
 
+#[derive(Clone)]
 pub struct Kvs {
-    pub conn: Arc<Mutex<redis::Connection>>,
+    backend: Arc<Mutex<Backend>>,
+}
+
+enum Backend {
+    Redis(redis::Connection),
+    #[cfg_attr(not(test), allow(dead_code))]
+    Memory(BTreeMap<String, Vec<u8>>), //process local, for tests and throwaway devices
 }
 
 impl Kvs {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    pub fn new() -> StorageResult<Self> {
         let client = redis::Client::open("redis://127.0.0.1/")?;
         let conn = client.get_connection()?;
         Ok(Kvs {
-            conn: Arc::new(Mutex::new(conn)),
+            backend: Arc::new(Mutex::new(Backend::Redis(conn))),
         })
     }
 
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Kvs {
+            backend: Arc::new(Mutex::new(Backend::Memory(BTreeMap::new()))),
+        }
+    }
+
+    fn backend(&self) -> StorageResult<MutexGuard<'_, Backend>> {
+        self.backend.lock()
+            .map_err(|_| StorageError::BackendUnavailable("kvs connection poisoned".into()))
+    }
+
     //FIXME: Move from manual commands
 
-    pub fn store<T: KvsStorable + Serialize>(&self, item: &T) -> Result<(), Box<dyn Error>> {
-        let mut guard = self.conn.lock().unwrap();
-        let conn = &mut *guard;
-        let serialized = serde_json::to_string(item)?;
-        let key = item.get_kvs_id();
-        redis::cmd("SET").arg(key).arg(serialized).query::<()> (conn)?;
+    pub fn store<T: KvsStorable + Serialize>(&self, item: &T) -> StorageResult<()> {
+        let serialized = Codec::encode(item)?;
+        self.set_raw(&item.get_kvs_id(), &serialized)
+    }
+
+    pub fn load<T: KvsStorable + DeserializeOwned>(&self, id: &str) -> StorageResult<T> {
+        let serialized = self.get_raw(id)?
+            .ok_or_else(|| StorageError::NotFound(id.to_string()))?;
+        Codec::decode(&serialized)
+    }
+
+    pub fn get_raw(&self, key: &str) -> StorageResult<Option<Vec<u8>>> {
+        let _timer = METRICS.kvs_latency.start_timer();
+        match &mut *self.backend()? {
+            Backend::Redis(conn) => Ok(redis::cmd("GET").arg(key).query(conn)?),
+            Backend::Memory(map) => Ok(map.get(key).cloned()),
+        }
+    }
+
+    pub fn set_raw(&self, key: &str, value: &[u8]) -> StorageResult<()> {
+        let _timer = METRICS.kvs_latency.start_timer();
+        match &mut *self.backend()? {
+            Backend::Redis(conn) => redis::cmd("SET").arg(key).arg(value).query::<()>(conn)?,
+            Backend::Memory(map) => {
+                map.insert(key.to_string(), value.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    //SET NX, returns false when the key already existed and was left untouched
+    pub fn set_raw_if_absent(&self, key: &str, value: &[u8]) -> StorageResult<bool> {
+        let _timer = METRICS.kvs_latency.start_timer();
+        match &mut *self.backend()? {
+            Backend::Redis(conn) => {
+                let reply: Option<String> = redis::cmd("SET").arg(key).arg(value).arg("NX").query(conn)?;
+                Ok(reply.is_some())
+            }
+            Backend::Memory(map) => {
+                if map.contains_key(key) {
+                    return Ok(false);
+                }
+                map.insert(key.to_string(), value.to_vec());
+                Ok(true)
+            }
+        }
+    }
+
+    //size of the stored value in bytes, 0 when the key does not exist
+    pub fn value_len(&self, key: &str) -> StorageResult<u64> {
+        let _timer = METRICS.kvs_latency.start_timer();
+        match &mut *self.backend()? {
+            Backend::Redis(conn) => Ok(redis::cmd("STRLEN").arg(key).query(conn)?),
+            Backend::Memory(map) => Ok(map.get(key).map(|v| v.len() as u64).unwrap_or(0)),
+        }
+    }
+
+    //INCRBY, atomically adds to an integer value and returns the result, a missing key counts as 0
+    pub fn incr_by(&self, key: &str, delta: i64) -> StorageResult<i64> {
+        let _timer = METRICS.kvs_latency.start_timer();
+        match &mut *self.backend()? {
+            Backend::Redis(conn) => Ok(redis::cmd("INCRBY").arg(key).arg(delta).query(conn)?),
+            Backend::Memory(map) => {
+                let current: i64 = match map.get(key) {
+                    Some(raw) => std::str::from_utf8(raw).ok().and_then(|v| v.parse().ok()).ok_or_else(|| {
+                        StorageError::InvalidArgument(format!("{} does not hold an integer", key))
+                    })?,
+                    None => 0,
+                };
+                let next = current.checked_add(delta)
+                    .ok_or_else(|| StorageError::InvalidArgument(format!("{} would overflow", key)))?;
+                map.insert(key.to_string(), next.to_string().into_bytes());
+                Ok(next)
+            }
+        }
+    }
+
+    pub fn delete(&self, key: &str) -> StorageResult<()> {
+        let _timer = METRICS.kvs_latency.start_timer();
+        match &mut *self.backend()? {
+            Backend::Redis(conn) => redis::cmd("DEL").arg(key).query::<()>(conn)?,
+            Backend::Memory(map) => {
+                map.remove(key);
+            }
+        }
         Ok(())
     }
 
-    pub fn load<T: KvsStorable + for<'de> Deserialize<'de>>(&self, id: &str) -> Result<T, Box<dyn Error>> {
-        let mut guard = self.conn.lock().unwrap();
-        let conn = &mut *guard;
-        let serialized: String = redis::cmd("GET").arg(id).query(conn)?;
-        let item: T = serde_json::from_str(&serialized)?;
-        Ok(item)
+    //deletes `key` together with `marker`, but only while `marker` still exists. Checked and done in one
+    //step, so a writer that drops the marker concurrently either wins and keeps the key or loses cleanly.
+    pub fn delete_if_marked(&self, marker: &str, key: &str) -> StorageResult<bool> {
+        const SCRIPT: &str = "if redis.call('EXISTS', KEYS[1]) == 1 then \
+            redis.call('DEL', KEYS[1], KEYS[2]) return 1 else return 0 end";
+        let _timer = METRICS.kvs_latency.start_timer();
+        match &mut *self.backend()? {
+            Backend::Redis(conn) => {
+                let deleted: i64 = redis::cmd("EVAL").arg(SCRIPT).arg(2).arg(marker).arg(key).query(conn)?;
+                Ok(deleted == 1)
+            }
+            Backend::Memory(map) => {
+                if map.remove(marker).is_none() {
+                    return Ok(false);
+                }
+                map.remove(key);
+                Ok(true)
+            }
+        }
+    }
+
+    //all keys matching a redis glob pattern, walked with SCAN so large keyspaces do not block the server
+    pub fn scan_keys(&self, pattern: &str) -> StorageResult<Vec<String>> {
+        let _timer = METRICS.kvs_latency.start_timer();
+        let mut keys = Vec::new();
+        match &mut *self.backend()? {
+            Backend::Redis(conn) => {
+                let mut cursor: u64 = 0;
+                loop {
+                    let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                        .arg(cursor)
+                        .arg("MATCH")
+                        .arg(pattern)
+                        .arg("COUNT")
+                        .arg(1000)
+                        .query(conn)?;
+                    keys.extend(batch);
+                    if next == 0 {
+                        break;
+                    }
+                    cursor = next;
+                }
+            }
+            Backend::Memory(map) => {
+                keys.extend(map.keys().filter(|k| glob_match(pattern.as_bytes(), k.as_bytes())).cloned());
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+}
+
+impl fmt::Debug for Kvs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.backend.lock().as_deref() {
+            Ok(Backend::Redis(_)) => "redis",
+            Ok(Backend::Memory(_)) => "memory",
+            Err(_) => "poisoned",
+        };
+        write!(f, "Kvs({})", kind)
+    }
+}
+
+//subset of redis glob syntax: `*` and `?`, enough for the prefix scans we issue
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((c, rest)) => key.first() == Some(c) && glob_match(rest, &key[1..]),
     }
 }
 
 
 pub trait KvsStorable {
-    fn store(&self, kvs: &Kvs) -> Result<(), Box<dyn Error>>;
-    fn load(id: &str, kvs: &Kvs) -> Result<Self, Box<dyn Error>>
+    fn store(&self, kvs: &Kvs) -> StorageResult<()>;
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self>
     where
         Self: Sized;
     fn get_kvs_id(&self) -> String;
-}
\ No newline at end of file
+}
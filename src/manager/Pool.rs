@@ -0,0 +1,236 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::storage::BlockDevice::{self as block_device, BlockDevice};
+use crate::utils::clock::unix_now;
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::Metrics::{Metrics, METRICS};
+
+pub const KVS_PREFIX: &str = "Pool:";
+pub const USAGE_PREFIX: &str = "PoolUsage:"; //integer, bytes allocated by the pool's devices
+pub const DEFAULT_HIGH_WATERMARK_PERCENT: u8 = 80;
+
+//Capacity budget shared by a group of thin devices. What counts against it are the bytes the
+//devices have mapped, before dedupe and compression, so the budget is never exceeded physically.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pool {
+    pub name: String,
+    pub capacity_bytes: u64,
+    pub high_watermark_percent: u8, //warn once allocation crosses this share of the capacity
+    pub created_at: u64,
+}
+
+//A pool with its current allocation, as listed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolStatus {
+    #[serde(flatten)]
+    pub pool: Pool,
+    pub allocated_bytes: u64,
+}
+
+impl Pool {
+    pub fn new(name: &str, capacity_bytes: u64) -> Self {
+        Pool {
+            name: name.to_string(),
+            capacity_bytes,
+            high_watermark_percent: DEFAULT_HIGH_WATERMARK_PERCENT,
+            created_at: unix_now(),
+        }
+    }
+
+    pub fn kvs_id(name: &str) -> String {
+        format!("{}{}", KVS_PREFIX, name)
+    }
+
+    fn usage_key(name: &str) -> String {
+        format!("{}{}", USAGE_PREFIX, name)
+    }
+
+    pub fn create(kvs: &Kvs, pool: &Pool) -> StorageResult<()> {
+        if pool.name.is_empty() || pool.high_watermark_percent > 100 {
+            return Err(StorageError::InvalidArgument("pools need a name and a watermark of at most 100%".into()));
+        }
+        if kvs.get_raw(&pool.get_kvs_id())?.is_some() {
+            return Err(StorageError::Conflict(format!("pool {} already exists", pool.name)));
+        }
+        pool.store(kvs)
+    }
+
+    pub fn list(kvs: &Kvs) -> StorageResult<Vec<PoolStatus>> {
+        let mut pools = Vec::new();
+        for key in kvs.scan_keys(&format!("{}*", KVS_PREFIX))? {
+            let pool = match Pool::load(&key, kvs) {
+                Ok(pool) => pool,
+                Err(StorageError::NotFound(_)) => continue, //deleted while we were scanning
+                Err(e) => return Err(e),
+            };
+            let allocated_bytes = Self::allocated(kvs, &pool.name)?;
+            pools.push(PoolStatus { pool, allocated_bytes });
+        }
+        Ok(pools)
+    }
+
+    pub fn allocated(kvs: &Kvs, name: &str) -> StorageResult<u64> {
+        Ok(kvs.incr_by(&Self::usage_key(name), 0)?.max(0) as u64)
+    }
+
+    //adds to the pool's allocation, failing with NoSpace instead of going over the capacity.
+    //Releasing space (a negative delta) always succeeds.
+    pub fn charge(kvs: &Kvs, name: &str, delta_bytes: i64) -> StorageResult<()> {
+        if delta_bytes == 0 {
+            return Ok(());
+        }
+        let key = Self::usage_key(name);
+        if delta_bytes < 0 {
+            kvs.incr_by(&key, delta_bytes)?;
+            return Ok(());
+        }
+        let pool = Pool::load(&Self::kvs_id(name), kvs)?;
+        //add first and take it back when that went over, two writers can never both fit into the last free bytes
+        let after = kvs.incr_by(&key, delta_bytes)?;
+        if after as u64 > pool.capacity_bytes {
+            kvs.incr_by(&key, -delta_bytes)?;
+            Metrics::inc(&METRICS.quota_rejections);
+            warn!(
+                pool = name;
+                "pool {} is full: {} of {} bytes allocated, refusing {} more",
+                name, after - delta_bytes, pool.capacity_bytes, delta_bytes
+            );
+            return Err(StorageError::NoSpace);
+        }
+        let mark = pool.capacity_bytes / 100 * pool.high_watermark_percent as u64;
+        if ((after - delta_bytes) as u64) < mark && after as u64 >= mark {
+            warn!(
+                pool = name;
+                "pool {} crossed its {}% high watermark: {} of {} bytes allocated",
+                name, pool.high_watermark_percent, after, pool.capacity_bytes
+            );
+        }
+        Ok(())
+    }
+
+    //only pools no device belongs to can go
+    pub fn delete(kvs: &Kvs, name: &str) -> StorageResult<()> {
+        let key = Self::kvs_id(name);
+        if kvs.get_raw(&key)?.is_none() {
+            return Err(StorageError::NotFound(key));
+        }
+        let members = Self::members(kvs)?.remove(name).unwrap_or_default();
+        if !members.is_empty() {
+            return Err(StorageError::Conflict(format!("pool {} still holds {} device(s)", name, members.len())));
+        }
+        kvs.delete(&Self::usage_key(name))?;
+        kvs.delete(&key)
+    }
+
+    //resets every pool's allocation to what its devices have mapped, repairing counts left behind
+    //by processes that died between allocating and flushing
+    pub fn recount(kvs: &Kvs) -> StorageResult<BTreeMap<String, u64>> {
+        let mut allocated: BTreeMap<String, u64> = Self::list(kvs)?.into_iter().map(|p| (p.pool.name, 0)).collect();
+        for (name, devices) in Self::members(kvs)? {
+            let total = allocated.entry(name).or_default();
+            for mut device in devices {
+                *total += device.allocated_bytes()?;
+            }
+        }
+        for (name, bytes) in &allocated {
+            kvs.set_raw(&Self::usage_key(name), bytes.to_string().as_bytes())?;
+        }
+        Ok(allocated)
+    }
+
+    //stored devices by the pool they belong to
+    fn members(kvs: &Kvs) -> StorageResult<BTreeMap<String, Vec<BlockDevice>>> {
+        let mut members: BTreeMap<String, Vec<BlockDevice>> = BTreeMap::new();
+        for key in kvs.scan_keys(&format!("{}*", block_device::KVS_PREFIX))? {
+            let device = match BlockDevice::load(&key, kvs) {
+                Ok(device) => device,
+                Err(StorageError::NotFound(_)) => continue, //deleted while we were scanning
+                Err(e) => return Err(e),
+            };
+            if let Some(pool) = device.pool.clone() {
+                members.entry(pool).or_default().push(device);
+            }
+        }
+        Ok(members)
+    }
+}
+
+impl KvsStorable for Pool {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        Self::kvs_id(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_in(kvs: &Kvs, id: u128, pool: &str) -> BlockDevice {
+        let mut device = BlockDevice::new(id, 64 * 512);
+        device.pool = Some(pool.to_string());
+        device.attach(kvs.clone());
+        device
+    }
+
+    #[test]
+    fn devices_share_the_pool_capacity() {
+        let kvs = Kvs::in_memory();
+        Pool::create(&kvs, &Pool::new("ci", 16 * 512)).unwrap();
+        let mut a = device_in(&kvs, 1, "ci");
+        let mut b = device_in(&kvs, 2, "ci");
+        a.write(0, &[1u8; 10 * 512]).unwrap();
+        a.write(0, &[2u8; 10 * 512]).unwrap(); //overwrites take no new space
+        assert!(matches!(b.write(0, &[3u8; 8 * 512]), Err(StorageError::NoSpace)));
+        assert_eq!(b.read(0, 512).unwrap(), vec![0u8; 512]);
+        b.write(0, &[3u8; 6 * 512]).unwrap();
+        assert_eq!(Pool::allocated(&kvs, "ci").unwrap(), 16 * 512);
+
+        a.trim(0, 4 * 512).unwrap();
+        b.write(6 * 512, &[3u8; 4 * 512]).unwrap();
+        assert_eq!(Pool::allocated(&kvs, "ci").unwrap(), 16 * 512);
+    }
+
+    #[test]
+    fn device_quota_applies_without_a_pool() {
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(3, 64 * 512);
+        device.quota_bytes = Some(4 * 512);
+        device.attach(kvs.clone());
+        device.write(0, &[1u8; 4 * 512]).unwrap();
+        assert!(matches!(device.write(8 * 512, &[1u8; 1]), Err(StorageError::NoSpace)));
+        device.write(0, &[0u8; 2 * 512]).unwrap(); //zeros unmap
+        device.write(8 * 512, &[1u8; 1]).unwrap();
+        assert_eq!(device.allocated_bytes().unwrap(), 3 * 512);
+    }
+
+    #[test]
+    fn recount_repairs_lost_allocations() {
+        let kvs = Kvs::in_memory();
+        Pool::create(&kvs, &Pool::new("ci", 64 * 512)).unwrap();
+        Pool::create(&kvs, &Pool::new("empty", 512)).unwrap();
+        let mut device = device_in(&kvs, 4, "ci");
+        device.write(0, &[1u8; 8 * 512]).unwrap();
+        device.flush(&kvs).unwrap();
+        device.write(8 * 512, &[1u8; 8 * 512]).unwrap();
+        drop(device); //died before flushing the second write
+
+        assert_eq!(Pool::allocated(&kvs, "ci").unwrap(), 16 * 512);
+        let counts = Pool::recount(&kvs).unwrap();
+        assert_eq!(counts["ci"], 8 * 512);
+        assert_eq!(counts["empty"], 0);
+        assert!(matches!(Pool::delete(&kvs, "ci"), Err(StorageError::Conflict(_))));
+        BlockDevice::delete(&kvs, 4).unwrap();
+        assert_eq!(Pool::allocated(&kvs, "ci").unwrap(), 0);
+        Pool::delete(&kvs, "ci").unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::id_string;
+
+pub const KVS_PREFIX: &str = "Qos:";
+
+//Sustained rate with the burst that may be spent at once after an idle period
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limit {
+    pub rate: u64, //per second
+    pub burst: u64,
+}
+
+impl Limit {
+    //`rate` or `rate:burst`, the burst defaults to one second worth of rate
+    pub fn parse(s: &str, parse_amount: impl Fn(&str) -> Option<u64>) -> StorageResult<Self> {
+        let invalid = || StorageError::InvalidArgument(format!("expected <rate>[:<burst>], got {:?}", s));
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (parse_amount(rate).ok_or_else(invalid)?, parse_amount(burst).ok_or_else(invalid)?),
+            None => {
+                let rate = parse_amount(s).ok_or_else(invalid)?;
+                (rate, rate)
+            }
+        };
+        if rate == 0 || burst == 0 {
+            return Err(invalid());
+        }
+        Ok(Limit { rate, burst })
+    }
+}
+
+//Per device limits, unset ones do not restrict anything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QosLimits {
+    #[serde(with = "id_string")]
+    pub device_id: u128,
+    pub read_iops: Option<Limit>,
+    pub write_iops: Option<Limit>,
+    pub read_bytes: Option<Limit>,
+    pub write_bytes: Option<Limit>,
+}
+
+impl QosLimits {
+    pub fn kvs_id(device_id: u128) -> String {
+        format!("{}{}", KVS_PREFIX, device_id)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.read_iops.is_none() && self.write_iops.is_none() && self.read_bytes.is_none() && self.write_bytes.is_none()
+    }
+
+    //limits of a device, unlimited when none were set
+    pub fn for_device(kvs: &Kvs, device_id: u128) -> StorageResult<Self> {
+        match QosLimits::load(&Self::kvs_id(device_id), kvs) {
+            Err(StorageError::NotFound(_)) => Ok(QosLimits { device_id, ..QosLimits::default() }),
+            other => other,
+        }
+    }
+
+    //stores the limits, dropping the record when nothing is limited
+    pub fn save(&self, kvs: &Kvs) -> StorageResult<()> {
+        if self.is_unlimited() {
+            return kvs.delete(&self.get_kvs_id());
+        }
+        self.store(kvs)
+    }
+}
+
+impl KvsStorable for QosLimits {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        Self::kvs_id(self.device_id)
+    }
+}
+
+//Token bucket that may go into debt: a request larger than the burst is let through once
+//enough time has passed to pay for it, instead of never fitting
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        TokenBucket { limit, tokens: limit.burst as f64, refilled_at: now }
+    }
+
+    //takes `amount` tokens and returns how long the caller has to wait before going ahead
+    fn take(&mut self, amount: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.refilled_at = now;
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.limit.rate as f64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoKind {
+    Read,
+    Write,
+}
+
+//Enforces QosLimits on the requests of one served device
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    limits: QosLimits,
+    read_iops: Option<TokenBucket>,
+    write_iops: Option<TokenBucket>,
+    read_bytes: Option<TokenBucket>,
+    write_bytes: Option<TokenBucket>,
+}
+
+impl Throttle {
+    pub fn new(limits: QosLimits) -> Self {
+        let now = Instant::now();
+        let bucket = |limit: Option<Limit>| limit.map(|limit| TokenBucket::new(limit, now));
+        Throttle {
+            read_iops: bucket(limits.read_iops),
+            write_iops: bucket(limits.write_iops),
+            read_bytes: bucket(limits.read_bytes),
+            write_bytes: bucket(limits.write_bytes),
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> &QosLimits {
+        &self.limits
+    }
+
+    //swaps in new limits, buckets of limits that did not change keep their state
+    pub fn update(&mut self, limits: QosLimits) {
+        if limits == self.limits {
+            return;
+        }
+        let mut next = Throttle::new(limits);
+        let keep = |old: &mut Option<TokenBucket>, new: &mut Option<TokenBucket>| {
+            if let (Some(o), Some(n)) = (old.as_ref(), new.as_ref())
+                && o.limit == n.limit
+            {
+                *new = old.take();
+            }
+        };
+        keep(&mut self.read_iops, &mut next.read_iops);
+        keep(&mut self.write_iops, &mut next.write_iops);
+        keep(&mut self.read_bytes, &mut next.read_bytes);
+        keep(&mut self.write_bytes, &mut next.write_bytes);
+        *self = next;
+    }
+
+    //accounts one request and returns how long to hold it back
+    pub fn delay(&mut self, kind: IoKind, bytes: u64, now: Instant) -> Duration {
+        let (iops, throughput) = match kind {
+            IoKind::Read => (&mut self.read_iops, &mut self.read_bytes),
+            IoKind::Write => (&mut self.write_iops, &mut self.write_bytes),
+        };
+        let ops_wait = iops.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(1, now));
+        let bytes_wait = throughput.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(bytes, now));
+        ops_wait.max(bytes_wait)
+    }
+}
+
+//A request the throttle holds back. Serve waits for it in the same select as for signals and the
+//drain deadline, so a request deep in debt never keeps shutdown or a handover waiting.
+#[derive(Debug)]
+pub struct Held<T> {
+    pub until: tokio::time::Instant,
+    pub item: T,
+}
+
+impl<T> Held<T> {
+    pub fn new(item: T, delay: Duration) -> Self {
+        Held { until: tokio::time::Instant::now() + delay, item }
+    }
+}
+
+//hands out the held item once it is due and never resolves while nothing is held. Dropping the
+//future before then leaves the item where it was.
+pub async fn release<T>(held: &mut Option<Held<T>>) -> T {
+    match held.as_ref().map(|held| held.until) {
+        Some(until) => tokio::time::sleep_until(until).await,
+        None => std::future::pending().await,
+    }
+    held.take().expect("checked to be held above").item
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_settles_at_the_rate() {
+        let limits = QosLimits {
+            write_iops: Some(Limit { rate: 100, burst: 10 }),
+            read_bytes: Some(Limit { rate: 1000, burst: 1000 }),
+            ..QosLimits::default()
+        };
+        let mut throttle = Throttle::new(limits);
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(throttle.delay(IoKind::Write, 4096, start), Duration::ZERO);
+        }
+        let wait = throttle.delay(IoKind::Write, 4096, start);
+        assert!((wait.as_secs_f64() - 0.01).abs() < 1e-9, "{wait:?}");
+        //after a second the bucket is full again, but never above the burst
+        let later = start + Duration::from_secs(1);
+        for _ in 0..10 {
+            assert_eq!(throttle.delay(IoKind::Write, 4096, later), Duration::ZERO);
+        }
+        assert!(throttle.delay(IoKind::Write, 4096, later) > Duration::ZERO);
+
+        //reads are limited separately, by bytes only
+        assert_eq!(throttle.delay(IoKind::Read, 1000, start), Duration::ZERO);
+        assert_eq!(throttle.delay(IoKind::Read, 3000, start), Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn held_requests_do_not_hold_up_signals() {
+        let (stop, mut stopped) = tokio::sync::oneshot::channel::<()>();
+        let mut held = Some(Held::new("write", Duration::from_secs(600)));
+        stop.send(()).unwrap();
+        let shutdown = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                _ = release(&mut held) => false,
+                _ = &mut stopped => true,
+            }
+        });
+        assert!(shutdown.await.unwrap());
+        assert_eq!(held.as_ref().map(|held| held.item), Some("write"));
+
+        held = Some(Held::new("read", Duration::from_millis(10)));
+        assert_eq!(release(&mut held).await, "read");
+        assert!(held.is_none());
+        let idle = tokio::time::timeout(Duration::from_millis(20), release(&mut held));
+        assert!(idle.await.is_err());
+    }
+
+    #[test]
+    fn parses_rates_with_optional_bursts() {
+        let number = |s: &str| s.parse().ok();
+        assert_eq!(Limit::parse("500", number).unwrap(), Limit { rate: 500, burst: 500 });
+        assert_eq!(Limit::parse("500:2000", number).unwrap(), Limit { rate: 500, burst: 2000 });
+        assert!(Limit::parse("0", number).is_err());
+        assert!(Limit::parse("fast", number).is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::storage::BlockDevice::{self as block_device, BlockDevice};
+use crate::utils::clock::unix_now;
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::id_string;
+
+pub const DEVICE_PREFIX: &str = "Registry:Device:";
+pub const ATTACHMENT_PREFIX: &str = "Registry:Attachment:";
+pub const NAME_PREFIX: &str = "Registry:Name:"; //name -> device id, makes names unique
+const MAX_NAME_LEN: usize = 64;
+
+//Index entry of a device, small enough to list every device without loading its record
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceEntry {
+    #[serde(with = "id_string")]
+    pub id: u128,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub size_bytes: u64,
+    pub created_at: u64,
+}
+
+impl DeviceEntry {
+    pub fn for_device(device: &BlockDevice) -> Self {
+        DeviceEntry {
+            id: device.id,
+            name: None,
+            labels: BTreeMap::new(),
+            size_bytes: device.logical_size_bytes,
+            created_at: unix_now(),
+        }
+    }
+
+    pub fn kvs_id(id: u128) -> String {
+        format!("{}{}", DEVICE_PREFIX, id)
+    }
+}
+
+impl KvsStorable for DeviceEntry {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        Self::kvs_id(self.id)
+    }
+}
+
+//Which host serves a device on which nbd device, at most one per device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    #[serde(with = "id_string")]
+    pub device_id: u128,
+    pub host: String,
+    pub nbd: String,
+    pub pid: u32, //of the serve process on that host
+    pub attached_at: u64,
+}
+
+impl Attachment {
+    pub fn kvs_id(device_id: u128) -> String {
+        format!("{}{}", ATTACHMENT_PREFIX, device_id)
+    }
+
+    pub fn find(kvs: &Kvs, device_id: u128) -> StorageResult<Option<Attachment>> {
+        match Attachment::load(&Self::kvs_id(device_id), kvs) {
+            Ok(attachment) => Ok(Some(attachment)),
+            Err(StorageError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn list(kvs: &Kvs) -> StorageResult<Vec<Attachment>> {
+        load_matching(kvs, &format!("{}*", ATTACHMENT_PREFIX))
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.host == hostname()
+    }
+}
+
+impl KvsStorable for Attachment {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        Self::kvs_id(self.device_id)
+    }
+}
+
+//Restricts a listing, every field that is set has to match
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceFilter {
+    pub labels: BTreeMap<String, String>,
+    pub host: Option<String>, //only devices attached on this host
+    pub attached: Option<bool>,
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, entry: &DeviceEntry, attachment: Option<&Attachment>) -> bool {
+        self.labels.iter().all(|(k, v)| entry.labels.get(k) == Some(v))
+            && self.host.as_ref().is_none_or(|host| attachment.is_some_and(|a| &a.host == host))
+            && self.attached.is_none_or(|attached| attached == attachment.is_some())
+    }
+}
+
+//stores the entry, claiming its name first and releasing the one it had before
+pub fn register(kvs: &Kvs, entry: &DeviceEntry) -> StorageResult<()> {
+    if let Some(name) = &entry.name {
+        validate_name(name)?;
+        claim_name(kvs, name, entry.id)?;
+    }
+    let previous = match self::entry(kvs, entry.id) {
+        Ok(previous) => previous.name,
+        Err(StorageError::NotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+    entry.store(kvs)?;
+    match previous {
+        Some(old) if entry.name.as_ref() != Some(&old) => release_name(kvs, &old, entry.id),
+        _ => Ok(()),
+    }
+}
+
+//drops the entry, its name and any attachment, called once the device record itself is gone
+pub fn unregister(kvs: &Kvs, device_id: u128) -> StorageResult<()> {
+    if let Ok(DeviceEntry { name: Some(name), .. }) = entry(kvs, device_id) {
+        release_name(kvs, &name, device_id)?;
+    }
+    kvs.delete(&Attachment::kvs_id(device_id))?;
+    kvs.delete(&DeviceEntry::kvs_id(device_id))
+}
+
+pub fn entry(kvs: &Kvs, device_id: u128) -> StorageResult<DeviceEntry> {
+    DeviceEntry::load(&DeviceEntry::kvs_id(device_id), kvs)
+}
+
+//gives the device a new name, or takes its name away
+pub fn rename(kvs: &Kvs, device_id: u128, name: Option<String>) -> StorageResult<DeviceEntry> {
+    let entry = DeviceEntry { name, ..entry(kvs, device_id)? };
+    register(kvs, &entry)?;
+    Ok(entry)
+}
+
+//sets and removes labels, removals are applied last
+pub fn relabel(kvs: &Kvs, device_id: u128, set: BTreeMap<String, String>, remove: &[String]) -> StorageResult<DeviceEntry> {
+    let mut entry = entry(kvs, device_id)?;
+    entry.labels.extend(set);
+    for key in remove {
+        entry.labels.remove(key);
+    }
+    entry.store(kvs)?;
+    Ok(entry)
+}
+
+//a device id, or the name of a device. Names never look like numbers, so there is no ambiguity.
+pub fn resolve(kvs: &Kvs, device: &str) -> StorageResult<u128> {
+    if let Ok(id) = device.parse() {
+        return Ok(id);
+    }
+    match kvs.get_raw(&name_key(device))? {
+        Some(id) => parse_id(&id),
+        None => Err(StorageError::NotFound(format!("no device is named {:?}", device))),
+    }
+}
+
+pub fn validate_name(name: &str) -> StorageResult<()> {
+    let valid_chars = name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !valid_chars || name.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(StorageError::InvalidArgument(format!(
+            "device names are 1 to {} letters, digits, '-', '_' or '.', and not a number: {:?}", MAX_NAME_LEN, name
+        )));
+    }
+    Ok(())
+}
+
+//fails when the name is malformed or taken, checked before creating a device under it
+pub fn check_name(kvs: &Kvs, name: &str) -> StorageResult<()> {
+    validate_name(name)?;
+    match kvs.get_raw(&name_key(name))? {
+        Some(owner) => Err(StorageError::Conflict(format!(
+            "name {:?} is taken by device {}", name, String::from_utf8_lossy(&owner)
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn name_key(name: &str) -> String {
+    format!("{}{}", NAME_PREFIX, name)
+}
+
+fn parse_id(raw: &[u8]) -> StorageResult<u128> {
+    std::str::from_utf8(raw).ok()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| StorageError::Corruption(format!("name lookup holds {:?}, not a device id", raw)))
+}
+
+fn claim_name(kvs: &Kvs, name: &str, device_id: u128) -> StorageResult<()> {
+    let key = name_key(name);
+    if kvs.set_raw_if_absent(&key, device_id.to_string().as_bytes())? {
+        return Ok(());
+    }
+    match kvs.get_raw(&key)? {
+        Some(owner) if parse_id(&owner)? == device_id => Ok(()),
+        Some(owner) => Err(StorageError::Conflict(format!(
+            "name {:?} is taken by device {}", name, String::from_utf8_lossy(&owner)
+        ))),
+        None => claim_name(kvs, name, device_id), //released in the meantime
+    }
+}
+
+//only drops the lookup while it still points at the device, someone may have claimed the name since
+fn release_name(kvs: &Kvs, name: &str, device_id: u128) -> StorageResult<()> {
+    match kvs.get_raw(&name_key(name))? {
+        Some(owner) if parse_id(&owner).ok() == Some(device_id) => kvs.delete(&name_key(name)),
+        _ => Ok(()),
+    }
+}
+
+//indexed devices passing the filter with their attachment, ordered by id
+pub fn devices(kvs: &Kvs, filter: &DeviceFilter) -> StorageResult<Vec<(DeviceEntry, Option<Attachment>)>> {
+    let mut attachments: BTreeMap<u128, Attachment> = Attachment::list(kvs)?
+        .into_iter()
+        .map(|a| (a.device_id, a))
+        .collect();
+    let mut entries: Vec<DeviceEntry> = load_matching(kvs, &format!("{}*", DEVICE_PREFIX))?;
+    entries.sort_by_key(|entry| entry.id);
+    Ok(entries.into_iter()
+        .map(|entry| {
+            let attachment = attachments.remove(&entry.id);
+            (entry, attachment)
+        })
+        .filter(|(entry, attachment)| filter.matches(entry, attachment.as_ref()))
+        .collect())
+}
+
+//Counts of what rebuild changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RebuildReport {
+    pub added: usize,
+    pub removed: usize,
+}
+
+//makes the index agree with the stored device records: devices written without going through
+//the registry are added, entries and attachments of devices that are gone are dropped
+pub fn rebuild(kvs: &Kvs) -> StorageResult<RebuildReport> {
+    let mut report = RebuildReport::default();
+    let stored: BTreeSet<u128> = ids_under(kvs, block_device::KVS_PREFIX)?;
+    let indexed: BTreeSet<u128> = ids_under(kvs, DEVICE_PREFIX)?;
+    for &id in stored.difference(&indexed) {
+        let device: BlockDevice = match kvs.load(&format!("{}{}", block_device::KVS_PREFIX, id)) {
+            Ok(device) => device,
+            Err(StorageError::NotFound(_)) => continue, //deleted while we were scanning
+            Err(e) => return Err(e),
+        };
+        if device.file_of.is_some() {
+            continue; //holds the contents of a file, the filesystem keeps track of it
+        }
+        register(kvs, &DeviceEntry::for_device(&device))?;
+        report.added += 1;
+    }
+    for &id in indexed.difference(&stored) {
+        unregister(kvs, id)?;
+        report.removed += 1;
+    }
+    for id in ids_under(kvs, ATTACHMENT_PREFIX)? {
+        if !stored.contains(&id) {
+            kvs.delete(&Attachment::kvs_id(id))?;
+        }
+    }
+    //name lookups follow the entries, two entries claiming one name keep whoever holds the lookup
+    let mut named = BTreeSet::new();
+    for entry in load_matching::<DeviceEntry>(kvs, &format!("{}*", DEVICE_PREFIX))? {
+        if let Some(name) = &entry.name {
+            match claim_name(kvs, name, entry.id) {
+                Ok(()) => {
+                    named.insert(name.clone());
+                }
+                Err(StorageError::Conflict(msg)) => warn!(device:% = entry.id; "registry: {}", msg),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    for key in kvs.scan_keys(&format!("{}*", NAME_PREFIX))? {
+        if !named.contains(&key[NAME_PREFIX.len()..]) {
+            kvs.delete(&key)?;
+        }
+    }
+    Ok(report)
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn ids_under(kvs: &Kvs, prefix: &str) -> StorageResult<BTreeSet<u128>> {
+    Ok(kvs.scan_keys(&format!("{}*", prefix))?
+        .iter()
+        .filter_map(|key| key.strip_prefix(prefix)?.parse().ok())
+        .collect())
+}
+
+fn load_matching<T: KvsStorable>(kvs: &Kvs, pattern: &str) -> StorageResult<Vec<T>> {
+    let mut items = Vec::new();
+    for key in kvs.scan_keys(pattern)? {
+        match T::load(&key, kvs) {
+            Ok(item) => items.push(item),
+            Err(StorageError::NotFound(_)) => continue, //deleted while we were scanning
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_by_label_and_attachment() {
+        let kvs = Kvs::in_memory();
+        for id in 1..=3 {
+            let mut entry = DeviceEntry::for_device(&BlockDevice::new(id, 4096));
+            entry.labels.insert("tier".into(), if id == 2 { "gold" } else { "bronze" }.into());
+            register(&kvs, &entry).unwrap();
+        }
+        Attachment { device_id: 3, host: "a".into(), nbd: "/dev/nbd1".into(), pid: 1, attached_at: 0 }
+            .store(&kvs).unwrap();
+
+        let ids = |filter: DeviceFilter| -> Vec<u128> {
+            devices(&kvs, &filter).unwrap().iter().map(|(e, _)| e.id).collect()
+        };
+        assert_eq!(ids(DeviceFilter::default()), [1, 2, 3]);
+        let mut bronze = DeviceFilter::default();
+        bronze.labels.insert("tier".into(), "bronze".into());
+        assert_eq!(ids(bronze.clone()), [1, 3]);
+        assert_eq!(ids(DeviceFilter { attached: Some(false), ..bronze }), [1]);
+        assert_eq!(ids(DeviceFilter { host: Some("a".into()), ..Default::default() }), [3]);
+        assert_eq!(ids(DeviceFilter { host: Some("b".into()), ..Default::default() }), Vec::<u128>::new());
+    }
+
+    #[test]
+    fn rebuild_follows_the_device_records() {
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(5, 4096);
+        device.attach(kvs.clone());
+        device.flush(&kvs).unwrap();
+        register(&kvs, &DeviceEntry::for_device(&BlockDevice::new(6, 4096))).unwrap();
+        Attachment { device_id: 6, host: "a".into(), nbd: "/dev/nbd0".into(), pid: 1, attached_at: 0 }
+            .store(&kvs).unwrap();
+
+        assert_eq!(rebuild(&kvs).unwrap(), RebuildReport { added: 1, removed: 1 });
+        assert_eq!(entry(&kvs, 5).unwrap().size_bytes, 4096);
+        assert!(matches!(entry(&kvs, 6), Err(StorageError::NotFound(_))));
+        assert!(Attachment::list(&kvs).unwrap().is_empty());
+        assert_eq!(rebuild(&kvs).unwrap(), RebuildReport::default());
+    }
+
+    #[test]
+    fn names_are_unique_and_resolve_to_ids() {
+        let kvs = Kvs::in_memory();
+        let named = |id: u128, name: &str| DeviceEntry {
+            name: Some(name.into()),
+            ..DeviceEntry::for_device(&BlockDevice::new(id, 4096))
+        };
+        register(&kvs, &named(1, "ci-golden-ubuntu")).unwrap();
+        assert_eq!(resolve(&kvs, "ci-golden-ubuntu").unwrap(), 1);
+        assert_eq!(resolve(&kvs, "7").unwrap(), 7);
+        assert!(matches!(register(&kvs, &named(2, "ci-golden-ubuntu")), Err(StorageError::Conflict(_))));
+        assert!(matches!(register(&kvs, &named(2, "42")), Err(StorageError::InvalidArgument(_))));
+
+        rename(&kvs, 1, Some("ci-golden-debian".into())).unwrap();
+        assert!(matches!(resolve(&kvs, "ci-golden-ubuntu"), Err(StorageError::NotFound(_))));
+        register(&kvs, &named(2, "ci-golden-ubuntu")).unwrap();
+        unregister(&kvs, 1).unwrap();
+        assert!(matches!(resolve(&kvs, "ci-golden-debian"), Err(StorageError::NotFound(_))));
+        assert_eq!(resolve(&kvs, "ci-golden-ubuntu").unwrap(), 2);
+    }
+}
@@ -0,0 +1,242 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, Instant};
+use crate::manager::Kvs::Kvs;
+use crate::nbd::Trace::{self, TraceReader};
+use crate::storage::BlockDevice::BlockDevice;
+use crate::utils::Error::StorageResult;
+
+//Where a trace is re-issued: a stored device directly, or anything that takes reads and writes
+//at an offset, /dev/nbdX of a served device included
+pub trait ReplayTarget {
+    fn read(&mut self, offset: u64, len: usize) -> StorageResult<Vec<u8>>;
+    fn write(&mut self, offset: u64, data: &[u8]) -> StorageResult<()>;
+    fn flush(&mut self) -> StorageResult<()>;
+    fn trim(&mut self, offset: u64, len: usize) -> StorageResult<()>;
+}
+
+//Runs requests the way serve does, journal writes included, without the nbd round trip
+pub struct DeviceTarget {
+    pub device: BlockDevice,
+    kvs: Kvs,
+}
+
+impl DeviceTarget {
+    pub fn new(mut device: BlockDevice, kvs: Kvs) -> Self {
+        device.enable_journal();
+        DeviceTarget { device, kvs }
+    }
+}
+
+impl ReplayTarget for DeviceTarget {
+    fn read(&mut self, offset: u64, len: usize) -> StorageResult<Vec<u8>> {
+        self.device.read(offset, len)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> StorageResult<()> {
+        self.device.write(offset, data)?;
+        self.device.persist_journal(&self.kvs)
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        self.device.flush(&self.kvs)
+    }
+
+    fn trim(&mut self, offset: u64, len: usize) -> StorageResult<()> {
+        self.device.trim(offset, len)?;
+        self.device.persist_journal(&self.kvs)
+    }
+}
+
+//A block device node or a plain file
+pub struct FileTarget {
+    file: File,
+}
+
+impl FileTarget {
+    pub fn new(file: File) -> Self {
+        FileTarget { file }
+    }
+}
+
+impl ReplayTarget for FileTarget {
+    fn read(&mut self, offset: u64, len: usize) -> StorageResult<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        self.file.read_exact_at(&mut data, offset)?;
+        Ok(data)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> StorageResult<()> {
+        Ok(self.file.write_all_at(data, offset)?)
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        Ok(self.file.sync_data()?)
+    }
+
+    //punching a hole in a block device node discards the range
+    fn trim(&mut self, offset: u64, len: usize) -> StorageResult<()> {
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        if unsafe { libc::fallocate(self.file.as_raw_fd(), mode, offset as libc::off_t, len as libc::off_t) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplayOptions {
+    pub speed: Option<f64>, //multiple of the recorded pace, as fast as possible when unset
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub mean_us: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayReport {
+    pub requests: u64,
+    pub skipped: u64, //commands replay does not know
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+    pub errno_mismatches: u64, //answered differently than when the trace was recorded
+    pub elapsed_secs: f64,
+    pub recorded_secs: f64,
+    pub latency: BTreeMap<&'static str, LatencySummary>,
+}
+
+//Re-issues every request of the trace against the target. Traces do not carry the data that
+//was written, so writes get generated data seeded by the recorded hash when there is one:
+//blocks that were identical are identical again and dedupe behaves as it did.
+pub fn replay<R: Read>(trace: TraceReader<R>, target: &mut dyn ReplayTarget, options: ReplayOptions) -> StorageResult<ReplayReport> {
+    let mut report = ReplayReport::default();
+    let mut latencies: BTreeMap<&'static str, Vec<u64>> = BTreeMap::new();
+    let started = Instant::now();
+    for (index, record) in trace.enumerate() {
+        let record = record?;
+        if let Some(speed) = options.speed {
+            let due = Duration::from_micros((record.at_us as f64 / speed) as u64);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        let issued = Instant::now();
+        let (command, result) = match record.cmd {
+            Trace::CMD_READ => {
+                let res = target.read(record.offset, record.len as usize);
+                if res.is_ok() {
+                    report.bytes_read += record.len as u64;
+                }
+                ("READ", res.map(|_| ()))
+            }
+            Trace::CMD_WRITE => {
+                let data = generated_data(record.hash.unwrap_or(index as u64), record.len as usize);
+                let res = target.write(record.offset, &data);
+                if res.is_ok() {
+                    report.bytes_written += record.len as u64;
+                }
+                ("WRITE", res)
+            }
+            Trace::CMD_FLUSH => ("FLUSH", target.flush()),
+            Trace::CMD_TRIM => ("TRIM", target.trim(record.offset, record.len as usize)),
+            _ => {
+                report.skipped += 1;
+                continue;
+            }
+        };
+        latencies.entry(command).or_default().push(issued.elapsed().as_micros() as u64);
+        report.requests += 1;
+        report.recorded_secs = record.at_us as f64 / 1e6;
+        let errno = errno_of(&result);
+        if errno != 0 {
+            report.errors += 1;
+        }
+        if errno != record.errno {
+            report.errno_mismatches += 1;
+        }
+    }
+    report.elapsed_secs = started.elapsed().as_secs_f64();
+    report.latency = latencies.into_iter().map(|(command, samples)| (command, summarize(samples))).collect();
+    Ok(report)
+}
+
+fn errno_of(result: &StorageResult<()>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.errno() as u32,
+    }
+}
+
+fn summarize(mut samples: Vec<u64>) -> LatencySummary {
+    samples.sort_unstable();
+    let at = |quantile: f64| samples[((samples.len() - 1) as f64 * quantile).round() as usize];
+    LatencySummary {
+        count: samples.len() as u64,
+        mean_us: samples.iter().sum::<u64>() / samples.len() as u64,
+        p50_us: at(0.5),
+        p99_us: at(0.99),
+        max_us: *samples.last().expect("summaries are only made for commands that ran"),
+    }
+}
+
+//splitmix64 stream, cheap and the same for the same seed
+fn generated_data(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    let mut data = Vec::with_capacity(len + 8);
+    while data.len() < len {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        data.extend_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+    }
+    data.truncate(len);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nbd::Trace::TraceWriter;
+
+    #[test]
+    fn replays_a_trace_against_a_device() {
+        let path = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        let mut writer = TraceWriter::create(&path, true).unwrap();
+        writer.record(Trace::CMD_WRITE as u32, 0, 4096, 0, &[1u8; 4096]).unwrap();
+        writer.record(Trace::CMD_WRITE as u32, 4096, 4096, 0, &[1u8; 4096]).unwrap();
+        writer.record(Trace::CMD_READ as u32, 0, 8192, 0, &[1u8; 8192]).unwrap();
+        writer.record(Trace::CMD_TRIM as u32, 4096, 4096, 0, &[]).unwrap();
+        writer.record(Trace::CMD_FLUSH as u32, 0, 0, 0, &[]).unwrap();
+        writer.record(Trace::CMD_READ as u32, 1 << 30, 512, libc::EINVAL as u32, &[]).unwrap();
+        writer.record(9, 0, 0, 0, &[]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(1, 64 * 512);
+        device.attach(kvs.clone());
+        let mut target = DeviceTarget::new(device, kvs);
+        let report = replay(TraceReader::open(&path).unwrap(), &mut target, ReplayOptions::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((report.requests, report.skipped), (6, 1));
+        assert_eq!((report.bytes_written, report.bytes_read), (8192, 8192));
+        assert_eq!((report.errors, report.errno_mismatches), (1, 0));
+        assert_eq!(report.latency["WRITE"].count, 2);
+        //written data is derived from the recorded hash, the trimmed half reads as zeros again
+        let expected = generated_data(xxhash_rust::xxh3::xxh3_64(&[1u8; 4096]), 4096);
+        assert_eq!(target.device.read(0, 4096).unwrap(), expected);
+        assert_eq!(target.device.read(4096, 4096).unwrap(), vec![0u8; 4096]);
+    }
+}
@@ -0,0 +1,327 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use crate::manager::Gc;
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::storage::BlockDevice::{self as block_device, BlockDevice};
+use crate::storage::Encryption::MasterKey;
+use crate::storage::Payload;
+use crate::utils::clock::unix_now;
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::Metrics::{Metrics, METRICS};
+
+pub const CURSOR_KEY: &str = "Scrub:cursor";
+pub const REPORT_KEY: &str = "Scrub:report";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrubFinding {
+    pub device_id: u128,
+    pub start_block: u64,
+    pub blocks: u64,
+    pub payload: String, //or the block map page, when that is what is missing
+    pub problem: String,
+}
+
+//Outcome of one scrub pass, the last finished one is kept under REPORT_KEY
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrubReport {
+    pub pass_started_at: u64,
+    pub finished_at: u64,
+    pub devices: u64,
+    pub payloads_verified: u64,
+    pub bytes_verified: u64,
+    pub unverified_locked: u64, //encrypted payloads only checked for presence and size, no key loaded
+    pub corrupt: Vec<ScrubFinding>,
+    pub dangling: Vec<ScrubFinding>, //extents pointing at payloads, or roots at pages, that do not exist
+    pub orphaned: Vec<String>, //payloads no device points at
+}
+
+//Where an interrupted pass picks up again, persisted after every page
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrubCursor {
+    pub device_key: String,
+    pub next_page: u64,
+    pub report: ScrubReport,
+}
+
+impl KvsStorable for ScrubCursor {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        CURSOR_KEY.to_string()
+    }
+}
+
+impl KvsStorable for ScrubReport {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        REPORT_KEY.to_string()
+    }
+}
+
+pub struct Scrubber {
+    kvs: Kvs,
+    master: Option<MasterKey>,
+    rate_bytes_per_sec: Option<u64>,
+    throttle_started: Instant,
+    throttle_bytes: u64,
+}
+
+impl Scrubber {
+    pub fn new(kvs: Kvs, master: Option<MasterKey>, rate_bytes_per_sec: Option<u64>) -> Self {
+        Scrubber {
+            kvs,
+            master,
+            rate_bytes_per_sec,
+            throttle_started: Instant::now(),
+            throttle_bytes: 0,
+        }
+    }
+
+    //sleeps until the bytes read so far fit the configured rate
+    fn throttle(&mut self, bytes: u64) {
+        let rate = match self.rate_bytes_per_sec {
+            Some(rate) if rate > 0 => rate,
+            _ => return,
+        };
+        self.throttle_bytes += bytes;
+        let due = Duration::from_secs_f64(self.throttle_bytes as f64 / rate as f64);
+        let elapsed = self.throttle_started.elapsed();
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+
+    //runs a pass to completion, resuming from the persisted cursor if a previous one was interrupted
+    pub fn run_pass(&mut self) -> StorageResult<ScrubReport> {
+        let mut cursor = match ScrubCursor::load(CURSOR_KEY, &self.kvs) {
+            Ok(cursor) => {
+                info!("resuming scrub at {} page {}", cursor.device_key, cursor.next_page);
+                cursor
+            }
+            Err(StorageError::NotFound(_)) => ScrubCursor {
+                report: ScrubReport { pass_started_at: unix_now(), ..ScrubReport::default() },
+                ..ScrubCursor::default()
+            },
+            Err(e) => return Err(e),
+        };
+        self.throttle_started = Instant::now();
+        self.throttle_bytes = 0;
+
+        for key in self.kvs.scan_keys(&format!("{}*", block_device::KVS_PREFIX))? {
+            if key < cursor.device_key {
+                continue;
+            }
+            if key != cursor.device_key {
+                cursor.device_key = key.clone();
+                cursor.next_page = 0;
+            }
+            self.scrub_device(&mut cursor)?;
+        }
+
+        let referenced = Gc::mark(&self.kvs)?.payloads;
+        let mut report = cursor.report;
+        report.orphaned = self.kvs.scan_keys(&format!("{}*", Payload::KVS_PREFIX))?
+            .into_iter()
+            .filter(|key| !referenced.contains(key))
+            .collect();
+        report.finished_at = unix_now();
+        report.store(&self.kvs)?;
+        self.kvs.delete(CURSOR_KEY)?;
+        Ok(report)
+    }
+
+    fn scrub_device(&mut self, cursor: &mut ScrubCursor) -> StorageResult<()> {
+        let mut device = match BlockDevice::load(&cursor.device_key, &self.kvs) {
+            Ok(device) => device,
+            Err(StorageError::NotFound(_)) => return Ok(()), //deleted while we were scanning
+            Err(e) => return Err(e),
+        };
+        if let Some(master) = &self.master
+            && device.encryption.is_some()
+            && let Err(e) = device.unlock(master)
+        {
+            warn!(device:% = device.id; "scrub: cannot unlock device: {}", e);
+        }
+        if cursor.next_page == 0 {
+            cursor.report.devices += 1;
+        }
+
+        let mut verified = BTreeSet::new();
+        let page_indices: Vec<u64> = device.page_roots.range(cursor.next_page..).map(|(&index, _)| index).collect();
+        for page_index in page_indices {
+            let extents = match device.page_extents(page_index) {
+                Ok(extents) => extents,
+                Err(StorageError::NotFound(page_key)) => {
+                    //reported and passed over, failing here would resume at this page forever
+                    error!(device:% = device.id; "scrub: block map page {} is missing", page_key);
+                    cursor.report.dangling.push(ScrubFinding {
+                        device_id: device.id,
+                        start_block: page_index * device.page_span_blocks,
+                        blocks: device.page_span_blocks,
+                        payload: page_key,
+                        problem: "block map page is missing".into(),
+                    });
+                    Vec::new()
+                }
+                Err(e) => return Err(e),
+            };
+            for extent in extents {
+                let payload_key = Payload::kvs_id(&extent.content.hash, extent.content.codec);
+                if !verified.insert(payload_key.clone()) {
+                    continue;
+                }
+                let finding = |problem: String| ScrubFinding {
+                    device_id: device.id,
+                    start_block: extent.start,
+                    blocks: extent.length,
+                    payload: payload_key.clone(),
+                    problem,
+                };
+                let stored = match self.kvs.get_raw(&payload_key)? {
+                    Some(stored) => stored,
+                    None => {
+                        error!(device:% = device.id; "scrub: blocks {}..{} point at missing payload {}", extent.start, extent.end(), payload_key);
+                        cursor.report.dangling.push(finding("payload is missing".into()));
+                        continue;
+                    }
+                };
+                self.throttle(stored.len() as u64);
+                match Payload::decode(&extent.content, &stored, device.unlocked_data_key()) {
+                    Ok(data) => {
+                        cursor.report.payloads_verified += 1;
+                        cursor.report.bytes_verified += data.len() as u64;
+                    }
+                    Err(StorageError::Locked(_)) => cursor.report.unverified_locked += 1,
+                    Err(e) => {
+                        Metrics::inc(&METRICS.integrity_errors);
+                        error!(device:% = device.id; "scrub: blocks {}..{}: {}", extent.start, extent.end(), e);
+                        cursor.report.corrupt.push(finding(e.to_string()));
+                    }
+                }
+            }
+            cursor.next_page = page_index + 1;
+            cursor.store(&self.kvs)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::BlockPage::BlockPage;
+
+    fn device(kvs: &Kvs, id: u128) -> BlockDevice {
+        let mut device = BlockDevice::new(id, 64 * 1024);
+        device.attach(kvs.clone());
+        device
+    }
+
+    fn payload_keys(device: &mut BlockDevice) -> Vec<String> {
+        device.page_extents(0).unwrap()
+            .iter()
+            .map(|extent| Payload::kvs_id(&extent.content.hash, extent.content.codec))
+            .collect()
+    }
+
+    #[test]
+    fn classifies_corrupt_dangling_and_orphaned_payloads() {
+        let kvs = Kvs::in_memory();
+        let mut device = device(&kvs, 1);
+        for (i, fill) in [0x11u8, 0x22, 0x33].into_iter().enumerate() {
+            device.write(i as u64 * 16384, &[fill; 4096]).unwrap();
+        }
+        device.flush(&kvs).unwrap();
+        let keys = payload_keys(&mut device);
+        assert_eq!(keys.len(), 3);
+        let mut stored = kvs.get_raw(&keys[0]).unwrap().unwrap();
+        *stored.last_mut().unwrap() ^= 0xff;
+        kvs.set_raw(&keys[0], &stored).unwrap();
+        kvs.delete(&keys[1]).unwrap();
+        let orphan = format!("{}unreferenced", Payload::KVS_PREFIX);
+        kvs.set_raw(&orphan, b"left behind").unwrap();
+
+        let report = Scrubber::new(kvs.clone(), None, None).run_pass().unwrap();
+        assert_eq!((report.devices, report.payloads_verified), (1, 1));
+        assert_eq!(report.corrupt.iter().map(|f| f.payload.as_str()).collect::<Vec<_>>(), [keys[0].as_str()]);
+        assert_eq!(report.dangling.iter().map(|f| f.payload.as_str()).collect::<Vec<_>>(), [keys[1].as_str()]);
+        assert_eq!((report.dangling[0].device_id, report.dangling[0].start_block), (1, 32));
+        assert_eq!(report.orphaned, [orphan]);
+        assert_eq!(ScrubReport::load(REPORT_KEY, &kvs).unwrap(), report);
+    }
+
+    #[test]
+    fn reports_missing_block_map_pages_and_moves_on() {
+        let kvs = Kvs::in_memory();
+        let mut device = device(&kvs, 1);
+        device.page_span_blocks = 8;
+        device.write(0, &[0x44u8; 4096]).unwrap();
+        device.write(8192, &[0x55u8; 4096]).unwrap();
+        device.flush(&kvs).unwrap();
+        let page = BlockPage::kvs_id(1, 0, device.page_roots[&0]);
+        kvs.delete(&page).unwrap();
+
+        for _ in 0..2 {
+            let report = Scrubber::new(kvs.clone(), None, None).run_pass().unwrap();
+            assert_eq!(report.payloads_verified, 1);
+            assert_eq!(report.dangling.iter().map(|f| f.payload.as_str()).collect::<Vec<_>>(), [page.as_str()]);
+            assert_eq!((report.dangling[0].start_block, report.dangling[0].blocks), (0, 8));
+        }
+    }
+
+    #[test]
+    fn resumes_from_the_stored_cursor() {
+        let kvs = Kvs::in_memory();
+        for id in [1, 2] {
+            let mut device = device(&kvs, id);
+            device.write(0, &[id as u8; 4096]).unwrap();
+            device.flush(&kvs).unwrap();
+        }
+        //a pass interrupted after the first device
+        let interrupted = ScrubCursor {
+            device_key: format!("{}2", block_device::KVS_PREFIX),
+            next_page: 0,
+            report: ScrubReport { pass_started_at: 42, devices: 1, payloads_verified: 1, ..ScrubReport::default() },
+        };
+        interrupted.store(&kvs).unwrap();
+
+        let report = Scrubber::new(kvs.clone(), None, None).run_pass().unwrap();
+        assert_eq!((report.pass_started_at, report.devices, report.payloads_verified), (42, 2, 2));
+        assert!(matches!(ScrubCursor::load(CURSOR_KEY, &kvs), Err(StorageError::NotFound(_))));
+
+        let report = Scrubber::new(kvs.clone(), None, None).run_pass().unwrap();
+        assert_ne!(report.pass_started_at, 42);
+        assert_eq!((report.devices, report.payloads_verified), (2, 2));
+    }
+
+    #[test]
+    fn counts_encrypted_payloads_it_has_no_key_for() {
+        let kvs = Kvs::in_memory();
+        let master = MasterKey::generate();
+        let mut device = device(&kvs, 1);
+        device.enable_encryption(&master).unwrap();
+        device.write(0, &[0x55u8; 4096]).unwrap();
+        device.flush(&kvs).unwrap();
+
+        let report = Scrubber::new(kvs.clone(), None, None).run_pass().unwrap();
+        assert_eq!((report.unverified_locked, report.payloads_verified), (1, 0));
+        assert!(report.corrupt.is_empty());
+        let report = Scrubber::new(kvs.clone(), Some(master), None).run_pass().unwrap();
+        assert_eq!((report.unverified_locked, report.payloads_verified), (0, 1));
+    }
+}
@@ -1 +1,11 @@
-pub mod Kvs;
\ No newline at end of file
+pub mod Codec;
+pub mod Config;
+pub mod Control;
+pub mod Exporter;
+pub mod Gc;
+pub mod Kvs;
+pub mod Pool;
+pub mod Qos;
+pub mod Registry;
+pub mod Replay;
+pub mod Scrub;
@@ -0,0 +1,222 @@
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+// Generic netlink interface of the nbd driver: include/uapi/linux/nbd-netlink.h.
+// Unlike the ioctls, a netlink configured device survives its server going away for
+// dead_conn_timeout seconds, and NBD_CMD_RECONFIGURE hands it a new socket; requests
+// the old server never answered are sent again on the new one.
+const NBD_FAMILY_NAME: &[u8] = b"nbd\0";
+const NBD_GENL_VERSION: u8 = 1;
+
+const NBD_CMD_CONNECT: u8 = 1;
+const NBD_CMD_DISCONNECT: u8 = 2;
+const NBD_CMD_RECONFIGURE: u8 = 3;
+
+const NBD_ATTR_INDEX: u16 = 1;
+const NBD_ATTR_SIZE_BYTES: u16 = 2;
+const NBD_ATTR_BLOCK_SIZE_BYTES: u16 = 3;
+const NBD_ATTR_SERVER_FLAGS: u16 = 5;
+const NBD_ATTR_SOCKETS: u16 = 7;
+const NBD_ATTR_DEAD_CONN_TIMEOUT: u16 = 8;
+const NBD_SOCK_ITEM: u16 = 1;
+const NBD_SOCK_FD: u16 = 1;
+
+// include/uapi/linux/genetlink.h
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLMSG_HDR_LEN: usize = 16;
+const GENL_HDR_LEN: usize = 4;
+
+fn align4(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+//A generic netlink message under construction, attributes are appended in place
+struct Message {
+    buf: Vec<u8>,
+    nests: Vec<usize>,
+}
+
+impl Message {
+    fn new(family: u16, cmd: u8, version: u8) -> Self {
+        let mut buf = vec![0u8; NLMSG_HDR_LEN];
+        buf[4..6].copy_from_slice(&family.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        buf.extend_from_slice(&[cmd, version, 0, 0]);
+        Message { buf, nests: Vec::new() }
+    }
+
+    fn attr(&mut self, kind: u16, payload: &[u8]) -> &mut Self {
+        let len = 4 + payload.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(payload);
+        self.buf.resize(align4(self.buf.len()), 0);
+        self
+    }
+
+    fn u32(&mut self, kind: u16, value: u32) -> &mut Self {
+        self.attr(kind, &value.to_ne_bytes())
+    }
+
+    fn u64(&mut self, kind: u16, value: u64) -> &mut Self {
+        self.attr(kind, &value.to_ne_bytes())
+    }
+
+    fn begin_nested(&mut self, kind: u16) -> &mut Self {
+        self.nests.push(self.buf.len());
+        self.attr(kind | NLA_F_NESTED, &[])
+    }
+
+    fn end_nested(&mut self) -> &mut Self {
+        let start = self.nests.pop().expect("nested attribute was opened");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+
+    fn finish(&mut self, seq: u32) -> &[u8] {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        &self.buf
+    }
+}
+
+//walks the attributes of a generic netlink reply body
+fn attrs(mut body: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if body.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([body[0], body[1]]) as usize;
+        let kind = u16::from_ne_bytes([body[2], body[3]]) & !NLA_F_NESTED;
+        if len < 4 || len > body.len() {
+            return None;
+        }
+        let payload = &body[4..len];
+        body = &body[align4(len).min(body.len())..];
+        Some((kind, payload))
+    })
+}
+
+pub struct NbdNetlink {
+    fd: OwnedFd,
+    family: u16,
+    seq: u32,
+}
+
+impl NbdNetlink {
+    //opens a generic netlink socket and resolves the nbd family, which needs the nbd module loaded
+    pub fn open() -> io::Result<Self> {
+        let raw = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_GENERIC) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let bound = unsafe {
+            libc::bind(fd.as_raw_fd(), &addr as *const _ as *const libc::sockaddr, size_of::<libc::sockaddr_nl>() as u32)
+        };
+        if bound != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut netlink = NbdNetlink { fd, family: GENL_ID_CTRL, seq: 0 };
+        let mut msg = Message::new(GENL_ID_CTRL, CTRL_CMD_GETFAMILY, 1);
+        msg.attr(CTRL_ATTR_FAMILY_NAME, NBD_FAMILY_NAME);
+        let reply = netlink.request(&mut msg)?;
+        netlink.family = attrs(&reply)
+            .find(|(kind, payload)| *kind == CTRL_ATTR_FAMILY_ID && payload.len() >= 2)
+            .map(|(_, payload)| u16::from_ne_bytes([payload[0], payload[1]]))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "nbd generic netlink family not found"))?;
+        Ok(netlink)
+    }
+
+    //Sends the message and waits for the kernel to acknowledge it. Returns the attributes
+    //of the data reply, if the command sends one ahead of the acknowledgement.
+    fn request(&mut self, msg: &mut Message) -> io::Result<Vec<u8>> {
+        self.seq = self.seq.wrapping_add(1);
+        let out = msg.finish(self.seq);
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), out.as_ptr() as *const libc::c_void, out.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let got = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if got < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut rest = &buf[..got as usize];
+            while rest.len() >= NLMSG_HDR_LEN {
+                let len = u32::from_ne_bytes(rest[0..4].try_into().expect("4 bytes")) as usize;
+                let kind = u16::from_ne_bytes([rest[4], rest[5]]);
+                let seq = u32::from_ne_bytes(rest[8..12].try_into().expect("4 bytes"));
+                if len < NLMSG_HDR_LEN || len > rest.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink reply"));
+                }
+                let body = &rest[NLMSG_HDR_LEN..len];
+                rest = &rest[align4(len).min(rest.len())..];
+                if seq != self.seq {
+                    continue;
+                }
+                if kind == NLMSG_ERROR {
+                    let errno = i32::from_ne_bytes(body[0..4].try_into().expect("4 bytes"));
+                    if errno != 0 {
+                        return Err(io::Error::from_raw_os_error(-errno));
+                    }
+                    return Ok(data);
+                }
+                if body.len() >= GENL_HDR_LEN {
+                    data = body[GENL_HDR_LEN..].to_vec();
+                }
+            }
+        }
+    }
+
+    //configures /dev/nbd<index> to be served over `sock`
+    pub fn connect(&mut self, index: u32, sock: RawFd, size_bytes: u64, block_size: u64, server_flags: u64, dead_conn_timeout_secs: u64) -> io::Result<()> {
+        let mut msg = Message::new(self.family, NBD_CMD_CONNECT, NBD_GENL_VERSION);
+        msg.u32(NBD_ATTR_INDEX, index)
+            .u64(NBD_ATTR_SIZE_BYTES, size_bytes)
+            .u64(NBD_ATTR_BLOCK_SIZE_BYTES, block_size)
+            .u64(NBD_ATTR_SERVER_FLAGS, server_flags)
+            .u64(NBD_ATTR_DEAD_CONN_TIMEOUT, dead_conn_timeout_secs)
+            .begin_nested(NBD_ATTR_SOCKETS)
+            .begin_nested(NBD_SOCK_ITEM)
+            .u32(NBD_SOCK_FD, sock as u32)
+            .end_nested()
+            .end_nested();
+        self.request(&mut msg).map(drop)
+    }
+
+    //replaces the dead socket of a configured device, fails when its socket is still alive
+    pub fn reconfigure(&mut self, index: u32, sock: RawFd, dead_conn_timeout_secs: u64) -> io::Result<()> {
+        let mut msg = Message::new(self.family, NBD_CMD_RECONFIGURE, NBD_GENL_VERSION);
+        msg.u32(NBD_ATTR_INDEX, index)
+            .u64(NBD_ATTR_DEAD_CONN_TIMEOUT, dead_conn_timeout_secs)
+            .begin_nested(NBD_ATTR_SOCKETS)
+            .begin_nested(NBD_SOCK_ITEM)
+            .u32(NBD_SOCK_FD, sock as u32)
+            .end_nested()
+            .end_nested();
+        self.request(&mut msg).map(drop)
+    }
+
+    //asks the kernel to send NBD_CMD_DISC once the requests already queued are out
+    pub fn disconnect(&mut self, index: u32) -> io::Result<()> {
+        let mut msg = Message::new(self.family, NBD_CMD_DISCONNECT, NBD_GENL_VERSION);
+        msg.u32(NBD_ATTR_INDEX, index);
+        self.request(&mut msg).map(drop)
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Compact binary log of the requests a served device answered, for reproducing workloads.
+// A 24 byte header is followed by fixed size little endian records, so a trace can be
+// cut at any record boundary and still be read.
+//
+//   header: magic[8] "NBDTRACE", version u16, flags u16, reserved u32, started_at u64 (unix ns)
+//   record: at_us u64, offset u64, hash u64, len u32, errno u32, cmd u16, flags u16
+const MAGIC: &[u8; 8] = b"NBDTRACE";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 24;
+pub const RECORD_LEN: usize = 36;
+const WITH_HASHES: u16 = 1 << 0;
+
+// nbd request types (include/uapi/linux/nbd.h), the low 16 bits of the request's type field
+pub const CMD_READ: u16 = 0;
+pub const CMD_WRITE: u16 = 1;
+pub const CMD_FLUSH: u16 = 3;
+pub const CMD_TRIM: u16 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub version: u16,
+    pub with_hashes: bool,
+    pub started_at_ns: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub at_us: u64, //since the trace started
+    pub cmd: u16,
+    pub flags: u16, //nbd command flags such as FUA
+    pub offset: u64,
+    pub len: u32,
+    pub errno: u32, //what the request was answered with
+    pub hash: Option<u64>, //xxh3 of the data written or read back, when recorded
+}
+
+impl TraceRecord {
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut out = [0u8; RECORD_LEN];
+        out[0..8].copy_from_slice(&self.at_us.to_le_bytes());
+        out[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        out[16..24].copy_from_slice(&self.hash.unwrap_or(0).to_le_bytes());
+        out[24..28].copy_from_slice(&self.len.to_le_bytes());
+        out[28..32].copy_from_slice(&self.errno.to_le_bytes());
+        out[32..34].copy_from_slice(&self.cmd.to_le_bytes());
+        out[34..36].copy_from_slice(&self.flags.to_le_bytes());
+        out
+    }
+
+    fn decode(raw: &[u8; RECORD_LEN], with_hashes: bool) -> Self {
+        let u64_at = |at: usize| u64::from_le_bytes(raw[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_le_bytes(raw[at..at + 2].try_into().unwrap());
+        TraceRecord {
+            at_us: u64_at(0),
+            offset: u64_at(8),
+            hash: Some(u64_at(16)).filter(|&hash| with_hashes && hash != 0), //0 marks requests without data
+            len: u32_at(24),
+            errno: u32_at(28),
+            cmd: u16_at(32),
+            flags: u16_at(34),
+        }
+    }
+}
+
+// Appends requests to a trace file as they are answered
+pub struct TraceWriter {
+    out: BufWriter<File>,
+    started: Instant,
+    with_hashes: bool,
+}
+
+impl TraceWriter {
+    pub fn create(path: &Path, with_hashes: bool) -> io::Result<Self> {
+        let started_at_ns = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut header = [0u8; HEADER_LEN];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..10].copy_from_slice(&VERSION.to_le_bytes());
+        header[10..12].copy_from_slice(&(if with_hashes { WITH_HASHES } else { 0 }).to_le_bytes());
+        header[16..24].copy_from_slice(&started_at_ns.to_le_bytes());
+        out.write_all(&header)?;
+        Ok(TraceWriter { out, started: Instant::now(), with_hashes })
+    }
+
+    // `request_type` is the nbd type field with its flags, `data` what was written or read back
+    pub fn record(&mut self, request_type: u32, offset: u64, len: u32, errno: u32, data: &[u8]) -> io::Result<()> {
+        let record = TraceRecord {
+            at_us: self.started.elapsed().as_micros() as u64,
+            cmd: request_type as u16,
+            flags: (request_type >> 16) as u16,
+            offset,
+            len,
+            errno,
+            hash: (self.with_hashes && !data.is_empty()).then(|| xxhash_rust::xxh3::xxh3_64(data)),
+        };
+        self.out.write_all(&record.encode())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Reads a trace back, record by record
+pub struct TraceReader<R: Read> {
+    input: R,
+    pub header: TraceHeader,
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        TraceReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_LEN];
+        input.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a trace file"));
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported trace version {}", version)));
+        }
+        let flags = u16::from_le_bytes([header[10], header[11]]);
+        let header = TraceHeader {
+            version,
+            with_hashes: flags & WITH_HASHES != 0,
+            started_at_ns: u64::from_le_bytes(header[16..24].try_into().unwrap()),
+        };
+        Ok(TraceReader { input, header })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    // a record cut short by a crash of the recording process ends the trace
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut raw = [0u8; RECORD_LEN];
+        let mut filled = 0;
+        while filled < RECORD_LEN {
+            match self.input.read(&mut raw[filled..]) {
+                Ok(0) => return None,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(TraceRecord::decode(&raw, self.header.with_hashes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip_and_torn_tails_are_dropped() {
+        let path = std::env::temp_dir().join(format!("trace-test-{}", std::process::id()));
+        let mut writer = TraceWriter::create(&path, true).unwrap();
+        writer.record(CMD_WRITE as u32 | 1 << 16, 4096, 512, 0, &[7u8; 512]).unwrap();
+        writer.record(CMD_FLUSH as u32, 0, 0, 0, &[]).unwrap();
+        writer.record(CMD_READ as u32, 1 << 40, 8, libc::EIO as u32, &[]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        let mut raw = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        raw.extend_from_slice(&[1, 2, 3]);
+
+        let reader = TraceReader::new(&raw[..]).unwrap();
+        assert!(reader.header.with_hashes);
+        let records: Vec<TraceRecord> = reader.map(Result::unwrap).collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!((records[0].cmd, records[0].flags, records[0].offset, records[0].len), (CMD_WRITE, 1, 4096, 512));
+        assert_eq!(records[0].hash, Some(xxhash_rust::xxh3::xxh3_64(&[7u8; 512])));
+        assert_eq!(records[1].hash, None);
+        assert_eq!((records[2].offset, records[2].errno), (1 << 40, libc::EIO as u32));
+        assert!(records.windows(2).all(|w| w[0].at_us <= w[1].at_us));
+        assert!(TraceReader::new(&b"NOTATRACE_______________"[..]).is_err());
+    }
+}
@@ -1,198 +1,1245 @@
+use log::{error, info, trace, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use std::error::Error;
+use std::collections::{BTreeMap, BTreeSet};
 use std::cmp::min;
+use crate::manager::Codec;
 use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::manager::Pool::Pool;
+use crate::manager::Registry::Attachment;
+use crate::storage::BlockPage::BlockPage;
+use crate::storage::Compression::Compression;
+use crate::storage::Delta::RestorePoint;
+use crate::storage::Encryption::{DataKey, MasterKey, NonceSeed, WrappedKey};
+use crate::storage::Extent::Extent;
+use crate::storage::Journal::{JournalEntry, JournalOp};
+use crate::storage::Payload::{self, PayloadCache, WriteOptions, WriteOverrides, MAX_PAYLOAD_BLOCKS};
+use crate::storage::Snapshot::Snapshot;
+use crate::utils::checksum::ChecksumKind;
+use crate::utils::clock::unix_now;
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::Metrics::{Metrics, METRICS};
 
+pub const KVS_PREFIX: &str = "BlockDevice:";
+pub const PAGE_SPAN_BLOCKS: u64 = 65536; //blocks covered by one BlockPage
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+const MAX_CACHED_PAGES: usize = 256; //clean pages beyond this are dropped and reloaded on demand
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+//Top-level device record: geometry, generation and the roots of its block map pages.
+//The block map itself lives in BlockPage records that are loaded on demand, and maps
+//logical block ranges onto extents of content addressed payloads.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockDevice {
     pub id: u128,
     pub logical_size_bytes: u64,
     pub block_size_bytes: usize,
     pub generation: u32,
-    pub blocks: BTreeMap<u64, Block>,
+    #[serde(default = "default_page_span")]
+    pub page_span_blocks: u64,
+    #[serde(default)]
+    pub page_roots: BTreeMap<u64, u32>, //page index -> generation the page was last written in
+    #[serde(default)]
+    pub compression: Compression, //codec new payloads are written with
+    #[serde(default)]
+    pub checksum: ChecksumKind, //how payload reads are verified
+    #[serde(default)]
+    pub encryption: Option<WrappedKey>, //data key payloads are sealed with, wrapped by a master key
+    #[serde(default)]
+    pub restored_from: Option<RestorePoint>, //last delta applied to this device
+    #[serde(default)]
+    pub pool: Option<String>, //pool whose capacity the device allocates from
+    #[serde(default)]
+    pub quota_bytes: Option<u64>, //most bytes the device may have mapped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_of: Option<String>, //filesystem whose file contents the device holds, kept out of the registry
+    #[serde(default)]
+    allocated_blocks: Option<u64>, //mapped blocks, None for records written before it was counted
+    #[serde(rename = "blocks", default, skip_serializing)]
+    legacy_blocks: BTreeMap<u64, LegacyBlock>, //inline block map of records written before pages existed
+    #[serde(skip)]
+    pages: BTreeMap<u64, BlockPage>,
+    #[serde(skip)]
+    dirty_pages: BTreeSet<u64>,
+    #[serde(skip)]
+    pub write_overrides: WriteOverrides, //set by serve from its config, the record keeps its own settings
+    #[serde(skip)]
+    payload_cache: PayloadCache,
+    #[serde(skip)]
+    data_key: Option<DataKey>,
+    #[serde(skip)]
+    kvs: Option<Kvs>,
+    #[serde(skip)]
+    read_only: bool, //set on snapshot views, whose pages are shared with later generations
+    #[serde(skip)]
+    journal: Option<Vec<JournalOp>>, //block map changes not yet persisted, None while journaling is off
+    #[serde(skip)]
+    journal_seq: u64,
+    #[serde(skip)]
+    journaled: Vec<String>, //journal entries the next flush makes redundant
+}
+
+fn default_page_span() -> u64 {
+    PAGE_SPAN_BLOCKS
 }
 
+//Logical vs physical space of a device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceUsage {
+    pub logical_size_bytes: u64,
+    pub allocated_bytes: u64, //logical bytes mapped to payloads
+    pub stored_bytes: u64, //bytes of the referenced payloads as stored, after compression
+    pub payloads: u64,
+}
+
+//Inline block with its data, as written before payloads moved out into their own records.
+//Only decoded to convert old records into extents.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Block {
+pub struct LegacyBlock {
     pub index: u64,
+    #[serde(with = "serde_bytes")]
     pub hash: [u8; 32],
-    data: Vec<u8> // Placeholder for block data, will be queried from storage
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
 }
 
-impl Block{
-    pub fn new(index: u64, hash: [u8; 32]) -> Self {
-        //fill data with zeros for now
-        let mut data = vec![0u8; 0];
-        Block {
-            index,
-            hash,
-            data
+impl BlockDevice {
+
+    pub fn new(id: u128, logical_size_bytes: u64) -> Self {
+        BlockDevice {
+            id,
+            logical_size_bytes,
+            block_size_bytes: DEFAULT_BLOCK_SIZE,
+            generation: 1,
+            page_span_blocks: PAGE_SPAN_BLOCKS,
+            page_roots: BTreeMap::new(),
+            compression: Compression::None,
+            checksum: ChecksumKind::Sha256,
+            encryption: None,
+            restored_from: None,
+            pool: None,
+            quota_bytes: None,
+            file_of: None,
+            allocated_blocks: Some(0),
+            legacy_blocks: BTreeMap::new(),
+            pages: BTreeMap::new(),
+            dirty_pages: BTreeSet::new(),
+            write_overrides: WriteOverrides::default(),
+            payload_cache: PayloadCache::default(),
+            data_key: None,
+            kvs: None,
+            read_only: false,
+            journal: None,
+            journal_seq: 0,
+            journaled: Vec::new(),
+        }
+    }
+
+    //binds the device to the store its pages and payloads live in
+    pub fn attach(&mut self, kvs: Kvs) {
+        self.kvs = Some(kvs);
+    }
+
+    //gives the device a fresh data key, only allowed while nothing has been written
+    pub fn enable_encryption(&mut self, master: &MasterKey) -> StorageResult<()> {
+        if self.encryption.is_some() || !self.page_roots.is_empty() || !self.legacy_blocks.is_empty() {
+            return Err(StorageError::Conflict(format!(
+                "device {} already holds data or a key, encryption can only be enabled on a new device", self.id
+            )));
         }
+        let data_key = DataKey::generate();
+        self.encryption = Some(master.wrap(&data_key, self.id)?);
+        self.data_key = Some(data_key);
+        Ok(())
     }
 
-    pub fn new_block(index: u64) -> Self {
-        println!("Creating new block with index {}", index);
-        //exactly 512 bytes of data 
-        let empty_data = vec![0u8; 512];
-        Block {
-            index,
-            hash: [0u8; 32],
-            data: empty_data,
+    //unwraps the data key so encrypted payloads can be read and written
+    pub fn unlock(&mut self, master: &MasterKey) -> StorageResult<()> {
+        if let Some(wrapped) = &self.encryption {
+            self.data_key = Some(master.unwrap(wrapped, self.id)?);
         }
+        Ok(())
     }
 
-    pub fn get_data(&self) -> Vec<u8> {
-        self.data.clone()
+    //re-wraps the data key under a new master key, payloads stay as they are
+    pub fn rewrap_key(&mut self, old: &MasterKey, new: &MasterKey) -> StorageResult<()> {
+        let wrapped = self.encryption.as_ref()
+            .ok_or_else(|| StorageError::InvalidArgument(format!("device {} is not encrypted", self.id)))?;
+        let data_key = old.unwrap(wrapped, self.id)?;
+        self.encryption = Some(new.wrap(&data_key, self.id)?);
+        self.data_key = Some(data_key);
+        Ok(())
     }
 
-    pub fn write_data(&mut self, offset: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
-        println!("Writing data to block {} of block size {} with data size {}", self.index, self.data.len(), data.len());
-        if data.len() > (self.data.len() - offset as usize) {
-            return Err("Data size exceeds block size".into());
+    fn data_key(&self) -> StorageResult<Option<&DataKey>> {
+        match (&self.encryption, &self.data_key) {
+            (None, _) => Ok(None),
+            (Some(_), Some(key)) => Ok(Some(key)),
+            (Some(_), None) => Err(StorageError::Locked(format!("device {} is encrypted", self.id))),
         }
-        for i in 0..data.len() {
-            self.data[offset as usize + i] = data[i];
+    }
+
+    //refuses further writes, used for the frozen records held by snapshots
+    pub fn freeze(&mut self) {
+        self.read_only = true;
+    }
+
+    fn check_writable(&self) -> StorageResult<()> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
         }
         Ok(())
     }
 
-    pub fn read_data(&self, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        if length != self.data.len() {
-            return Err("Requested length does not match block size".into());
+    fn kvs(&self) -> StorageResult<Kvs> {
+        self.kvs.clone().ok_or_else(|| {
+            StorageError::BackendUnavailable(format!("device {} is not attached to a store", self.id))
+        })
+    }
+
+    pub fn translate_block_to_page_index(&self, block_index: u64) -> u64 {
+        block_index / self.page_span_blocks
+    }
+
+    //validates the whole span [byte_offset, byte_offset + length) and returns its end offset
+    pub fn check_span(&self, byte_offset: u64, length: usize) -> StorageResult<u64> {
+        let end = byte_offset.checked_add(length as u64).ok_or(StorageError::Overflow {
+            offset: byte_offset,
+            length: length as u64,
+        })?;
+        if end > self.logical_size_bytes {
+            return Err(self.out_of_range(byte_offset, length));
         }
-        let data = self.data[..length].to_vec();
-        Ok(data) 
+        Ok(end)
     }
 
+    //the payload block backing a logical block, None for holes
+    #[cfg(test)]
+    pub fn lookup_block(&mut self, block_index: u64) -> StorageResult<Option<crate::storage::Extent::ContentRef>> {
+        let page_index = self.translate_block_to_page_index(block_index);
+        if !self.load_page(page_index)? {
+            return Ok(None);
+        }
+        Ok(self.pages.get(&page_index)
+            .and_then(|page| page.find(block_index))
+            .map(|extent| extent.content_at(block_index)))
+    }
 
-    pub fn to_string(&self) -> String {
-        format!(
-            "Block {{ index: {}, hash: {:x?}, actual_size_bytes: {} }}",
-            self.index, self.hash, self.data.len()
-        )
+    fn out_of_range(&self, byte_offset: u64, length: usize) -> StorageError {
+        StorageError::OutOfRange {
+            offset: byte_offset,
+            length: length as u64,
+            size: self.logical_size_bytes,
+        }
     }
 
+    //makes sure the page is in the cache if it exists at all, returns whether it does
+    fn load_page(&mut self, page_index: u64) -> StorageResult<bool> {
+        if self.pages.contains_key(&page_index) {
+            Metrics::inc(&METRICS.page_cache_hits);
+            return Ok(true);
+        }
+        let generation = match self.page_roots.get(&page_index) {
+            Some(&generation) => generation,
+            None => return Ok(false),
+        };
+        Metrics::inc(&METRICS.page_cache_misses);
+        let mut page = BlockPage::load(&BlockPage::kvs_id(self.id, page_index, generation), &self.kvs()?)?;
+        let legacy = std::mem::take(&mut page.legacy_blocks);
+        self.pages.insert(page_index, page);
+        for (block_index, block) in legacy {
+            self.write_legacy_block(block_index, block)?;
+        }
+        Ok(true)
+    }
 
-}
+    //loads or creates the page for writing and marks it dirty in the current generation
+    fn page_for_write(&mut self, page_index: u64) -> StorageResult<&mut BlockPage> {
+        if !self.load_page(page_index)? {
+            self.pages.insert(page_index, BlockPage::new(self.id, page_index, self.generation));
+        }
+        self.page_roots.insert(page_index, self.generation);
+        self.dirty_pages.insert(page_index);
+        let page = self.pages.get_mut(&page_index).expect("page was just loaded");
+        page.generation = self.generation;
+        Ok(page)
+    }
 
-impl BlockDevice {
+    //drops clean pages once the cache grows past MAX_CACHED_PAGES, dirty ones stay until stored
+    fn evict_clean_pages(&mut self) {
+        if self.pages.len() <= MAX_CACHED_PAGES || self.kvs.is_none() {
+            return;
+        }
+        let clean: Vec<u64> = self.pages.keys()
+            .filter(|index| !self.dirty_pages.contains(index))
+            .copied()
+            .collect();
+        for index in clean {
+            if self.pages.len() <= MAX_CACHED_PAGES {
+                break;
+            }
+            self.pages.remove(&index);
+        }
+    }
 
-    pub fn new(id: u128, logical_size_bytes: u64) -> Self {
-        return BlockDevice {
-            id,
-            logical_size_bytes,
-            block_size_bytes: 512,
-            generation: 1,
-            blocks: BTreeMap::new(),
+    //reads `count` whole blocks starting at `first`, holes read as zeros
+    fn read_blocks(&mut self, first: u64, count: u64) -> StorageResult<Vec<u8>> {
+        let block_size = self.block_size_bytes as u64;
+        let mut result = vec![0u8; (count * block_size) as usize];
+        let end = first + count;
+        let mut page_start = first;
+        while page_start < end {
+            let page_index = self.translate_block_to_page_index(page_start);
+            let page_end = min(end, (page_index + 1) * self.page_span_blocks);
+            if self.load_page(page_index)? {
+                let extents = self.pages[&page_index].overlapping(page_start, page_end);
+                for extent in extents {
+                    let kvs = self.kvs()?;
+                    let key = self.data_key.as_ref();
+                    let payload = match self.payload_cache.get(&kvs, &extent.content, key) {
+                        Ok(payload) => payload,
+                        Err(StorageError::Corruption(msg)) => {
+                            Metrics::inc(&METRICS.integrity_errors);
+                            error!(
+                                device:% = self.id;
+                                "integrity error on blocks {}..{}: {}", extent.start, extent.end(), msg
+                            );
+                            return Err(StorageError::Corruption(msg));
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    let src_start = (extent.content.offset * block_size) as usize;
+                    let src_end = src_start + (extent.length * block_size) as usize;
+                    if src_end > payload.len() {
+                        return Err(StorageError::Corruption(format!(
+                            "payload {} is {} bytes, extent needs {}",
+                            Payload::kvs_id(&extent.content.hash, extent.content.codec), payload.len(), src_end
+                        )));
+                    }
+                    let dst_start = ((extent.start - first) * block_size) as usize;
+                    result[dst_start..dst_start + (src_end - src_start)].copy_from_slice(&payload[src_start..src_end]);
+                }
+            }
+            page_start = page_end;
         }
+        Ok(result)
     }
 
-    pub fn translate_byte_to_block_index(&self, byte_offset: u64) -> Option<(u64, usize)> { //returns (block_index, offset_within_block)
-        if byte_offset >= self.logical_size_bytes {
-            return None;
+    //maps whole blocks of data starting at block `first`, one payload per page and MAX_PAYLOAD_BLOCKS
+    fn write_blocks(&mut self, first: u64, data: &[u8]) -> StorageResult<()> {
+        let block_size = self.block_size_bytes as u64;
+        let end = first + data.len() as u64 / block_size;
+        let kvs = self.kvs()?;
+        let data_key = self.data_key()?.cloned();
+        let mut chunk_start = first;
+        while chunk_start < end {
+            let page_index = self.translate_block_to_page_index(chunk_start);
+            let chunk_end = min(min(end, (page_index + 1) * self.page_span_blocks), chunk_start + MAX_PAYLOAD_BLOCKS);
+            let chunk = &data[((chunk_start - first) * block_size) as usize..((chunk_end - first) * block_size) as usize];
+            let mapped = self.mapped_blocks(chunk_start, chunk_end)?;
+            if chunk.iter().all(|&b| b == 0) {
+                //zeros read back from holes, no need to store them
+                self.apply(JournalOp::Punch { start: chunk_start, end: chunk_end })?;
+                self.charge(-(mapped as i64))?;
+            } else {
+                //space is taken before the payload is stored, a refused write leaves nothing behind
+                let charged = (chunk_end - chunk_start - mapped) as i64;
+                self.charge(charged)?;
+                if let Err(e) = self.map_chunk(&kvs, chunk_start, chunk_end, chunk, data_key.as_ref()) {
+                    //and neither does one that failed after the space was taken
+                    if let Err(refund) = self.charge(-charged) {
+                        error!(device:% = self.id; "could not give back {} blocks of a failed write: {}", charged, refund);
+                    }
+                    return Err(e);
+                }
+            }
+            chunk_start = chunk_end;
         }
-        let block_index = byte_offset / self.block_size_bytes as u64;
-        let offset_within_block = (byte_offset % self.block_size_bytes as u64) as usize;
-        Some((block_index, offset_within_block))
+        Ok(())
     }
 
-    pub fn translate_span_to_block_indices(&self, byte_offset: u64, length: usize) -> Option<Vec<u64>> { //returns list of block indices
-        if byte_offset >= self.logical_size_bytes {
-            return None;
+    //stores one chunk as a payload and maps it
+    fn map_chunk(&mut self, kvs: &Kvs, start: u64, end: u64, chunk: &[u8], data_key: Option<&DataKey>) -> StorageResult<()> {
+        let seed = NonceSeed {
+            device_id: self.id,
+            block_index: start,
+            generation: self.generation,
+        };
+        let content = Payload::put(kvs, chunk, WriteOptions {
+            compression: self.write_overrides.compression.unwrap_or(self.compression),
+            checksum: self.write_overrides.checksum.unwrap_or(self.checksum),
+            sealing: data_key.map(|key| (key, seed)),
+        })?;
+        trace!(device:% = self.id; "mapping blocks {}..{} to payload {}", start, end, Payload::kvs_id(&content.hash, content.codec));
+        self.apply(JournalOp::Map(Extent { start, length: end - start, content }))
+    }
+
+    //makes one change to the block map, journaling it when the journal is on
+    fn apply(&mut self, op: JournalOp) -> StorageResult<()> {
+        let mapped_before = match op {
+            JournalOp::Map(extent) => self.mapped_blocks(extent.start, extent.end())?,
+            JournalOp::Punch { start, end } => self.mapped_blocks(start, end)?,
+        };
+        let mapped_after = match op {
+            JournalOp::Map(extent) => {
+                let page_index = self.translate_block_to_page_index(extent.start);
+                self.page_for_write(page_index)?.insert(extent);
+                extent.length
+            }
+            JournalOp::Punch { start, end } => {
+                self.punch_blocks(start, end)?;
+                0
+            }
+        };
+        if let Some(allocated) = &mut self.allocated_blocks {
+            *allocated = *allocated + mapped_after - mapped_before;
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.push(op);
         }
-        let mut block_indices = Vec::new();
-        let mut remaining_length = length;
-        let mut current_offset = byte_offset;
+        Ok(())
+    }
 
-        while remaining_length > 0 {
-            let (block_index, offset_within_block) = self.translate_byte_to_block_index(current_offset)?;
-            block_indices.push(block_index);
-            let space_in_block = self.block_size_bytes - offset_within_block;
-            if remaining_length <= space_in_block {
-                break;
+    //how many blocks of [first, end) are mapped
+    fn mapped_blocks(&mut self, first: u64, end: u64) -> StorageResult<u64> {
+        let mut mapped = 0;
+        let mut page_start = first;
+        while page_start < end {
+            let page_index = self.translate_block_to_page_index(page_start);
+            let page_end = min(end, (page_index + 1) * self.page_span_blocks);
+            if self.load_page(page_index)? {
+                mapped += self.pages[&page_index].overlapping(page_start, page_end).iter().map(|e| e.length).sum::<u64>();
+            }
+            page_start = page_end;
+        }
+        Ok(mapped)
+    }
+
+    //bytes of logical space mapped to payloads, what quotas and pools count
+    pub fn allocated_bytes(&mut self) -> StorageResult<u64> {
+        let blocks = match self.allocated_blocks {
+            Some(blocks) => blocks,
+            None => {
+                let blocks = self.usage()?.allocated_bytes / self.block_size_bytes as u64;
+                self.allocated_blocks = Some(blocks);
+                blocks
+            }
+        };
+        Ok(blocks * self.block_size_bytes as u64)
+    }
+
+    //settles the pool for changes made since `allocated_bytes` were mapped, when they are dropped instead of flushed
+    pub fn refund_since(&mut self, allocated_bytes: u64) -> StorageResult<()> {
+        let delta_blocks = (self.allocated_bytes()? as i64 - allocated_bytes as i64) / self.block_size_bytes as i64;
+        self.charge(-delta_blocks)
+    }
+
+    //takes newly mapped blocks from the device quota and the pool, or gives freed ones back
+    fn charge(&mut self, delta_blocks: i64) -> StorageResult<()> {
+        let delta_bytes = delta_blocks * self.block_size_bytes as i64;
+        if delta_bytes > 0
+            && let Some(quota) = self.quota_bytes
+        {
+            let allocated = self.allocated_bytes()?;
+            if allocated + delta_bytes as u64 > quota {
+                Metrics::inc(&METRICS.quota_rejections);
+                warn!(
+                    device:% = self.id;
+                    "device is at its quota: {} of {} bytes allocated, refusing {} more", allocated, quota, delta_bytes
+                );
+                return Err(StorageError::NoSpace);
             }
-            remaining_length -= space_in_block;
-            current_offset += space_in_block as u64;
         }
+        match &self.pool {
+            Some(pool) => Pool::charge(&self.kvs()?, pool, delta_bytes),
+            None => Ok(()),
+        }
+    }
 
-        Some(block_indices)
+    //unmaps the blocks in [first, end), dropping pages that become empty
+    fn punch_blocks(&mut self, first: u64, end: u64) -> StorageResult<()> {
+        let mut page_start = first;
+        while page_start < end {
+            let page_index = self.translate_block_to_page_index(page_start);
+            let page_end = min(end, (page_index + 1) * self.page_span_blocks);
+            if self.load_page(page_index)? {
+                let page = self.page_for_write(page_index)?;
+                page.punch(page_start, page_end);
+                if page.is_empty() {
+                    self.page_roots.remove(&page_index);
+                }
+            }
+            page_start = page_end;
+        }
+        Ok(())
     }
 
-    pub fn block_exists(&self, block_index: u64) -> bool {
-        self.blocks.contains_key(&block_index)
+    fn write_legacy_block(&mut self, block_index: u64, block: LegacyBlock) -> StorageResult<()> {
+        let mut data = block.data;
+        data.resize(self.block_size_bytes, 0);
+        self.write_blocks(block_index, &data)
     }
 
-    pub fn write(&mut self, byte_offset: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
-        let block_indices = self.translate_span_to_block_indices(byte_offset, data.len())
-            .ok_or("Byte offset out of bounds")?;
+    pub fn write(&mut self, byte_offset: u64, data: &[u8]) -> StorageResult<()> {
+        self.check_writable()?;
+        let end = self.check_span(byte_offset, data.len())?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let block_size = self.block_size_bytes as u64;
+        let first = byte_offset / block_size;
+        let last = (end - 1) / block_size;
+        let head = (byte_offset - first * block_size) as usize;
+
+        //partial blocks at either end are merged with what is already stored
+        let mut buffer = vec![0u8; ((last - first + 1) * block_size) as usize];
+        if head != 0 {
+            let existing = self.read_blocks(first, 1)?;
+            buffer[..block_size as usize].copy_from_slice(&existing);
+        }
+        if end % block_size != 0 && (last != first || head == 0) {
+            let existing = self.read_blocks(last, 1)?;
+            let tail_start = buffer.len() - block_size as usize;
+            buffer[tail_start..].copy_from_slice(&existing);
+        }
+        buffer[head..head + data.len()].copy_from_slice(data);
 
-        println!("Writing to block indices: {:?}", block_indices);
+        self.write_blocks(first, &buffer)?;
+        self.evict_clean_pages();
+        Ok(())
+    }
 
-        let mut remaining_data = data;
-        let mut current_offset = byte_offset;
+    pub fn read(&mut self, byte_offset: u64, length: usize) -> StorageResult<Vec<u8>> {
+        let end = self.check_span(byte_offset, length)?;
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        let block_size = self.block_size_bytes as u64;
+        let first = byte_offset / block_size;
+        let last = (end - 1) / block_size;
+        let head = (byte_offset - first * block_size) as usize;
 
-        for &block_index in &block_indices {
-            let (_, offset_within_block) = self.translate_byte_to_block_index(current_offset)
-                .ok_or("Byte offset out of bounds")?;
-            println!("For block index {}, offset within block: {}", block_index, offset_within_block);
-            let space_in_block = self.block_size_bytes - offset_within_block;
-            let bytes_to_write = min(remaining_data.len(), space_in_block);
-            let block = self.blocks.entry(block_index).or_insert_with(|| Block::new_block(block_index)); //FIXME: calculate the hash later
-            block.write_data(offset_within_block as u64, &remaining_data[..bytes_to_write])?;
-            remaining_data = &remaining_data[bytes_to_write..];
-            current_offset += bytes_to_write as u64;
-            if remaining_data.is_empty() {
-                break;
+        let blocks = self.read_blocks(first, last - first + 1)?;
+        self.evict_clean_pages();
+        Ok(blocks[head..head + length].to_vec())
+    }
+
+    //discards the span: fully covered blocks are unmapped, partially covered edges are zeroed
+    pub fn trim(&mut self, byte_offset: u64, length: usize) -> StorageResult<()> {
+        self.check_writable()?;
+        let end = self.check_span(byte_offset, length)?;
+        if length == 0 {
+            return Ok(());
+        }
+        let block_size = self.block_size_bytes as u64;
+        let full_start = byte_offset.div_ceil(block_size);
+        let full_end = end / block_size;
+
+        if full_start >= full_end {
+            //the span sits inside a single block or straddles one boundary without covering a block
+            self.write(byte_offset, &vec![0u8; length])?;
+            return Ok(());
+        }
+        let head_end = full_start * block_size;
+        if byte_offset < head_end {
+            self.write(byte_offset, &vec![0u8; (head_end - byte_offset) as usize])?;
+        }
+        let tail_start = full_end * block_size;
+        if tail_start < end {
+            self.write(tail_start, &vec![0u8; (end - tail_start) as usize])?;
+        }
+        let mapped = self.mapped_blocks(full_start, full_end)?;
+        self.apply(JournalOp::Punch { start: full_start, end: full_end })?;
+        self.charge(-(mapped as i64))?;
+        self.evict_clean_pages();
+        Ok(())
+    }
+
+    pub fn unlocked_data_key(&self) -> Option<&DataKey> {
+        self.data_key.as_ref()
+    }
+
+    //extents of one page of the block map, empty when the page does not exist
+    pub fn page_extents(&mut self, page_index: u64) -> StorageResult<Vec<Extent>> {
+        if !self.load_page(page_index)? {
+            return Ok(Vec::new());
+        }
+        let extents = self.pages[&page_index].extents.values().copied().collect();
+        self.evict_clean_pages();
+        Ok(extents)
+    }
+
+    //block ranges [start, end) whose mapping differs from `base`, an older view of the same device.
+    //Pages both sides hold at the same generation are skipped unread, without a base everything mapped counts.
+    pub fn changed_ranges(&mut self, mut base: Option<&mut BlockDevice>) -> StorageResult<Vec<(u64, u64)>> {
+        let mut page_indices: BTreeSet<u64> = self.page_roots.keys().copied().collect();
+        if let Some(base) = &base {
+            page_indices.extend(base.page_roots.keys().copied());
+        }
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for page_index in page_indices {
+            let base_root = base.as_ref().and_then(|b| b.page_roots.get(&page_index).copied());
+            if base_root.is_some() && base_root == self.page_roots.get(&page_index).copied() {
+                continue;
+            }
+            let ours = self.page_extents(page_index)?;
+            let theirs = match base.as_deref_mut() {
+                Some(base) => base.page_extents(page_index)?,
+                None => Vec::new(),
+            };
+            for (start, end) in diff_extents(&ours, &theirs) {
+                match ranges.last_mut() {
+                    Some(last) if last.1 == start => last.1 = end,
+                    _ => ranges.push((start, end)),
+                }
+            }
+        }
+        Ok(ranges)
+    }
+
+    //walks the whole block map; payloads shared by several extents are counted once
+    pub fn usage(&mut self) -> StorageResult<DeviceUsage> {
+        let block_size = self.block_size_bytes as u64;
+        let mut usage = DeviceUsage {
+            logical_size_bytes: self.logical_size_bytes,
+            ..DeviceUsage::default()
+        };
+        let mut seen = BTreeSet::new();
+        let page_indices: Vec<u64> = self.page_roots.keys().copied().collect();
+        for page_index in page_indices {
+            self.load_page(page_index)?;
+            for extent in self.pages[&page_index].extents.values() {
+                usage.allocated_bytes += extent.length * block_size;
+                if seen.insert((extent.content.hash, extent.content.codec)) {
+                    usage.payloads += 1;
+                    usage.stored_bytes += extent.content.stored_len;
+                }
+            }
+            self.evict_clean_pages();
+        }
+        Ok(usage)
+    }
+
+    //journals block map changes from now on, see persist_journal
+    pub fn enable_journal(&mut self) {
+        if self.journal.is_none() {
+            self.journal = Some(Vec::new());
+        }
+    }
+
+    //stores the block map changes made since the last call, so an acknowledged write
+    //survives a crash before the next flush
+    pub fn persist_journal(&mut self, kvs: &Kvs) -> StorageResult<()> {
+        let ops = match &mut self.journal {
+            Some(ops) if !ops.is_empty() => std::mem::take(ops),
+            _ => return Ok(()),
+        };
+        let entry = JournalEntry { device_id: self.id, seq: self.journal_seq, ops };
+        entry.store(kvs)?;
+        self.journal_seq += 1;
+        self.journaled.push(entry.get_kvs_id());
+        Ok(())
+    }
+
+    //redoes changes journaled by a process that stopped before flushing them
+    fn replay_journal(&mut self, kvs: &Kvs) -> StorageResult<usize> {
+        let mut replayed = 0;
+        for entry in JournalEntry::list(kvs, self.id)? {
+            for op in &entry.ops {
+                self.apply(*op)?;
             }
+            replayed += entry.ops.len();
+            self.journal_seq = self.journal_seq.max(entry.seq + 1);
+            self.journaled.push(entry.get_kvs_id());
+        }
+        Ok(replayed)
+    }
+
+    //writes dirty pages and the top-level record, then forgets the dirty state
+    pub fn flush(&mut self, kvs: &Kvs) -> StorageResult<()> {
+        self.check_writable()?;
+        self.check_generation(kvs)?;
+        self.store(kvs)?;
+        if let Some(journal) = &mut self.journal {
+            journal.clear();
+        }
+        for key in std::mem::take(&mut self.journaled) {
+            kvs.delete(&key)?;
         }
+        self.dirty_pages.clear();
+        self.evict_clean_pages();
         Ok(())
     }
 
-    pub fn read(&self, byte_offset: u64, length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
-        let block_indices = self.translate_span_to_block_indices(byte_offset, length)
-            .ok_or("Byte offset out of bounds")?;
+    //a snapshot taken by someone else bumped the stored generation, storing our pages now
+    //would overwrite the ones the snapshot holds
+    fn check_generation(&self, kvs: &Kvs) -> StorageResult<()> {
+        let raw = match kvs.get_raw(&self.get_kvs_id())? {
+            Some(raw) => raw,
+            None => return Ok(()),
+        };
+        let stored: BlockDevice = Codec::decode(&raw)?;
+        if stored.generation > self.generation {
+            return Err(StorageError::Conflict(format!(
+                "device {} is at generation {} in the store but {} here, reload it", self.id, stored.generation, self.generation
+            )));
+        }
+        Ok(())
+    }
 
-        let mut result = Vec::with_capacity(length);
-        let mut remaining_length = length;
-        let mut current_offset = byte_offset;
+    //freezes the current generation as a snapshot and moves the device on to the next one
+    pub fn snapshot(&mut self, kvs: &Kvs) -> StorageResult<Snapshot> {
+        self.flush(kvs)?;
+        let next = self.generation.checked_add(1)
+            .ok_or_else(|| StorageError::Conflict(format!("device {} ran out of generations", self.id)))?;
+        let snapshot = Snapshot {
+            device_id: self.id,
+            generation: self.generation,
+            created_at: unix_now(),
+            device: self.frozen_record(),
+        };
+        snapshot.store(kvs)?;
+        self.generation = next;
+        self.flush(kvs)?;
+        Ok(snapshot)
+    }
 
-        for &block_index in &block_indices {
+    //grows or shrinks the device, blocks cut off by shrinking are unmapped
+    pub fn resize(&mut self, logical_size_bytes: u64) -> StorageResult<()> {
+        self.check_writable()?;
+        let block_size = self.block_size_bytes as u64;
+        if logical_size_bytes == 0 || !logical_size_bytes.is_multiple_of(block_size) {
+            return Err(StorageError::InvalidArgument(format!(
+                "size must be a non-zero multiple of the {} byte block size", block_size
+            )));
+        }
+        let old_blocks = self.logical_size_bytes.div_ceil(block_size);
+        let new_blocks = logical_size_bytes / block_size;
+        if new_blocks < old_blocks {
+            let mapped = self.mapped_blocks(new_blocks, old_blocks)?;
+            self.apply(JournalOp::Punch { start: new_blocks, end: old_blocks })?;
+            self.charge(-(mapped as i64))?;
+            self.evict_clean_pages();
+        }
+        self.logical_size_bytes = logical_size_bytes;
+        Ok(())
+    }
 
-            let (_, offset_within_block) = self.translate_byte_to_block_index(current_offset)
-                .ok_or("Byte offset out of bounds")?;
-            
-            let space_in_block = self.block_size_bytes - offset_within_block;
-            let bytes_to_read = min(remaining_length, space_in_block);
-            let block_data = if let Some(block) = self.blocks.get(&block_index) {
-                block.read_data(self.block_size_bytes)?
-            } else {
-                vec![0u8; self.block_size_bytes]
+    //copies the block map into a new device at generation 1, payloads are shared rather than copied.
+    //The data key of an encrypted device is wrapped anew for the clone, which needs the master key.
+    pub fn clone_to(&mut self, new_id: u128, kvs: &Kvs, master: Option<&MasterKey>) -> StorageResult<BlockDevice> {
+        let mut clone = BlockDevice::new(new_id, self.logical_size_bytes);
+        if kvs.get_raw(&clone.get_kvs_id())?.is_some() {
+            return Err(StorageError::Conflict(format!("device {} already exists", new_id)));
+        }
+        clone.block_size_bytes = self.block_size_bytes;
+        clone.page_span_blocks = self.page_span_blocks;
+        clone.compression = self.compression;
+        clone.checksum = self.checksum;
+        if let Some(wrapped) = &self.encryption {
+            //wrapped keys are bound to the device id, the source's would not unwrap for the clone
+            let (Some(data_key), Some(master)) = (&self.data_key, master) else {
+                return Err(StorageError::Locked(format!("device {} is encrypted, unlock it to clone it", self.id)));
             };
-            result.extend_from_slice(&block_data[offset_within_block..offset_within_block + bytes_to_read]);
-            remaining_length -= bytes_to_read;
-            current_offset += bytes_to_read as u64;
-            if remaining_length == 0 {
-                break;
+            if wrapped.master_key_id != master.id() {
+                return Err(StorageError::InvalidArgument(format!(
+                    "data key of device {} is wrapped by master key {}, not {}", self.id, wrapped.master_key_id, master.id()
+                )));
+            }
+            clone.encryption = Some(master.wrap(data_key, new_id)?);
+            clone.data_key = Some(data_key.clone());
+        }
+        clone.pool = self.pool.clone();
+        clone.quota_bytes = self.quota_bytes;
+        //the clone maps as much as the source, the pool has to have room for it up front
+        let allocated = self.allocated_bytes()?;
+        clone.allocated_blocks = Some(allocated / self.block_size_bytes as u64);
+        if let Some(pool) = &clone.pool {
+            Pool::charge(kvs, pool, allocated as i64)?;
+        }
+        let page_indices: Vec<u64> = self.page_roots.keys().copied().collect();
+        for page_index in page_indices {
+            self.load_page(page_index)?;
+            //pages go straight to the store so a large device is never held in memory whole
+            let mut page = self.pages[&page_index].clone();
+            page.device_id = new_id;
+            page.generation = clone.generation;
+            page.store(kvs)?;
+            clone.page_roots.insert(page_index, clone.generation);
+            self.evict_clean_pages();
+        }
+        clone.attach(kvs.clone());
+        clone.flush(kvs)?;
+        Ok(clone)
+    }
+
+    //drops the record, its journal and its snapshots, pages and payloads are left to the garbage collector
+    pub fn delete(kvs: &Kvs, id: u128) -> StorageResult<()> {
+        let key = format!("{}{}", KVS_PREFIX, id);
+        let mut device = BlockDevice::load(&key, kvs)?;
+        if let Some(pool) = device.pool.clone() {
+            let allocated = device.allocated_bytes()?;
+            Pool::charge(kvs, &pool, -(allocated as i64))?;
+        }
+        for entry in JournalEntry::list(kvs, id)? {
+            kvs.delete(&entry.get_kvs_id())?;
+        }
+        //they would keep their pages marked, and show up on a later device with the same id
+        for snapshot in Snapshot::list(kvs, id)? {
+            kvs.delete(&snapshot.get_kvs_id())?;
+        }
+        kvs.delete(&key)
+    }
+
+    //copy of the top-level record without caches or key material
+    fn frozen_record(&self) -> BlockDevice {
+        BlockDevice {
+            id: self.id,
+            logical_size_bytes: self.logical_size_bytes,
+            block_size_bytes: self.block_size_bytes,
+            generation: self.generation,
+            page_span_blocks: self.page_span_blocks,
+            page_roots: self.page_roots.clone(),
+            compression: self.compression,
+            checksum: self.checksum,
+            encryption: self.encryption.clone(),
+            restored_from: self.restored_from,
+            pool: self.pool.clone(),
+            quota_bytes: self.quota_bytes,
+            file_of: self.file_of.clone(),
+            allocated_blocks: self.allocated_blocks,
+            legacy_blocks: BTreeMap::new(),
+            pages: BTreeMap::new(),
+            dirty_pages: BTreeSet::new(),
+            write_overrides: WriteOverrides::default(),
+            payload_cache: PayloadCache::default(),
+            data_key: None,
+            kvs: None,
+            read_only: true,
+            journal: None,
+            journal_seq: 0,
+            journaled: Vec::new(),
+        }
+    }
+
+    //moves the inline block map of a pre-page record into extents
+    fn split_legacy_blocks(&mut self) -> StorageResult<()> {
+        let legacy = std::mem::take(&mut self.legacy_blocks);
+        for (block_index, block) in legacy {
+            self.write_legacy_block(block_index, block)?;
+        }
+        Ok(())
+    }
+
+    //rewrites every stored device in the current record format, converting legacy block maps into extents
+    pub fn migrate_all(kvs: &Kvs) -> StorageResult<usize> {
+        let mut migrated = 0;
+        for key in kvs.scan_keys(&format!("{}*", KVS_PREFIX))? {
+            let raw = match kvs.get_raw(&key)? {
+                Some(raw) => raw,
+                None => continue, //deleted while we were scanning
+            };
+            let mut device = BlockDevice::load(&key, kvs)?;
+            //pull every page through load_page so per-block pages get converted too
+            let page_indices: Vec<u64> = device.page_roots.keys().copied().collect();
+            for page_index in page_indices {
+                device.load_page(page_index)?;
+                device.evict_clean_pages();
+            }
+            if Codec::is_current(&raw) && device.dirty_pages.is_empty() {
+                continue;
+            }
+            //the serve process holding it would store its old record over ours
+            if let Some(attachment) = Attachment::find(kvs, device.id)? {
+                warn!(device:% = device.id; "attached to {} on {}, left unmigrated", attachment.nbd, attachment.host);
+                continue;
             }
+            device.flush(kvs)?;
+            migrated += 1;
         }
-        return Ok(result)
+        Ok(migrated)
     }
 
 }
 
 
+//ranges where two sorted, non-overlapping extent lists map blocks differently, holes included
+fn diff_extents(ours: &[Extent], theirs: &[Extent]) -> Vec<(u64, u64)> {
+    let mut bounds: Vec<u64> = ours.iter().chain(theirs).flat_map(|e| [e.start, e.end()]).collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+    let mapping = |extents: &[Extent], block: u64| {
+        let found = extents.partition_point(|e| e.end() <= block);
+        extents.get(found)
+            .filter(|e| e.contains(block))
+            .map(|e| {
+                let content = e.content_at(block);
+                (content.hash, content.codec, content.offset)
+            })
+    };
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for pair in bounds.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        if mapping(ours, start) == mapping(theirs, start) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
 impl KvsStorable for BlockDevice {
-    fn store(&self, kvs: &Kvs) -> Result<(), Box<dyn Error>> {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        for &page_index in &self.dirty_pages {
+            match self.pages.get(&page_index) {
+                Some(page) if !page.is_empty() => page.store(kvs)?,
+                _ => kvs.delete(&BlockPage::kvs_id(self.id, page_index, self.generation))?,
+            }
+        }
         kvs.store(self)
     }
 
-    fn load(id: &str, kvs: &Kvs) -> Result<Self, Box<dyn Error>> { //FIXME: id should be u128
-        kvs.load(id)
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> { //FIXME: id should be u128
+        let mut device: BlockDevice = kvs.load(id)?;
+        device.attach(kvs.clone());
+        device.split_legacy_blocks()?;
+        let replayed = device.replay_journal(kvs)?;
+        if replayed > 0 {
+            info!(device:% = device.id; "replayed {} journaled block map change(s)", replayed);
+        }
+        Ok(device)
     }
 
     fn get_kvs_id(&self) -> String {
-        format!("BlockDevice:{}", self.id)
+        format!("{}{}", KVS_PREFIX, self.id)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const DEVICE_SIZE: u64 = 4 * 512 + 100; //deliberately not a multiple of the block size
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Write(u64, Vec<u8>),
+        Read(u64, usize),
+        Trim(u64, usize),
+    }
+
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        let offset = prop_oneof![0..DEVICE_SIZE + 600, Just(DEVICE_SIZE), Just(u64::MAX - 4)];
+        prop_oneof![
+            (offset.clone(), prop::collection::vec(any::<u8>(), 0..1500)).prop_map(|(o, d)| Op::Write(o, d)),
+            (offset.clone(), 0usize..1500).prop_map(|(o, l)| Op::Read(o, l)),
+            (offset, 0usize..1500).prop_map(|(o, l)| Op::Trim(o, l)),
+        ]
+    }
+
+    //span validity as seen by the flat reference buffer
+    fn reference_span(offset: u64, length: usize) -> Option<std::ops::Range<usize>> {
+        let end = offset.checked_add(length as u64)?;
+        if end > DEVICE_SIZE {
+            return None;
+        }
+        Some(offset as usize..end as usize)
+    }
+
+    fn assert_span_error(err: StorageError, offset: u64, length: usize) {
+        match err {
+            StorageError::Overflow { .. } => assert!(offset.checked_add(length as u64).is_none()),
+            StorageError::OutOfRange { size, .. } => assert_eq!(size, DEVICE_SIZE),
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn matches_flat_reference_buffer(
+            ops in prop::collection::vec(op_strategy(), 1..40),
+            compression in prop_oneof![Just(Compression::None), Just(Compression::Lz4), Just(Compression::Zstd)],
+        ) {
+            let kvs = Kvs::in_memory();
+            let mut device = BlockDevice::new(1, DEVICE_SIZE);
+            device.compression = compression;
+            device.attach(kvs.clone());
+            device.page_span_blocks = 2; //spread the small device over several pages
+            let mut reference = vec![0u8; DEVICE_SIZE as usize];
+
+            for op in ops {
+                match op {
+                    Op::Write(offset, data) => match (reference_span(offset, data.len()), device.write(offset, &data)) {
+                        (Some(range), Ok(())) => reference[range].copy_from_slice(&data),
+                        (None, Err(e)) => assert_span_error(e, offset, data.len()),
+                        (expected, got) => panic!("write {offset}+{}: expected {expected:?}, got {got:?}", data.len()),
+                    },
+                    Op::Read(offset, length) => match (reference_span(offset, length), device.read(offset, length)) {
+                        (Some(range), Ok(data)) => prop_assert_eq!(&data[..], &reference[range]),
+                        (None, Err(e)) => assert_span_error(e, offset, length),
+                        (expected, got) => panic!("read {offset}+{length}: expected {expected:?}, got {got:?}"),
+                    },
+                    Op::Trim(offset, length) => match (reference_span(offset, length), device.trim(offset, length)) {
+                        (Some(range), Ok(())) => reference[range].fill(0),
+                        (None, Err(e)) => assert_span_error(e, offset, length),
+                        (expected, got) => panic!("trim {offset}+{length}: expected {expected:?}, got {got:?}"),
+                    },
+                }
+            }
+
+            prop_assert_eq!(&device.read(0, DEVICE_SIZE as usize).unwrap(), &reference);
+
+            device.flush(&kvs).unwrap();
+            let mut reloaded = BlockDevice::load(&device.get_kvs_id(), &kvs).unwrap();
+            prop_assert_eq!(reloaded.read(0, DEVICE_SIZE as usize).unwrap(), reference);
+        }
+    }
+
+    #[test]
+    fn span_crossing_device_end_is_rejected() {
+        let mut device = BlockDevice::new(1, DEVICE_SIZE);
+        device.attach(Kvs::in_memory());
+        assert!(matches!(device.read(DEVICE_SIZE - 10, 20), Err(StorageError::OutOfRange { .. })));
+        assert!(matches!(device.write(DEVICE_SIZE - 10, &[1u8; 20]), Err(StorageError::OutOfRange { .. })));
+        assert!(matches!(device.trim(DEVICE_SIZE - 10, 20), Err(StorageError::OutOfRange { .. })));
+        assert!(device.page_roots.is_empty());
+    }
+
+    #[test]
+    fn zero_length_spans_are_validated() {
+        let mut device = BlockDevice::new(1, DEVICE_SIZE);
+        device.attach(Kvs::in_memory());
+        assert_eq!(device.read(DEVICE_SIZE, 0).unwrap(), Vec::<u8>::new());
+        assert!(matches!(device.read(DEVICE_SIZE + 1, 0), Err(StorageError::OutOfRange { .. })));
+        assert!(matches!(device.read(u64::MAX, 2), Err(StorageError::Overflow { .. })));
+    }
+
+    #[test]
+    fn stores_only_dirty_pages() {
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(3, 64 * 512);
+        device.attach(kvs.clone());
+        device.page_span_blocks = 4;
+        device.write(0, &[1u8; 512]).unwrap();
+        device.write(40 * 512, &[2u8; 512]).unwrap();
+        device.flush(&kvs).unwrap();
+        assert_eq!(kvs.scan_keys("BlockPage:3:*").unwrap(), vec!["BlockPage:3:0:1", "BlockPage:3:10:1"]);
+
+        let mut reloaded = BlockDevice::load(&device.get_kvs_id(), &kvs).unwrap();
+        assert!(reloaded.pages.is_empty());
+        assert_eq!(reloaded.read(40 * 512, 512).unwrap(), vec![2u8; 512]);
+        assert_eq!(reloaded.pages.len(), 1);
+
+        reloaded.trim(40 * 512, 512).unwrap();
+        reloaded.flush(&kvs).unwrap();
+        assert_eq!(kvs.scan_keys("BlockPage:3:*").unwrap(), vec!["BlockPage:3:0:1"]);
+    }
+
+    #[test]
+    fn overwrites_split_extents() {
+        let mut device = BlockDevice::new(4, 4096 * 512);
+        device.attach(Kvs::in_memory());
+        device.write(0, &vec![7u8; 1024 * 512]).unwrap();
+        assert_eq!(device.pages[&0].extents.len(), 1);
+
+        device.write(100 * 512 + 10, &[9u8; 600]).unwrap();
+        device.trim(500 * 512, 512).unwrap();
+        let starts: Vec<u64> = device.pages[&0].extents.keys().copied().collect();
+        assert_eq!(starts, vec![0, 100, 102, 501]);
+        assert_eq!(device.lookup_block(500).unwrap(), None);
+        assert_eq!(device.lookup_block(501).unwrap().unwrap().offset, 501);
+        assert_eq!(device.read(100 * 512 + 8, 4).unwrap(), vec![7, 7, 9, 9]);
+    }
+
+    #[test]
+    fn compresses_when_it_saves_space() {
+        let mut device = BlockDevice::new(5, 64 * 512);
+        device.compression = Compression::Zstd;
+        device.attach(Kvs::in_memory());
+        device.write(0, &vec![3u8; 16 * 512]).unwrap();
+        let random: Vec<u8> = (0..512).map(|_| rand::random()).collect();
+        device.write(32 * 512, &random).unwrap();
+
+        assert_eq!(device.lookup_block(0).unwrap().unwrap().codec, Compression::Zstd);
+        assert_eq!(device.lookup_block(32).unwrap().unwrap().codec, Compression::None);
+        let usage = device.usage().unwrap();
+        assert_eq!(usage.allocated_bytes, 17 * 512);
+        assert!(usage.stored_bytes < usage.allocated_bytes);
+        assert_eq!(device.read(32 * 512, 512).unwrap(), random);
+    }
+
+    #[test]
+    fn encrypted_payloads_need_the_data_key() {
+        let kvs = Kvs::in_memory();
+        let master = MasterKey::generate();
+        let mut device = BlockDevice::new(6, 64 * 512);
+        device.attach(kvs.clone());
+        device.enable_encryption(&master).unwrap();
+        device.write(512, b"secret block").unwrap();
+        device.flush(&kvs).unwrap();
+
+        let content = device.lookup_block(1).unwrap().unwrap();
+        assert!(content.encrypted);
+        let stored = kvs.get_raw(&Payload::kvs_id(&content.hash, content.codec)).unwrap().unwrap();
+        assert!(!stored.windows(12).any(|w| w == b"secret block"));
+
+        let mut reloaded = BlockDevice::load(&device.get_kvs_id(), &kvs).unwrap();
+        assert!(matches!(reloaded.read(512, 12), Err(StorageError::Locked(_))));
+
+        let rotated = MasterKey::generate();
+        reloaded.rewrap_key(&master, &rotated).unwrap();
+        reloaded.flush(&kvs).unwrap();
+        let mut reloaded = BlockDevice::load(&device.get_kvs_id(), &kvs).unwrap();
+        assert!(reloaded.unlock(&master).is_err());
+        reloaded.unlock(&rotated).unwrap();
+        assert_eq!(reloaded.read(512, 12).unwrap(), b"secret block");
+    }
+
+    #[test]
+    fn corrupted_payloads_fail_reads() {
+        for checksum in [ChecksumKind::Sha256, ChecksumKind::Xxh3] {
+            let kvs = Kvs::in_memory();
+            let mut device = BlockDevice::new(7, 64 * 512);
+            device.checksum = checksum;
+            device.attach(kvs.clone());
+            device.write(0, &[5u8; 1024]).unwrap();
+            device.flush(&kvs).unwrap();
+
+            let content = device.lookup_block(0).unwrap().unwrap();
+            let key = Payload::kvs_id(&content.hash, content.codec);
+            let mut stored = kvs.get_raw(&key).unwrap().unwrap();
+            stored[700] ^= 0xff;
+            kvs.set_raw(&key, &stored).unwrap();
+
+            let mut reloaded = BlockDevice::load(&device.get_kvs_id(), &kvs).unwrap();
+            let err = reloaded.read(0, 512).unwrap_err();
+            assert!(matches!(err, StorageError::Corruption(_)));
+            assert_eq!(err.errno(), libc::EIO);
+        }
+    }
+
+    #[test]
+    fn replays_journaled_changes_after_a_crash() {
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(7, 64 * 512);
+        device.page_span_blocks = 8;
+        device.attach(kvs.clone());
+        device.enable_journal();
+        device.write(0, &[1u8; 4096]).unwrap();
+        device.flush(&kvs).unwrap();
+        device.write(1024, &[2u8; 8192]).unwrap();
+        device.persist_journal(&kvs).unwrap();
+        device.trim(0, 512).unwrap();
+        device.persist_journal(&kvs).unwrap();
+        let expected = device.read(0, 64 * 512).unwrap();
+        drop(device); //dies without flushing
+
+        let mut reloaded = BlockDevice::load("BlockDevice:7", &kvs).unwrap();
+        assert_eq!(reloaded.read(0, 64 * 512).unwrap(), expected);
+        reloaded.flush(&kvs).unwrap();
+        assert!(kvs.scan_keys("Journal:*").unwrap().is_empty());
+        let mut reloaded = BlockDevice::load("BlockDevice:7", &kvs).unwrap();
+        assert_eq!(reloaded.read(0, 64 * 512).unwrap(), expected);
+    }
+
+    #[test]
+    fn clones_share_payloads_but_not_pages() {
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(8, 32 * 512);
+        device.page_span_blocks = 8;
+        device.attach(kvs.clone());
+        device.write(0, &[5u8; 16 * 512]).unwrap();
+        device.flush(&kvs).unwrap();
+        let payloads = kvs.scan_keys("Payload:*").unwrap().len();
+
+        let mut clone = device.clone_to(9, &kvs, None).unwrap();
+        assert_eq!(kvs.scan_keys("Payload:*").unwrap().len(), payloads);
+        clone.write(0, &[6u8; 512]).unwrap();
+        clone.flush(&kvs).unwrap();
+        assert_eq!(device.read(0, 512).unwrap(), vec![5u8; 512]);
+        let mut clone = BlockDevice::load("BlockDevice:9", &kvs).unwrap();
+        assert_eq!(clone.read(0, 512).unwrap(), vec![6u8; 512]);
+        assert_eq!(clone.read(512, 15 * 512).unwrap(), vec![5u8; 15 * 512]);
+        assert!(matches!(device.clone_to(9, &kvs, None), Err(StorageError::Conflict(_))));
+    }
+
+    #[test]
+    fn deleting_a_device_drops_its_snapshots() {
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(12, 16 * 512);
+        device.attach(kvs.clone());
+        device.write(0, &[4u8; 512]).unwrap();
+        device.snapshot(&kvs).unwrap();
+        device.write(0, &[5u8; 512]).unwrap();
+        device.snapshot(&kvs).unwrap();
+        assert_eq!(Snapshot::list(&kvs, 12).unwrap().len(), 2);
+
+        BlockDevice::delete(&kvs, 12).unwrap();
+        assert!(Snapshot::list(&kvs, 12).unwrap().is_empty());
+        assert!(kvs.get_raw("BlockDevice:12").unwrap().is_none());
+    }
+
+    #[test]
+    fn encrypted_clones_unlock_with_the_master_key() {
+        let kvs = Kvs::in_memory();
+        let master = MasterKey::generate();
+        let mut device = BlockDevice::new(8, 16 * 512);
+        device.attach(kvs.clone());
+        device.enable_encryption(&master).unwrap();
+        device.write(0, &[5u8; 4 * 512]).unwrap();
+        device.flush(&kvs).unwrap();
+        assert!(matches!(device.clone_to(9, &kvs, None), Err(StorageError::Locked(_))));
+
+        device.clone_to(9, &kvs, Some(&master)).unwrap();
+        let mut clone = BlockDevice::load("BlockDevice:9", &kvs).unwrap();
+        clone.unlock(&master).unwrap();
+        assert_eq!(clone.read(0, 4 * 512).unwrap(), vec![5u8; 4 * 512]);
+        let mut locked = BlockDevice::load("BlockDevice:8", &kvs).unwrap();
+        assert!(matches!(locked.clone_to(10, &kvs, Some(&master)), Err(StorageError::Locked(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn shrinking_unmaps_the_cut_off_blocks() {
+        let kvs = Kvs::in_memory();
+        let mut device = BlockDevice::new(10, 16 * 512);
+        device.attach(kvs.clone());
+        device.write(0, &[3u8; 16 * 512]).unwrap();
+        device.resize(4 * 512).unwrap();
+        assert!(device.read(4 * 512, 512).is_err());
+        device.resize(16 * 512).unwrap();
+        assert_eq!(device.read(3 * 512, 2 * 512).unwrap(), [vec![3u8; 512], vec![0u8; 512]].concat());
+        assert!(matches!(device.resize(1000), Err(StorageError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn migrates_legacy_json_records() {
+        let kvs = Kvs::in_memory();
+        //as the first releases stored devices: json, with the block map and its data inline
+        let data: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        let record = serde_json::json!({
+            "id": 11,
+            "logical_size_bytes": 2048,
+            "block_size_bytes": 512,
+            "generation": 1,
+            "blocks": { "1": { "index": 1, "hash": vec![0u8; 32], "data": data } },
+        });
+        kvs.set_raw("BlockDevice:11", &serde_json::to_vec(&record).unwrap()).unwrap();
+
+        //a serve process holding the device would store its record over the migrated one
+        let attachment = Attachment { device_id: 11, host: "a".into(), nbd: "/dev/nbd0".into(), pid: 1, attached_at: 0 };
+        attachment.store(&kvs).unwrap();
+        assert_eq!(BlockDevice::migrate_all(&kvs).unwrap(), 0);
+        assert!(!Codec::is_current(&kvs.get_raw("BlockDevice:11").unwrap().unwrap()));
+        kvs.delete(&attachment.get_kvs_id()).unwrap();
+
+        assert_eq!(BlockDevice::migrate_all(&kvs).unwrap(), 1);
+        assert!(Codec::is_current(&kvs.get_raw("BlockDevice:11").unwrap().unwrap()));
+        let mut device = BlockDevice::load("BlockDevice:11", &kvs).unwrap();
+        assert!(device.legacy_blocks.is_empty());
+        assert_eq!(device.read(0, 2048).unwrap(), [vec![0u8; 512], data, vec![0u8; 1024]].concat());
+        assert_eq!(BlockDevice::migrate_all(&kvs).unwrap(), 0);
+    }
+}
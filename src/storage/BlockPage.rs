@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::storage::BlockDevice::LegacyBlock;
+use crate::storage::Extent::Extent;
+use crate::utils::Error::StorageResult;
+
+pub const KVS_PREFIX: &str = "BlockPage:";
+
+//A fixed range of a device's block map, stored under its own key so a store only rewrites what changed.
+//Pages are versioned by the device generation they were written in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockPage {
+    pub device_id: u128,
+    pub index: u64,
+    pub generation: u32,
+    #[serde(default)]
+    pub extents: BTreeMap<u64, Extent>, //keyed by first block, never overlapping
+    #[serde(rename = "blocks", default, skip_serializing)]
+    pub legacy_blocks: BTreeMap<u64, LegacyBlock>, //per-block entries of pages written before extents
+}
+
+impl BlockPage {
+    pub fn new(device_id: u128, index: u64, generation: u32) -> Self {
+        BlockPage {
+            device_id,
+            index,
+            generation,
+            extents: BTreeMap::new(),
+            legacy_blocks: BTreeMap::new(),
+        }
+    }
+
+    pub fn kvs_id(device_id: u128, index: u64, generation: u32) -> String {
+        format!("{}{}:{}:{}", KVS_PREFIX, device_id, index, generation)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extents.is_empty() && self.legacy_blocks.is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn find(&self, block_index: u64) -> Option<&Extent> {
+        self.extents.range(..=block_index)
+            .next_back()
+            .map(|(_, extent)| extent)
+            .filter(|extent| extent.contains(block_index))
+    }
+
+    //extents clipped to [start, end), in block order
+    pub fn overlapping(&self, start: u64, end: u64) -> Vec<Extent> {
+        let mut result: Vec<Extent> = self.extents.range(..end)
+            .rev()
+            .take_while(|(_, extent)| extent.end() > start)
+            .filter_map(|(_, extent)| extent.clip(start, end))
+            .collect();
+        result.reverse();
+        result
+    }
+
+    //unmaps [start, end), splitting extents that stick out on either side
+    pub fn punch(&mut self, start: u64, end: u64) {
+        let hit: Vec<Extent> = self.extents.range(..end)
+            .rev()
+            .take_while(|(_, extent)| extent.end() > start)
+            .map(|(_, extent)| *extent)
+            .collect();
+        for extent in hit {
+            self.extents.remove(&extent.start);
+            if let Some(left) = extent.clip(extent.start, start) {
+                self.extents.insert(left.start, left);
+            }
+            if let Some(right) = extent.clip(end, extent.end()) {
+                self.extents.insert(right.start, right);
+            }
+        }
+    }
+
+    //maps the extent over whatever was there and merges it with contiguous neighbours
+    pub fn insert(&mut self, extent: Extent) {
+        self.punch(extent.start, extent.end());
+        let mut merged = extent;
+        if let Some(prev) = self.extents.range(..merged.start).next_back().map(|(_, e)| *e)
+            && prev.can_merge(&merged)
+        {
+            self.extents.remove(&prev.start);
+            merged = Extent { start: prev.start, length: prev.length + merged.length, content: prev.content };
+        }
+        if let Some(next) = self.extents.get(&merged.end()).copied()
+            && merged.can_merge(&next)
+        {
+            self.extents.remove(&next.start);
+            merged.length += next.length;
+        }
+        self.extents.insert(merged.start, merged);
+    }
+}
+
+impl KvsStorable for BlockPage {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        Self::kvs_id(self.device_id, self.index, self.generation)
+    }
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use crate::utils::Error::{StorageError, StorageResult};
+
+const ZSTD_LEVEL: i32 = 3;
+
+//Codec a payload is stored with, chosen per device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn compress(&self, data: &[u8]) -> StorageResult<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)
+                .map_err(|e| StorageError::InvalidArgument(format!("zstd compression failed: {}", e))),
+        }
+    }
+
+    pub fn decompress(&self, stored: &[u8]) -> StorageResult<Vec<u8>> {
+        match self {
+            Compression::None => Ok(stored.to_vec()),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(stored)
+                .map_err(|e| StorageError::Corruption(format!("lz4 payload does not decode: {}", e))),
+            Compression::Zstd => zstd::decode_all(stored)
+                .map_err(|e| StorageError::Corruption(format!("zstd payload does not decode: {}", e))),
+        }
+    }
+
+    //suffix of the payload key, so the same content stored with different codecs never collides
+    pub fn key_suffix(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Lz4 => ".lz4",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(StorageError::InvalidArgument(format!("unknown compression codec {:?}", other))),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use crate::storage::BlockDevice::BlockDevice;
+use crate::storage::Payload::MAX_PAYLOAD_BLOCKS;
+use crate::utils::clock::unix_now;
+use crate::utils::Error::{StorageError, StorageResult};
+
+// Delta file layout:
+// MAGIC | FORMAT_VERSION (u16) | header length (u32) | CBOR DeltaHeader
+// records: DATA (u8) | first block (u64) | block count (u32) | count * block size bytes
+//          ZERO (u8) | first block (u64) | block count (u32)
+// END (u8) | SHA-256 over everything before it
+// All integers are little endian. Data is written in the clear, even for encrypted devices.
+pub const MAGIC: [u8; 8] = *b"KVSDELTA";
+pub const FORMAT_VERSION: u16 = 1;
+const RECORD_END: u8 = 0;
+const RECORD_DATA: u8 = 1;
+const RECORD_ZERO: u8 = 2;
+const MAX_HEADER_LEN: u32 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaHeader {
+    pub device_id: u128,
+    pub from_generation: u32, //0 for a full export
+    pub to_generation: u32,
+    pub logical_size_bytes: u64,
+    pub block_size_bytes: usize,
+    pub created_at: u64,
+}
+
+//Source device and generation a device was last restored to, so deltas can only be applied in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestorePoint {
+    pub device_id: u128,
+    pub generation: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeltaStats {
+    pub data_blocks: u64,
+    pub zero_blocks: u64,
+}
+
+//Reader or writer that hashes everything passing through it, for the trailer
+struct Hashing<T> {
+    inner: T,
+    hasher: Sha256,
+}
+
+impl<W: Write> Hashing<W> {
+    fn put(&mut self, bytes: &[u8]) -> StorageResult<()> {
+        self.hasher.update(bytes);
+        self.inner.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Hashing<R> {
+    fn take(&mut self, len: usize) -> StorageResult<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.inner.read_exact(&mut buf).map_err(truncated)?;
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> StorageResult<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.inner.read_exact(&mut buf).map_err(truncated)?;
+        self.hasher.update(buf);
+        Ok(buf)
+    }
+}
+
+fn truncated(e: std::io::Error) -> StorageError {
+    match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => StorageError::Corruption("delta file is truncated".into()),
+        _ => StorageError::Io(e),
+    }
+}
+
+//streams the blocks of `to` that differ from `from`, both views of the same device
+pub fn export<W: Write>(to: &mut BlockDevice, from: Option<&mut BlockDevice>, out: W) -> StorageResult<DeltaStats> {
+    let from_generation = match &from {
+        Some(from) if from.id != to.id => {
+            return Err(StorageError::InvalidArgument(format!(
+                "cannot diff device {} against device {}", to.id, from.id
+            )));
+        }
+        Some(from) if from.generation >= to.generation => {
+            return Err(StorageError::InvalidArgument(format!(
+                "generation {} is not older than {}", from.generation, to.generation
+            )));
+        }
+        Some(from) => from.generation,
+        None => 0,
+    };
+    let ranges = to.changed_ranges(from)?;
+    let header = DeltaHeader {
+        device_id: to.id,
+        from_generation,
+        to_generation: to.generation,
+        logical_size_bytes: to.logical_size_bytes,
+        block_size_bytes: to.block_size_bytes,
+        created_at: unix_now(),
+    };
+    let mut header_bytes = Vec::new();
+    ciborium::into_writer(&header, &mut header_bytes)
+        .map_err(|e| StorageError::Corruption(format!("encode failed: {}", e)))?;
+
+    let mut out = Hashing { inner: out, hasher: Sha256::new() };
+    out.put(&MAGIC)?;
+    out.put(&FORMAT_VERSION.to_le_bytes())?;
+    out.put(&(header_bytes.len() as u32).to_le_bytes())?;
+    out.put(&header_bytes)?;
+
+    let block_size = to.block_size_bytes as u64;
+    let mut stats = DeltaStats::default();
+    for (start, end) in ranges {
+        let mut first = start;
+        while first < end {
+            let count = (end - first).min(MAX_PAYLOAD_BLOCKS);
+            //records hold whole blocks, the part of the last one past the end of the device is zeros
+            let offset = first * block_size;
+            let readable = (count * block_size).min(to.logical_size_bytes.saturating_sub(offset));
+            let mut data = to.read(offset, readable as usize)?;
+            data.resize((count * block_size) as usize, 0);
+            //blocks that changed into holes or zeros only need their range
+            let zero = data.iter().all(|&b| b == 0);
+            out.put(&[if zero { RECORD_ZERO } else { RECORD_DATA }])?;
+            out.put(&first.to_le_bytes())?;
+            out.put(&(count as u32).to_le_bytes())?;
+            if zero {
+                stats.zero_blocks += count;
+            } else {
+                out.put(&data)?;
+                stats.data_blocks += count;
+            }
+            first += count;
+        }
+    }
+    out.put(&[RECORD_END])?;
+    let digest = out.hasher.finalize();
+    out.inner.write_all(&digest)?;
+    out.inner.flush()?;
+    Ok(stats)
+}
+
+//reads just the header, to find or create the device a delta applies to
+pub fn read_header<R: Read>(input: R) -> StorageResult<DeltaHeader> {
+    let mut input = Hashing { inner: input, hasher: Sha256::new() };
+    header_of(&mut input)
+}
+
+fn header_of<R: Read>(input: &mut Hashing<R>) -> StorageResult<DeltaHeader> {
+    if input.take_array::<8>()? != MAGIC {
+        return Err(StorageError::Corruption("not a delta file".into()));
+    }
+    let version = u16::from_le_bytes(input.take_array()?);
+    if version > FORMAT_VERSION {
+        return Err(StorageError::Corruption(format!(
+            "delta format version {} is newer than supported version {}", version, FORMAT_VERSION
+        )));
+    }
+    let header_len = u32::from_le_bytes(input.take_array()?);
+    if header_len > MAX_HEADER_LEN {
+        return Err(StorageError::Corruption(format!("delta header of {} bytes is too large", header_len)));
+    }
+    let header_bytes = input.take(header_len as usize)?;
+    ciborium::from_reader(&header_bytes[..])
+        .map_err(|e| StorageError::Corruption(format!("decode failed: {}", e)))
+}
+
+//Applies a delta to `device`, a full one replaces everything on it. Nothing is flushed, so when the
+//trailer does not match the caller just drops the device: the pool gets back what the dropped blocks
+//were charged, and the payloads already written are garbage.
+pub fn apply<R: Read>(device: &mut BlockDevice, input: R, force: bool) -> StorageResult<(DeltaHeader, DeltaStats)> {
+    let mut input = Hashing { inner: input, hasher: Sha256::new() };
+    let header = header_of(&mut input)?;
+    if header.logical_size_bytes != device.logical_size_bytes || header.block_size_bytes != device.block_size_bytes {
+        return Err(StorageError::InvalidArgument(format!(
+            "delta is for a {} byte device with {} byte blocks, device {} is {} bytes with {} byte blocks",
+            header.logical_size_bytes, header.block_size_bytes, device.id, device.logical_size_bytes, device.block_size_bytes
+        )));
+    }
+    let expected = RestorePoint { device_id: header.device_id, generation: header.from_generation };
+    if !force && header.from_generation != 0 && device.restored_from != Some(expected) {
+        return Err(StorageError::Conflict(format!(
+            "delta starts at generation {} of device {}, device {} was restored to {:?}",
+            header.from_generation, header.device_id, device.id, device.restored_from
+        )));
+    }
+
+    let allocated = device.allocated_bytes()?;
+    let stats = match apply_records(device, &mut input, header.from_generation == 0) {
+        Ok(stats) => stats,
+        Err(e) => {
+            if let Err(refund) = device.refund_since(allocated) {
+                error!(device:% = device.id; "could not give back the pool charge of a rejected delta: {}", refund);
+            }
+            return Err(e);
+        }
+    };
+    device.restored_from = Some(RestorePoint { device_id: header.device_id, generation: header.to_generation });
+    Ok((header, stats))
+}
+
+fn apply_records<R: Read>(device: &mut BlockDevice, input: &mut Hashing<R>, full: bool) -> StorageResult<DeltaStats> {
+    if full {
+        //a full delta only carries what is mapped, blocks it leaves out are holes
+        device.trim(0, device.logical_size_bytes as usize)?;
+    }
+    let block_size = device.block_size_bytes as u64;
+    let mut stats = DeltaStats::default();
+    loop {
+        let [kind] = input.take_array::<1>()?;
+        if kind == RECORD_END {
+            break;
+        }
+        let first = u64::from_le_bytes(input.take_array()?);
+        let count = u32::from_le_bytes(input.take_array()?) as u64;
+        if count > MAX_PAYLOAD_BLOCKS {
+            return Err(StorageError::Corruption(format!("delta record of {} blocks is too large", count)));
+        }
+        let offset = first.checked_mul(block_size)
+            .ok_or(StorageError::Overflow { offset: first, length: count })?;
+        let length = (count * block_size) as usize;
+        let inside = (length as u64).min(device.logical_size_bytes.saturating_sub(offset)) as usize;
+        match kind {
+            RECORD_DATA => {
+                let data = input.take(length)?;
+                device.write(offset, &data[..inside])?;
+                stats.data_blocks += count;
+            }
+            RECORD_ZERO => {
+                device.trim(offset, inside)?;
+                stats.zero_blocks += count;
+            }
+            other => return Err(StorageError::Corruption(format!("unknown delta record type {}", other))),
+        }
+    }
+    let digest = input.hasher.clone().finalize();
+    let mut trailer = [0u8; 32];
+    input.inner.read_exact(&mut trailer).map_err(truncated)?;
+    if digest[..] != trailer[..] {
+        return Err(StorageError::Corruption("delta checksum does not match".into()));
+    }
+    if input.inner.read(&mut [0u8; 1])? != 0 {
+        return Err(StorageError::Corruption("trailing bytes after delta checksum".into()));
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manager::Kvs::{Kvs, KvsStorable};
+    use crate::manager::Pool::Pool;
+    use crate::storage::Snapshot::Snapshot;
+
+    fn device(kvs: &Kvs, id: u128) -> BlockDevice {
+        let mut device = BlockDevice::new(id, 256 * 1024);
+        device.page_span_blocks = 64;
+        device.attach(kvs.clone());
+        device
+    }
+
+    fn open(kvs: &Kvs, snapshot: &Snapshot) -> BlockDevice {
+        Snapshot::load(&snapshot.get_kvs_id(), kvs).unwrap().open(kvs)
+    }
+
+    #[test]
+    fn full_and_incremental_deltas_restore_the_source() {
+        let kvs = Kvs::in_memory();
+        let mut source = device(&kvs, 1);
+        source.write(0, &vec![0x11u8; 64 * 1024]).unwrap();
+        source.write(200 * 1024, &vec![0x22u8; 1000]).unwrap();
+        let first = source.snapshot(&kvs).unwrap();
+        source.write(4096, &vec![0x33u8; 512]).unwrap();
+        source.trim(200 * 1024, 1024).unwrap();
+        let second = source.snapshot(&kvs).unwrap();
+
+        let mut full = Vec::new();
+        export(&mut open(&kvs, &first), None, &mut full).unwrap();
+        let mut incremental = Vec::new();
+        let stats = export(&mut open(&kvs, &second), Some(&mut open(&kvs, &first)), &mut incremental).unwrap();
+        assert_eq!(stats, DeltaStats { data_blocks: 1, zero_blocks: 2 });
+
+        let mut target = device(&kvs, 2);
+        assert!(matches!(apply(&mut target, &incremental[..], false), Err(StorageError::Conflict(_))));
+        apply(&mut target, &full[..], false).unwrap();
+        apply(&mut target, &incremental[..], false).unwrap();
+        assert_eq!(target.restored_from, Some(RestorePoint { device_id: 1, generation: second.generation }));
+        let mut expected = open(&kvs, &second);
+        assert_eq!(target.read(0, 256 * 1024).unwrap(), expected.read(0, 256 * 1024).unwrap());
+    }
+
+    #[test]
+    fn deltas_of_devices_ending_mid_block() {
+        let kvs = Kvs::in_memory();
+        let mut source = BlockDevice::new(1, 3000);
+        source.attach(kvs.clone());
+        source.write(2000, &[0x66u8; 1000]).unwrap();
+        let mut delta = Vec::new();
+        let stats = export(&mut source, None, &mut delta).unwrap();
+        assert_eq!(stats.data_blocks, 3);
+
+        let mut target = BlockDevice::new(2, 3000);
+        target.attach(kvs.clone());
+        apply(&mut target, &delta[..], false).unwrap();
+        assert_eq!(target.read(0, 3000).unwrap(), source.read(0, 3000).unwrap());
+    }
+
+    #[test]
+    fn damaged_deltas_are_rejected() {
+        let kvs = Kvs::in_memory();
+        let mut source = device(&kvs, 1);
+        source.write(0, &vec![0x44u8; 4096]).unwrap();
+        let snapshot = source.snapshot(&kvs).unwrap();
+        let mut delta = Vec::new();
+        export(&mut open(&kvs, &snapshot), None, &mut delta).unwrap();
+
+        let mut flipped = delta.clone();
+        let middle = flipped.len() / 2;
+        flipped[middle] ^= 0x01;
+        assert!(matches!(apply(&mut device(&kvs, 2), &flipped[..], false), Err(StorageError::Corruption(_))));
+        assert!(matches!(apply(&mut device(&kvs, 2), &delta[..delta.len() - 1], false), Err(StorageError::Corruption(_))));
+
+        //the pool is charged as records are applied, a rejected delta gives that back
+        Pool::create(&kvs, &Pool::new("ci", 1024 * 1024)).unwrap();
+        let mut target = device(&kvs, 3);
+        target.pool = Some("ci".into());
+        target.write(8192, &[0x55u8; 1024]).unwrap();
+        target.flush(&kvs).unwrap();
+        assert!(apply(&mut target, &flipped[..], false).is_err());
+        assert_eq!(Pool::allocated(&kvs, "ci").unwrap(), 1024);
+    }
+
+    #[test]
+    fn full_deltas_replace_what_the_target_held() {
+        let kvs = Kvs::in_memory();
+        let mut source = device(&kvs, 1);
+        source.write(0, &vec![0x66u8; 4096]).unwrap();
+        let snapshot = source.snapshot(&kvs).unwrap();
+        let mut full = Vec::new();
+        export(&mut open(&kvs, &snapshot), None, &mut full).unwrap();
+
+        let mut target = device(&kvs, 2);
+        target.write(100 * 1024, &[0x77u8; 4096]).unwrap();
+        apply(&mut target, &full[..], false).unwrap();
+        assert_eq!(target.read(0, 256 * 1024).unwrap(), open(&kvs, &snapshot).read(0, 256 * 1024).unwrap());
+        assert_eq!(target.allocated_bytes().unwrap(), 4096);
+    }
+}
@@ -0,0 +1,184 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload as AeadPayload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use crate::utils::checksum::to_hex;
+use crate::utils::Error::{StorageError, StorageResult};
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+const DERIVED_NONCE_LEN: usize = 16; //rest of the nonce is random
+
+//Key encryption key, read from a local keyfile holding 64 hex characters
+pub struct MasterKey {
+    key: [u8; KEY_LEN],
+}
+
+impl MasterKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LEN];
+        rand::fill(&mut key);
+        MasterKey { key }
+    }
+
+    pub fn load(path: &Path) -> StorageResult<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| StorageError::NotFound(format!("keyfile {}: {}", path.display(), e)))?;
+        let key = parse_hex_key(text.trim())
+            .ok_or_else(|| StorageError::InvalidArgument(format!("keyfile {} does not hold a 256 bit hex key", path.display())))?;
+        Ok(MasterKey { key })
+    }
+
+    //writes the key readable by the owner only, refusing to overwrite an existing keyfile
+    pub fn save(&self, path: &Path) -> StorageResult<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| StorageError::Conflict(format!("keyfile {}: {}", path.display(), e)))?;
+        writeln!(file, "{}", to_hex(&self.key))
+            .map_err(|e| StorageError::BackendUnavailable(format!("keyfile {}: {}", path.display(), e)))
+    }
+
+    //public fingerprint recorded next to wrapped keys, so we can tell which master key they need
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"storage master key id");
+        hasher.update(self.key);
+        to_hex(&hasher.finalize()[..8])
+    }
+
+    pub fn wrap(&self, data_key: &DataKey, device_id: u128) -> StorageResult<WrappedKey> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::fill(&mut nonce);
+        let cipher = XChaCha20Poly1305::new((&self.key).into());
+        let aad = device_id.to_le_bytes();
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), AeadPayload { msg: &data_key.key, aad: &aad })
+            .map_err(|_| StorageError::InvalidArgument("wrapping the data key failed".into()))?;
+        Ok(WrappedKey {
+            master_key_id: self.id(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub fn unwrap(&self, wrapped: &WrappedKey, device_id: u128) -> StorageResult<DataKey> {
+        if wrapped.master_key_id != self.id() {
+            return Err(StorageError::InvalidArgument(format!(
+                "data key of device {} is wrapped by master key {}, not {}", device_id, wrapped.master_key_id, self.id()
+            )));
+        }
+        let cipher = XChaCha20Poly1305::new((&self.key).into());
+        let aad = device_id.to_le_bytes();
+        let plain = cipher
+            .decrypt(XNonce::from_slice(&wrapped.nonce), AeadPayload { msg: &wrapped.ciphertext, aad: &aad })
+            .map_err(|_| StorageError::Corruption(format!("wrapped data key of device {} does not authenticate", device_id)))?;
+        let key: [u8; KEY_LEN] = plain.try_into()
+            .map_err(|_| StorageError::Corruption(format!("wrapped data key of device {} has the wrong length", device_id)))?;
+        Ok(DataKey { key })
+    }
+}
+
+//A device data key encrypted under a master key, this is what gets persisted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub master_key_id: String,
+    #[serde(with = "serde_bytes")]
+    pub nonce: [u8; NONCE_LEN],
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+//Where a payload is written from, feeds the derived part of its nonce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonceSeed {
+    pub device_id: u128,
+    pub block_index: u64,
+    pub generation: u32,
+}
+
+//Per-device key payloads are encrypted with, only ever held in memory unwrapped
+#[derive(Clone)]
+pub struct DataKey {
+    key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(<redacted>)")
+    }
+}
+
+impl DataKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LEN];
+        rand::fill(&mut key);
+        DataKey { key }
+    }
+
+    //keyed content hash: payload addresses of encrypted devices reveal nothing about the plaintext
+    pub fn content_hash(&self, data: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"storage payload address");
+        hasher.update(self.key);
+        hasher.update(data);
+        hasher.finalize().into()
+    }
+
+    //nonce = H(device, block index, generation)[..16] | 8 random bytes. The derived half keeps
+    //devices, blocks and generations apart, the random half keeps rewrites of a block apart.
+    fn nonce(seed: &NonceSeed) -> [u8; NONCE_LEN] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"storage payload nonce");
+        hasher.update(seed.device_id.to_le_bytes());
+        hasher.update(seed.block_index.to_le_bytes());
+        hasher.update(seed.generation.to_le_bytes());
+        let derived = hasher.finalize();
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..DERIVED_NONCE_LEN].copy_from_slice(&derived[..DERIVED_NONCE_LEN]);
+        rand::fill(&mut nonce[DERIVED_NONCE_LEN..]);
+        nonce
+    }
+
+    //returns nonce | ciphertext, authenticated together with the payload address
+    pub fn seal(&self, data: &[u8], seed: &NonceSeed, hash: &[u8; 32]) -> StorageResult<Vec<u8>> {
+        let nonce = Self::nonce(seed);
+        let cipher = XChaCha20Poly1305::new((&self.key).into());
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), AeadPayload { msg: data, aad: hash })
+            .map_err(|_| StorageError::InvalidArgument("payload encryption failed".into()))?;
+        let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    pub fn open(&self, stored: &[u8], hash: &[u8; 32]) -> StorageResult<Vec<u8>> {
+        if stored.len() < NONCE_LEN {
+            return Err(StorageError::Corruption(format!("encrypted payload {} is truncated", to_hex(hash))));
+        }
+        let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+        let cipher = XChaCha20Poly1305::new((&self.key).into());
+        cipher
+            .decrypt(XNonce::from_slice(nonce), AeadPayload { msg: ciphertext, aad: hash })
+            .map_err(|_| StorageError::Corruption(format!("encrypted payload {} does not authenticate", to_hex(hash))))
+    }
+}
+
+fn parse_hex_key(text: &str) -> Option<[u8; KEY_LEN]> {
+    if text.len() != KEY_LEN * 2 {
+        return None;
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use crate::storage::Compression::Compression;
+
+//Where the data of an extent lives: a stored payload and the block offset into it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentRef {
+    #[serde(with = "serde_bytes")]
+    pub hash: [u8; 32],
+    pub offset: u64,
+    #[serde(default)]
+    pub codec: Compression,
+    #[serde(default)]
+    pub stored_len: u64, //bytes of the payload as stored, 0 when unknown
+    #[serde(default)]
+    pub encrypted: bool, //sealed with the device data key, hash is then keyed too
+    #[serde(default)]
+    pub checksum: Option<u64>, //xxh3 of the payload data, verified instead of rehashing when present
+}
+
+//A run of `length` logical blocks starting at block `start`, backed by consecutive blocks of one payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extent {
+    pub start: u64,
+    pub length: u64,
+    pub content: ContentRef,
+}
+
+impl Extent {
+    pub fn end(&self) -> u64 {
+        self.start + self.length
+    }
+
+    pub fn contains(&self, block_index: u64) -> bool {
+        block_index >= self.start && block_index < self.end()
+    }
+
+    //content reference for a single block inside the extent
+    pub fn content_at(&self, block_index: u64) -> ContentRef {
+        ContentRef {
+            offset: self.content.offset + (block_index - self.start),
+            ..self.content
+        }
+    }
+
+    //the part of the extent inside [start, end), None when they do not overlap
+    pub fn clip(&self, start: u64, end: u64) -> Option<Extent> {
+        let clipped_start = self.start.max(start);
+        let clipped_end = self.end().min(end);
+        if clipped_start >= clipped_end {
+            return None;
+        }
+        Some(Extent {
+            start: clipped_start,
+            length: clipped_end - clipped_start,
+            content: self.content_at(clipped_start),
+        })
+    }
+
+    //true when `next` continues this extent in both the logical range and the payload
+    pub fn can_merge(&self, next: &Extent) -> bool {
+        self.end() == next.start
+            && self.content.hash == next.content.hash
+            && self.content.codec == next.content.codec
+            && self.content.encrypted == next.content.encrypted
+            && self.content.offset + self.length == next.content.offset
+    }
+}
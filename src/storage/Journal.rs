@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::storage::Extent::Extent;
+use crate::utils::Error::{StorageError, StorageResult};
+
+pub const KVS_PREFIX: &str = "Journal:";
+
+//One change to the block map, as made by a write or trim
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JournalOp {
+    Map(Extent), //always inside a single page
+    Punch { start: u64, end: u64 },
+}
+
+//Block map changes acknowledged to the client but not yet flushed into pages. Entries are
+//replayed in order when the device is loaded and deleted by the next flush; replaying changes
+//that did make it into the pages is harmless, the last change to a block wins either way.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub device_id: u128,
+    pub seq: u64,
+    pub ops: Vec<JournalOp>,
+}
+
+impl JournalEntry {
+    //zero padded so key order is replay order
+    pub fn kvs_id(device_id: u128, seq: u64) -> String {
+        format!("{}{}:{:020}", KVS_PREFIX, device_id, seq)
+    }
+
+    pub fn list(kvs: &Kvs, device_id: u128) -> StorageResult<Vec<JournalEntry>> {
+        let mut entries = Vec::new();
+        for key in kvs.scan_keys(&format!("{}{}:*", KVS_PREFIX, device_id))? {
+            match JournalEntry::load(&key, kvs) {
+                Ok(entry) => entries.push(entry),
+                Err(StorageError::NotFound(_)) => continue, //flushed while we were scanning
+                Err(e) => return Err(e),
+            }
+        }
+        entries.sort_by_key(|entry| entry.seq);
+        Ok(entries)
+    }
+}
+
+impl KvsStorable for JournalEntry {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        kvs.load(id)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        Self::kvs_id(self.device_id, self.seq)
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use crate::manager::Kvs::Kvs;
+use crate::storage::Compression::Compression;
+use crate::storage::Encryption::{DataKey, NonceSeed};
+use crate::storage::Extent::ContentRef;
+use crate::utils::checksum::{calculate_checksum, fast_checksum, to_hex, ChecksumKind};
+use crate::utils::Error::{StorageError, StorageResult};
+use crate::utils::Metrics::{Metrics, METRICS};
+
+pub const KVS_PREFIX: &str = "Payload:";
+pub const MAX_PAYLOAD_BLOCKS: u64 = 2048; //upper bound for the blocks written as one payload
+const CACHE_ENTRIES: usize = 16;
+
+//Marks keys the garbage collector found unreferenced, holding since when. Writers reusing a
+//payload drop its mark, which is what keeps the collector from deleting it.
+pub const GC_CANDIDATE_PREFIX: &str = "GcCandidate:";
+
+pub fn gc_candidate_key(key: &str) -> String {
+    format!("{}{}", GC_CANDIDATE_PREFIX, key)
+}
+
+pub fn kvs_id(hash: &[u8; 32], codec: Compression) -> String {
+    format!("{}{}{}", KVS_PREFIX, to_hex(hash), codec.key_suffix())
+}
+
+//How a payload gets written, taken from the device it belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions<'a> {
+    pub compression: Compression,
+    pub checksum: ChecksumKind,
+    pub sealing: Option<(&'a DataKey, NonceSeed)>,
+}
+
+//Codec and checksum a serve process writes with instead of the device's, never stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteOverrides {
+    pub compression: Option<Compression>,
+    pub checksum: Option<ChecksumKind>,
+}
+
+//Payloads are content addressed, writing the same bytes twice with the same codec stores them once.
+//Compression is skipped when it does not make the payload smaller, encryption happens after it.
+pub fn put(kvs: &Kvs, data: &[u8], options: WriteOptions) -> StorageResult<ContentRef> {
+    let hash = match options.sealing {
+        Some((key, _)) => key.content_hash(data),
+        None => calculate_checksum(data),
+    };
+    let compressed = options.compression.compress(data)?;
+    let (codec, mut stored) = if options.compression != Compression::None && compressed.len() < data.len() {
+        (options.compression, compressed)
+    } else {
+        (Compression::None, data.to_vec())
+    };
+    if let Some((key, seed)) = options.sealing {
+        stored = key.seal(&stored, &seed, &hash)?;
+    }
+    let payload_key = kvs_id(&hash, codec);
+    if !kvs.set_raw_if_absent(&payload_key, &stored)? {
+        //reusing a payload the collector may have marked, take it off the list before we point at it.
+        //A sweep that deleted it before the mark was gone cannot be told apart, so store it again if missing.
+        kvs.delete(&gc_candidate_key(&payload_key))?;
+        kvs.set_raw_if_absent(&payload_key, &stored)?;
+    }
+    Ok(ContentRef {
+        hash,
+        offset: 0,
+        codec,
+        stored_len: stored.len() as u64,
+        encrypted: options.sealing.is_some(),
+        checksum: match options.checksum {
+            ChecksumKind::Sha256 => None,
+            ChecksumKind::Xxh3 => Some(fast_checksum(data)),
+        },
+    })
+}
+
+//fetches, decrypts and decompresses a payload, then checks the data against the reference
+pub fn get(kvs: &Kvs, content: &ContentRef, key: Option<&DataKey>) -> StorageResult<Vec<u8>> {
+    let payload_key = kvs_id(&content.hash, content.codec);
+    let stored = kvs.get_raw(&payload_key)?
+        .ok_or_else(|| StorageError::Corruption(format!("referenced payload {} is missing", payload_key)))?;
+    Metrics::inc(&METRICS.payload_reads);
+    decode(content, &stored, key)
+}
+
+//turns the stored bytes of a payload back into its data, verifying them on the way
+pub fn decode(content: &ContentRef, stored: &[u8], key: Option<&DataKey>) -> StorageResult<Vec<u8>> {
+    let payload_key = kvs_id(&content.hash, content.codec);
+    if content.stored_len != 0 && stored.len() as u64 != content.stored_len {
+        return Err(StorageError::Corruption(format!(
+            "payload {} is {} bytes, expected {}", payload_key, stored.len(), content.stored_len
+        )));
+    }
+    let data = if content.encrypted {
+        let key = key.ok_or_else(|| StorageError::Locked(format!("payload {} is encrypted", payload_key)))?;
+        content.codec.decompress(&key.open(stored, &content.hash)?)?
+    } else {
+        content.codec.decompress(stored)?
+    };
+    verify(content, &data, key)?;
+    Ok(data)
+}
+
+pub fn verify(content: &ContentRef, data: &[u8], key: Option<&DataKey>) -> StorageResult<()> {
+    let intact = match (content.checksum, content.encrypted) {
+        (Some(sum), _) => fast_checksum(data) == sum,
+        (None, true) => key.map(|key| key.content_hash(data) == content.hash).unwrap_or(false),
+        (None, false) => calculate_checksum(data) == content.hash,
+    };
+    if !intact {
+        return Err(StorageError::Corruption(format!(
+            "payload {} does not match its checksum", kvs_id(&content.hash, content.codec)
+        )));
+    }
+    Ok(())
+}
+
+//Small read cache so sequential reads inside one payload do not refetch it for every request
+#[derive(Debug, Clone, Default)]
+pub struct PayloadCache {
+    entries: VecDeque<(String, Arc<Vec<u8>>)>,
+}
+
+impl PayloadCache {
+    pub fn get(&mut self, kvs: &Kvs, content: &ContentRef, key: Option<&DataKey>) -> StorageResult<Arc<Vec<u8>>> {
+        let cache_key = kvs_id(&content.hash, content.codec);
+        if let Some(pos) = self.entries.iter().position(|(k, _)| *k == cache_key) {
+            let entry = self.entries.remove(pos).expect("position is in range");
+            let data = entry.1.clone();
+            self.entries.push_front(entry);
+            Metrics::inc(&METRICS.payload_cache_hits);
+            return Ok(data);
+        }
+        Metrics::inc(&METRICS.payload_cache_misses);
+        let data = Arc::new(get(kvs, content, key)?);
+        self.entries.push_front((cache_key, data.clone()));
+        self.entries.truncate(CACHE_ENTRIES);
+        Ok(data)
+    }
+}
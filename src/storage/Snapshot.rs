@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use crate::manager::Kvs::{Kvs, KvsStorable};
+use crate::storage::BlockDevice::BlockDevice;
+use crate::utils::Error::{StorageError, StorageResult};
+
+pub const KVS_PREFIX: &str = "Snapshot:";
+
+//Frozen copy of a device's top-level record. Taking one bumps the device generation, so later
+//writes go to new page keys and the pages listed here stay as they were.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub device_id: u128,
+    pub generation: u32,
+    pub created_at: u64,
+    pub device: BlockDevice,
+}
+
+impl Snapshot {
+    pub fn kvs_id(device_id: u128, generation: u32) -> String {
+        format!("{}{}:{}", KVS_PREFIX, device_id, generation)
+    }
+
+    //snapshots of one device, oldest first
+    pub fn list(kvs: &Kvs, device_id: u128) -> StorageResult<Vec<Snapshot>> {
+        Self::load_matching(kvs, &format!("{}{}:*", KVS_PREFIX, device_id))
+    }
+
+    pub fn list_all(kvs: &Kvs) -> StorageResult<Vec<Snapshot>> {
+        Self::load_matching(kvs, &format!("{}*", KVS_PREFIX))
+    }
+
+    fn load_matching(kvs: &Kvs, pattern: &str) -> StorageResult<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for key in kvs.scan_keys(pattern)? {
+            match Snapshot::load(&key, kvs) {
+                Ok(snapshot) => snapshots.push(snapshot),
+                Err(StorageError::NotFound(_)) => continue, //deleted while we were scanning
+                Err(e) => return Err(e),
+            }
+        }
+        snapshots.sort_by_key(|s| (s.device_id, s.generation));
+        Ok(snapshots)
+    }
+
+    //read-only view of the device as it was, encrypted snapshots still need unlocking
+    pub fn open(&self, kvs: &Kvs) -> BlockDevice {
+        let mut device = self.device.clone();
+        device.attach(kvs.clone());
+        device
+    }
+
+    //only drops the record, the pages and payloads it held are left to the garbage collector
+    pub fn delete(kvs: &Kvs, device_id: u128, generation: u32) -> StorageResult<()> {
+        let key = Self::kvs_id(device_id, generation);
+        if kvs.get_raw(&key)?.is_none() {
+            return Err(StorageError::NotFound(key));
+        }
+        kvs.delete(&key)
+    }
+}
+
+impl KvsStorable for Snapshot {
+    fn store(&self, kvs: &Kvs) -> StorageResult<()> {
+        kvs.store(self)
+    }
+
+    fn load(id: &str, kvs: &Kvs) -> StorageResult<Self> {
+        let mut snapshot: Snapshot = kvs.load(id)?;
+        snapshot.device.freeze();
+        Ok(snapshot)
+    }
+
+    fn get_kvs_id(&self) -> String {
+        Self::kvs_id(self.device_id, self.generation)
+    }
+}
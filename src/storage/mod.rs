@@ -1,2 +1,9 @@
-
-pub mod BlockDevice;
\ No newline at end of file
+pub mod BlockDevice;
+pub mod BlockPage;
+pub mod Compression;
+pub mod Delta;
+pub mod Encryption;
+pub mod Extent;
+pub mod Journal;
+pub mod Payload;
+pub mod Snapshot;
@@ -0,0 +1,79 @@
+use std::fmt;
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+//Errors shared by the storage and manager layers, each one maps to a single errno for NBD replies
+#[derive(Debug)]
+pub enum StorageError {
+    OutOfRange { offset: u64, length: u64, size: u64 },
+    Overflow { offset: u64, length: u64 },
+    InvalidArgument(String),
+    NotFound(String),
+    BackendUnavailable(String),
+    Conflict(String),
+    Corruption(String),
+    Locked(String),
+    ReadOnly,
+    NoSpace,
+    Io(std::io::Error),
+}
+
+impl StorageError {
+    pub fn errno(&self) -> i32 {
+        match self {
+            StorageError::OutOfRange { .. } => libc::EINVAL,
+            StorageError::Overflow { .. } => libc::EOVERFLOW,
+            StorageError::InvalidArgument(_) => libc::EINVAL,
+            StorageError::NotFound(_) => libc::EIO,
+            StorageError::BackendUnavailable(_) => libc::EIO,
+            StorageError::Conflict(_) => libc::EIO,
+            StorageError::Corruption(_) => libc::EIO,
+            StorageError::Locked(_) => libc::EPERM,
+            StorageError::ReadOnly => libc::EPERM,
+            StorageError::NoSpace => libc::ENOSPC,
+            StorageError::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::OutOfRange { offset, length, size } => {
+                write!(f, "span {}+{} out of range for size {}", offset, length, size)
+            }
+            StorageError::Overflow { offset, length } => {
+                write!(f, "span {}+{} overflows the byte offset range", offset, length)
+            }
+            StorageError::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            StorageError::NotFound(key) => write!(f, "not found: {}", key),
+            StorageError::BackendUnavailable(msg) => write!(f, "backend unavailable: {}", msg),
+            StorageError::Conflict(msg) => write!(f, "conflict: {}", msg),
+            StorageError::Corruption(msg) => write!(f, "corruption: {}", msg),
+            StorageError::Locked(msg) => write!(f, "locked, no key loaded: {}", msg),
+            StorageError::ReadOnly => write!(f, "device is read-only"),
+            StorageError::NoSpace => write!(f, "no space left"),
+            StorageError::Io(e) => write!(f, "i/o error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<redis::RedisError> for StorageError {
+    fn from(e: redis::RedisError) -> Self {
+        StorageError::BackendUnavailable(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Corruption(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
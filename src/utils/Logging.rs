@@ -0,0 +1,117 @@
+use log::kv::{self, VisitSource};
+use log::Record;
+use serde_json::{Map, Value};
+use std::io::Write;
+
+//picks the output format, anything but `json` gives text
+pub const FORMAT_ENV: &str = "STORAGE_LOG_FORMAT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text, //`<time> <LEVEL> <target>: <message> key=value ...`
+    Json, //one object per line for log pipelines
+}
+
+impl LogFormat {
+    pub fn from_env() -> Self {
+        match std::env::var(FORMAT_ENV).as_deref() {
+            Ok("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+//Installs the logger for the whole process. Levels and per module filters come from RUST_LOG
+//(e.g. `info,storage::storage=debug`), info when unset. Serve processes started by the daemon
+//inherit both variables.
+pub fn init() {
+    let format = LogFormat::from_env();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(move |buf, record| {
+            let ts = buf.timestamp_millis().to_string();
+            let line = match format {
+                LogFormat::Text => text_line(&ts, record),
+                LogFormat::Json => json_line(&ts, record),
+            };
+            writeln!(buf, "{}", line)
+        })
+        .init();
+}
+
+fn text_line(ts: &str, record: &Record) -> String {
+    let mut line = format!("{} {:<5} {}: {}", ts, record.level(), record.target(), record.args());
+    for (key, value) in fields(record) {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line
+}
+
+fn json_line(ts: &str, record: &Record) -> String {
+    let mut object = Map::new();
+    object.insert("ts".into(), ts.into());
+    object.insert("level".into(), record.level().as_str().into());
+    object.insert("target".into(), record.target().into());
+    object.insert("msg".into(), record.args().to_string().into());
+    for (key, value) in fields(record) {
+        object.insert(key, value);
+    }
+    Value::Object(object).to_string()
+}
+
+//the record's key-values, numbers and flags kept as such and everything else as text
+fn fields(record: &Record) -> Vec<(String, Value)> {
+    struct Collect(Vec<(String, Value)>);
+    impl<'kvs> VisitSource<'kvs> for Collect {
+        fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+            let value = if let Some(n) = value.to_u64() {
+                n.into()
+            } else if let Some(n) = value.to_i64() {
+                n.into()
+            } else if let Some(b) = value.to_bool() {
+                b.into()
+            } else if let Some(x) = value.to_f64() {
+                x.into()
+            } else {
+                value.to_string().into()
+            };
+            self.0.push((key.to_string(), value));
+            Ok(())
+        }
+    }
+    let mut collect = Collect(Vec::new());
+    let _ = record.key_values().visit(&mut collect);
+    collect.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn formats_fields_as_text_and_json() {
+        let fields: &[(&str, kv::Value)] = &[
+            ("device", kv::Value::from_display(&u128::MAX)),
+            ("offset", kv::Value::from(4096u64)),
+            ("cmd", kv::Value::from("READ")),
+        ];
+        let args = format_args!("request answered");
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("storage::serve")
+            .args(args)
+            .key_values(&fields)
+            .build();
+
+        assert_eq!(
+            text_line("T", &record),
+            format!("T INFO  storage::serve: request answered device=\"{}\" offset=4096 cmd=\"READ\"", u128::MAX)
+        );
+        let json: Value = serde_json::from_str(&json_line("T", &record)).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["msg"], "request answered");
+        assert_eq!(json["device"], u128::MAX.to_string()); //ids are logged with `device:% = id`
+        assert_eq!(json["offset"], 4096);
+        assert_eq!(json["cmd"], "READ");
+    }
+}
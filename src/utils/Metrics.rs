@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+//upper bounds in seconds, from a cached read to a stalled kvs
+pub const LATENCY_BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+//Process wide counters, bumped from the storage layer
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub payload_reads: AtomicU64,
+    pub integrity_errors: AtomicU64,
+    pub quota_rejections: AtomicU64,
+    pub page_cache_hits: AtomicU64,
+    pub page_cache_misses: AtomicU64,
+    pub payload_cache_hits: AtomicU64,
+    pub payload_cache_misses: AtomicU64,
+    pub kvs_latency: Histogram, //round trips to the kvs, including the wait for its connection
+}
+
+pub static METRICS: Metrics = Metrics {
+    payload_reads: AtomicU64::new(0),
+    integrity_errors: AtomicU64::new(0),
+    quota_rejections: AtomicU64::new(0),
+    page_cache_hits: AtomicU64::new(0),
+    page_cache_misses: AtomicU64::new(0),
+    payload_cache_hits: AtomicU64::new(0),
+    payload_cache_misses: AtomicU64::new(0),
+    kvs_latency: Histogram::new(),
+};
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //the process wide metrics in prometheus text format
+    pub fn render(&self, out: &mut String) {
+        let counters = [
+            ("storage_payload_reads_total", "payloads fetched from the kvs", &self.payload_reads),
+            ("storage_integrity_errors_total", "payloads that failed their checksum", &self.integrity_errors),
+            ("storage_quota_rejections_total", "writes refused by a quota or pool", &self.quota_rejections),
+            ("storage_page_cache_hits_total", "block pages found in memory", &self.page_cache_hits),
+            ("storage_page_cache_misses_total", "block pages loaded from the kvs", &self.page_cache_misses),
+            ("storage_payload_cache_hits_total", "payload reads answered from the read cache", &self.payload_cache_hits),
+            ("storage_payload_cache_misses_total", "payload reads that went to the kvs", &self.payload_cache_misses),
+        ];
+        for (name, help, counter) in counters {
+            header(out, name, help, "counter");
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        let name = "storage_kvs_request_duration_seconds";
+        header(out, name, "round trips to the kvs", "histogram");
+        self.kvs_latency.render(out, name, "");
+    }
+}
+
+//Latency histogram with fixed LATENCY_BUCKETS, cheap enough to update on every request
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()], //not cumulative, summed up when rendering
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(elapsed.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+    }
+
+    //observes the time until the returned guard is dropped
+    pub fn start_timer(&self) -> Timer<'_> {
+        Timer { histogram: self, started: Instant::now() }
+    }
+
+    //`labels` is empty or a comma terminated list like `device="1",`
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, count);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let labels = labels.trim_end_matches(',');
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+pub struct Timer<'a> {
+    histogram: &'a Histogram,
+    started: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.observe(self.started.elapsed());
+    }
+}
+
+//Request metrics of one served device, labelled with its id
+#[derive(Debug)]
+pub struct DeviceMetrics {
+    device_id: u128,
+    commands: Mutex<BTreeMap<&'static str, CommandStats>>,
+    errors: Mutex<BTreeMap<u32, u64>>, //by errno
+    pub queue_depth: AtomicU64, //requests read from the kernel and not answered yet
+    pub allocated_blocks: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct CommandStats {
+    ops: u64,
+    bytes: u64,
+    latency: Histogram,
+}
+
+impl DeviceMetrics {
+    pub fn new(device_id: u128) -> Self {
+        DeviceMetrics {
+            device_id,
+            commands: Mutex::new(BTreeMap::new()),
+            errors: Mutex::new(BTreeMap::new()),
+            queue_depth: AtomicU64::new(0),
+            allocated_blocks: AtomicU64::new(0),
+        }
+    }
+
+    //accounts one answered request, `errno` 0 meaning success
+    pub fn record(&self, command: &'static str, bytes: u64, elapsed: Duration, errno: u32) {
+        if let Ok(mut commands) = self.commands.lock() {
+            let stats = commands.entry(command).or_default();
+            stats.ops += 1;
+            stats.bytes += bytes;
+            stats.latency.observe(elapsed);
+        }
+        if errno != 0
+            && let Ok(mut errors) = self.errors.lock()
+        {
+            *errors.entry(errno).or_default() += 1;
+        }
+    }
+
+    pub fn render(&self, out: &mut String) {
+        let device = self.device_id;
+        let commands = self.commands.lock().map(|c| c.iter().map(|(k, v)| (*k, v.ops, v.bytes)).collect::<Vec<_>>());
+        let commands = commands.unwrap_or_default();
+        header(out, "storage_requests_total", "answered nbd requests", "counter");
+        for (command, ops, _) in &commands {
+            let _ = writeln!(out, "storage_requests_total{{device=\"{}\",command=\"{}\"}} {}", device, command, ops);
+        }
+        header(out, "storage_request_bytes_total", "bytes asked for by nbd requests", "counter");
+        for (command, _, bytes) in &commands {
+            let _ = writeln!(out, "storage_request_bytes_total{{device=\"{}\",command=\"{}\"}} {}", device, command, bytes);
+        }
+        let name = "storage_request_duration_seconds";
+        header(out, name, "time from taking a request to answering it, qos delays excluded", "histogram");
+        if let Ok(stats) = self.commands.lock() {
+            for (command, stats) in stats.iter() {
+                stats.latency.render(out, name, &format!("device=\"{}\",command=\"{}\",", device, command));
+            }
+        }
+        header(out, "storage_request_errors_total", "failed nbd requests by errno", "counter");
+        if let Ok(errors) = self.errors.lock() {
+            for (errno, count) in errors.iter() {
+                let _ = writeln!(out, "storage_request_errors_total{{device=\"{}\",errno=\"{}\"}} {}", device, errno, count);
+            }
+        }
+        let gauges = [
+            ("storage_queue_depth", "requests waiting to be answered", &self.queue_depth),
+            ("storage_allocated_blocks", "blocks the device has mapped", &self.allocated_blocks),
+        ];
+        for (name, help, gauge) in gauges {
+            header(out, name, help, "gauge");
+            let _ = writeln!(out, "{}{{device=\"{}\"}} {}", name, device, gauge.load(Ordering::Relaxed));
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_device_metrics_as_prometheus_text() {
+        let metrics = DeviceMetrics::new(7);
+        metrics.record("READ", 4096, Duration::from_micros(300), 0);
+        metrics.record("READ", 4096, Duration::from_millis(20), 0);
+        metrics.record("WRITE", 512, Duration::from_secs(3), libc::ENOSPC as u32);
+        metrics.allocated_blocks.store(12, Ordering::Relaxed);
+        let mut out = String::new();
+        metrics.render(&mut out);
+
+        let lines: Vec<&str> = out.lines().collect();
+        for expected in [
+            "storage_requests_total{device=\"7\",command=\"READ\"} 2",
+            "storage_request_bytes_total{device=\"7\",command=\"READ\"} 8192",
+            "storage_request_duration_seconds_bucket{device=\"7\",command=\"READ\",le=\"0.0005\"} 1",
+            "storage_request_duration_seconds_bucket{device=\"7\",command=\"READ\",le=\"0.025\"} 2",
+            "storage_request_duration_seconds_bucket{device=\"7\",command=\"WRITE\",le=\"1\"} 0",
+            "storage_request_duration_seconds_bucket{device=\"7\",command=\"WRITE\",le=\"+Inf\"} 1",
+            "storage_request_duration_seconds_count{device=\"7\",command=\"WRITE\"} 1",
+            "storage_request_errors_total{device=\"7\",errno=\"28\"} 1",
+            "storage_allocated_blocks{device=\"7\"} 12",
+        ] {
+            assert!(lines.contains(&expected), "missing {expected} in\n{out}");
+        }
+    }
+}
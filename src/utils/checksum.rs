@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use crate::utils::Error::StorageError;
+
+//How payload reads are verified: the SHA-256 address itself, or a cheaper xxh3 sum stored next to it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChecksumKind {
+    #[default]
+    Sha256,
+    Xxh3,
+}
+
+impl fmt::Display for ChecksumKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChecksumKind::Sha256 => "sha256",
+            ChecksumKind::Xxh3 => "xxh3",
+        })
+    }
+}
+
+impl FromStr for ChecksumKind {
+    type Err = StorageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(ChecksumKind::Sha256),
+            "xxh3" => Ok(ChecksumKind::Xxh3),
+            other => Err(StorageError::InvalidArgument(format!("unknown checksum {:?}", other))),
+        }
+    }
+}
+
+pub fn calculate_checksum(data: &[u8]) -> [u8; 32] {
+    use sha2::{Sha256, Digest};
+
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    let result = hasher.finalize();
+    let mut checksum = [0u8; 32];
+    checksum.copy_from_slice(&result);
+    checksum
+}
+
+pub fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+pub fn fast_checksum(data: &[u8]) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(data)
+}
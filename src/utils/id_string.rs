@@ -0,0 +1,10 @@
+use serde::{Deserialize, Deserializer, Serializer};
+
+//serde adapter writing u128 ids as decimal strings, JSON numbers cannot hold them
+pub fn serialize<S: Serializer>(id: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+    String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+}
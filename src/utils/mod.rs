@@ -0,0 +1,6 @@
+pub mod checksum;
+pub mod clock;
+pub mod Error;
+pub mod id_string;
+pub mod Logging;
+pub mod Metrics;